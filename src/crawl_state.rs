@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::SaveShopError;
+use crate::journal::truncate_partial_line;
+
+// Identifies a single request made during a crawl.
+// Region and language are empty for requests that aren't tied to a locale (e.g. media files)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct JobKey {
+    pub region: String,
+    pub language: String,
+    pub content_type: String,
+    pub id: String,
+    pub endpoint: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobRecord {
+    #[serde(flatten)]
+    pub key: JobKey,
+    pub status: JobStatus,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    // SHA-256 of the data saved to disk, for finished jobs
+    pub sha256: Option<String>,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// Append-only JSON-lines journal of finished and failed jobs. Later records for the same job override earlier ones.
+pub struct CrawlState {
+    jobs: Mutex<HashMap<JobKey, JobRecord>>,
+    file: Mutex<File>,
+    // If set, previously finished jobs are fetched again
+    refetch: bool,
+}
+
+impl CrawlState {
+    pub fn open(path: &Path, refetch: bool) -> Result<CrawlState, SaveShopError> {
+        let mut jobs = HashMap::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // Ignore a partially written last line from an interrupted run
+                if let Ok(record) = serde_json::from_str::<JobRecord>(&line) {
+                    jobs.insert(record.key.clone(), record);
+                }
+            }
+        }
+
+        let file = fs::OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        truncate_partial_line(&file, path)?;
+        Ok(CrawlState { jobs: Mutex::new(jobs), file: Mutex::new(file), refetch })
+    }
+
+    // Checks if the job was finished in a previous run and its output at the given path is still intact
+    pub fn is_done(&self, key: &JobKey, path: &Path) -> bool {
+        if self.refetch {
+            return false;
+        }
+
+        let expected_hash = match self.jobs.lock().unwrap().get(key) {
+            Some(JobRecord { status: JobStatus::Done, sha256: Some(hash), .. }) => hash.clone(),
+            _ => return false,
+        };
+        fs::read(path).map(|data| sha256_hex(&data) == expected_hash).unwrap_or(false)
+    }
+
+    pub fn record_done(&self, key: JobKey, data: &[u8]) -> Result<(), SaveShopError> {
+        self.record(key, JobStatus::Done, Some(sha256_hex(data)))
+    }
+
+    pub fn record_failed(&self, key: JobKey) -> Result<(), SaveShopError> {
+        self.record(key, JobStatus::Failed, None)
+    }
+
+    fn record(&self, key: JobKey, status: JobStatus, sha256: Option<String>) -> Result<(), SaveShopError> {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let record = JobRecord { key, status, timestamp, sha256 };
+
+        {
+            let mut file = self.file.lock().unwrap();
+            writeln!(file, "{}", serde_json::to_string(&record)?)?;
+            file.sync_data()?;
+        }
+        self.jobs.lock().unwrap().insert(record.key.clone(), record);
+        Ok(())
+    }
+}
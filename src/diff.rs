@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::SaveShopError;
+use crate::prices::{read_prices_document, TitlePrice};
+use crate::snapshot::SnapshotStore;
+use crate::{
+    ninja_dir, online_prices_path, read_document, record_failure, samurai_dir, DemoDocument, DirectoryDocument, Locale,
+    MediaReferences, MovieDocument, NodeRatingInfo, NodeTitleOrMovie, RankingDocument, TitleDocument,
+};
+
+#[derive(clap::ArgEnum, Clone, Copy, PartialEq)]
+pub enum DiffFormat {
+    Text,
+    Json,
+}
+
+// Metadata files of an archive root or snapshot, by their location relative to the archive root
+struct Archive {
+    // Archive root or snapshot ID, as given on the command line
+    label: String,
+    files: BTreeMap<String, PathBuf>,
+}
+
+impl Archive {
+    // Opens the archive root at the given path, or the snapshot with the given ID if there's no such directory
+    fn open(spec: &str, regions: &[String]) -> Result<Archive, SaveShopError> {
+        let mut files = BTreeMap::new();
+        let root = Path::new(spec);
+        if root.is_dir() {
+            for region in regions {
+                for dir in [samurai_dir(region), ninja_dir(region)] {
+                    let mut paths = Vec::new();
+                    crate::snapshot::walk_files(&root.join(dir), &mut paths)?;
+                    for path in paths {
+                        let relative_path = path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/");
+                        files.insert(relative_path, path);
+                    }
+                }
+            }
+        } else {
+            let store = SnapshotStore::open(Path::new("snapshots"))?;
+            let region_prefixes: Vec<String> = regions.iter()
+                .flat_map(|region| [format!("{}/", samurai_dir(region)), format!("{}/", ninja_dir(region))])
+                .collect();
+            for entry in store.read_manifest(spec)? {
+                if region_prefixes.iter().any(|prefix| entry.path.starts_with(prefix)) {
+                    files.insert(entry.path, store.object_path(&entry.sha256));
+                }
+            }
+        }
+        Ok(Archive { label: spec.to_string(), files })
+    }
+
+    fn languages(&self, region: &str) -> BTreeSet<String> {
+        let prefix = format!("{}/", samurai_dir(region));
+        self.files.range(prefix.clone()..).take_while(|(path, _)| path.starts_with(&prefix))
+            .filter_map(|(path, _)| path[prefix.len()..].split_once('/').map(|(language, _)| language.to_string()))
+            .collect()
+    }
+
+    // Documents of the given kind of content (e.g. "title") as pairs of content ID and document
+    fn documents(&self, locale: &Locale, kind: &str) -> Vec<(String, Document<'_>)> {
+        let prefix = format!("{}/{}/", locale.samurai_dir(), kind);
+        self.files.range(prefix.clone()..).take_while(|(path, _)| path.starts_with(&prefix))
+            .filter(|(path, _)| !path[prefix.len()..].contains('/'))
+            .map(|(path, file)| (path[prefix.len()..].to_string(), Document { archive: self, path, file }))
+            .collect()
+    }
+
+    fn price(&self, locale: &Locale, title_id: &str) -> Option<TitlePrice> {
+        let path = online_prices_path(locale, title_id);
+        let file = self.files.get(&path)?;
+        match read_prices_document(file) {
+            Ok(prices) => prices.into_iter().find(|price| price.title_id == title_id),
+            Err(err) => { record_failure(format!("{} in {}", path, self.label), err); None },
+        }
+    }
+}
+
+struct Document<'a> {
+    archive: &'a Archive,
+    // Location relative to the archive root
+    path: &'a str,
+    file: &'a Path,
+}
+
+impl Document<'_> {
+    // Parses the document, recording a failure if it can't be parsed
+    fn parse<T>(&self, parse: impl FnOnce(&Path) -> Result<T, SaveShopError>) -> Option<T> {
+        parse(self.file).map_err(|err| record_failure(format!("{} in {}", self.path, self.archive.label), err)).ok()
+    }
+}
+
+#[derive(Serialize)]
+struct ContentRef {
+    kind: String,
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct FieldChange {
+    kind: String,
+    id: String,
+    field: String,
+    old: Option<String>,
+    new: Option<String>,
+}
+
+// Position changes of a single entry of a ranking. Positions are 0-based and None if the entry wasn't listed
+#[derive(Serialize)]
+struct RankingMove {
+    kind: String,
+    id: String,
+    old_position: Option<usize>,
+    new_position: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct RankingDiff {
+    ranking_id: String,
+    moves: Vec<RankingMove>,
+}
+
+#[derive(Serialize)]
+struct LocaleDiff {
+    region: String,
+    language: String,
+    added: Vec<ContentRef>,
+    removed: Vec<ContentRef>,
+    changed: Vec<FieldChange>,
+    rankings: Vec<RankingDiff>,
+}
+
+// Media URLs referenced by the metadata of a region
+#[derive(Serialize)]
+struct MediaDiff {
+    region: String,
+    added_urls: Vec<String>,
+    removed_urls: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ArchiveDiff {
+    old: String,
+    new: String,
+    locales: Vec<LocaleDiff>,
+    media: Vec<MediaDiff>,
+}
+
+// Fields of titles and movies that are compared between archives
+struct ContentSummary {
+    name: String,
+    fields: Vec<(&'static str, Option<String>)>,
+}
+
+fn rating_summary(rating_info: &Option<NodeRatingInfo>) -> Option<String> {
+    rating_info.as_ref().map(|info| {
+        let system = info.rating_system.as_ref().map(|system| system.name.as_str()).unwrap_or("?");
+        format!("{} {}", system, info.rating.name.as_deref().or(info.rating.age.as_deref()).unwrap_or("?"))
+    })
+}
+
+fn price_summary(price: &Option<TitlePrice>) -> Option<String> {
+    let price = price.as_ref()?;
+    let mut summary = price.sales_status.clone().unwrap_or_default();
+    if let Some(regular) = &price.regular {
+        summary.push_str(&format!(" {}", regular.amount));
+    }
+    if let Some(discount) = &price.discount {
+        summary.push_str(&format!(" (discounted to {} from {} until {})", discount.price.amount,
+                                  discount.start.as_deref().unwrap_or("?"), discount.end.as_deref().unwrap_or("?")));
+    }
+    Some(summary)
+}
+
+// Parses all titles and movies of a locale, also collecting their media URLs
+fn summarize(archive: &Archive, locale: &Locale, media_urls: &mut BTreeSet<String>) -> BTreeMap<(String, String), ContentSummary> {
+    let mut media = MediaReferences::default();
+    let mut summaries = BTreeMap::new();
+
+    for (id, document) in archive.documents(locale, "title") {
+        let title = match document.parse(TitleDocument::read) {
+            Some(doc) => doc.title,
+            None => continue,
+        };
+        media.add_title(&title);
+        let price = archive.price(locale, &id);
+        summaries.insert(("title".to_string(), id), ContentSummary {
+            fields: vec![
+                ("name", Some(title.name.clone())),
+                ("description", title.description),
+                ("rating", rating_summary(&title.rating_info)),
+                ("price", price_summary(&price)),
+            ],
+            name: title.name,
+        });
+    }
+
+    for (id, document) in archive.documents(locale, "movie") {
+        let movie = match document.parse(read_document::<MovieDocument>) {
+            Some(doc) => doc.movie,
+            None => continue,
+        };
+        media.add_movie(&movie);
+        summaries.insert(("movie".to_string(), id), ContentSummary {
+            fields: vec![("name", Some(movie.name.clone())), ("rating", rating_summary(&movie.rating_info))],
+            name: movie.name,
+        });
+    }
+
+    for (_, document) in archive.documents(locale, "demo") {
+        if let Some(doc) = document.parse(read_document::<DemoDocument>) {
+            media.add_demo(&doc.content.demo);
+        }
+    }
+    for (_, document) in archive.documents(locale, "directory") {
+        if let Some(doc) = document.parse(read_document::<DirectoryDocument>) {
+            media.add_directory(&doc.directory);
+        }
+    }
+
+    media_urls.extend(media.resources.into_iter().map(|(_, url)| url));
+    media_urls.extend(media.movie_files.into_iter().map(|file| file.movie_url));
+    summaries
+}
+
+// Entries of each ranking of a locale, in order
+fn rankings(archive: &Archive, locale: &Locale) -> BTreeMap<String, Vec<(String, String)>> {
+    let mut rankings = BTreeMap::new();
+    for (id, document) in archive.documents(locale, "ranking") {
+        let ranking = match document.parse(read_document::<RankingDocument>) {
+            Some(doc) => doc.ranking,
+            None => continue,
+        };
+        let entries = ranking.contents.into_iter().flat_map(|c| c.content).map(|content| match content.title_or_movie {
+            NodeTitleOrMovie::Title(title) => ("title".to_string(), title.id),
+            NodeTitleOrMovie::Movie(movie) => ("movie".to_string(), movie.id),
+        }).collect();
+        rankings.insert(id, entries);
+    }
+    rankings
+}
+
+fn diff_rankings(old: BTreeMap<String, Vec<(String, String)>>, new: BTreeMap<String, Vec<(String, String)>>) -> Vec<RankingDiff> {
+    let ranking_ids: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let mut diffs = Vec::new();
+    for ranking_id in ranking_ids {
+        let old_entries = old.get(ranking_id).map(Vec::as_slice).unwrap_or_default();
+        let new_entries = new.get(ranking_id).map(Vec::as_slice).unwrap_or_default();
+
+        let mut moves = Vec::new();
+        for (new_position, entry) in new_entries.iter().enumerate() {
+            let old_position = old_entries.iter().position(|old_entry| old_entry == entry);
+            if old_position != Some(new_position) {
+                moves.push(RankingMove { kind: entry.0.clone(), id: entry.1.clone(), old_position, new_position: Some(new_position) });
+            }
+        }
+        for (old_position, entry) in old_entries.iter().enumerate() {
+            if !new_entries.contains(entry) {
+                moves.push(RankingMove { kind: entry.0.clone(), id: entry.1.clone(), old_position: Some(old_position), new_position: None });
+            }
+        }
+
+        if !moves.is_empty() {
+            diffs.push(RankingDiff { ranking_id: ranking_id.clone(), moves });
+        }
+    }
+    diffs
+}
+
+fn diff_archives(old_spec: &str, new_spec: &str, regions: &[String]) -> Result<ArchiveDiff, SaveShopError> {
+    let old = Archive::open(old_spec, regions)?;
+    let new = Archive::open(new_spec, regions)?;
+    let mut diff = ArchiveDiff { old: old_spec.to_string(), new: new_spec.to_string(), locales: Vec::new(), media: Vec::new() };
+
+    for region in regions {
+        let mut old_media_urls = BTreeSet::new();
+        let mut new_media_urls = BTreeSet::new();
+
+        let languages: BTreeSet<String> = old.languages(region).into_iter().chain(new.languages(region)).collect();
+        for language in languages {
+            let locale = Locale { region: region.clone(), language };
+            let old_contents = summarize(&old, &locale, &mut old_media_urls);
+            let new_contents = summarize(&new, &locale, &mut new_media_urls);
+
+            let mut locale_diff = LocaleDiff {
+                region: locale.region.clone(),
+                language: locale.language.clone(),
+                added: Vec::new(),
+                removed: Vec::new(),
+                changed: Vec::new(),
+                rankings: diff_rankings(rankings(&old, &locale), rankings(&new, &locale)),
+            };
+            for ((kind, id), old_content) in &old_contents {
+                match new_contents.get(&(kind.clone(), id.clone())) {
+                    None => locale_diff.removed.push(ContentRef { kind: kind.clone(), id: id.clone(), name: old_content.name.clone() }),
+                    Some(new_content) => {
+                        for ((field, old_value), (_, new_value)) in old_content.fields.iter().zip(&new_content.fields) {
+                            if old_value != new_value {
+                                locale_diff.changed.push(FieldChange {
+                                    kind: kind.clone(),
+                                    id: id.clone(),
+                                    field: field.to_string(),
+                                    old: old_value.clone(),
+                                    new: new_value.clone(),
+                                });
+                            }
+                        }
+                    },
+                }
+            }
+            for ((kind, id), new_content) in &new_contents {
+                if !old_contents.contains_key(&(kind.clone(), id.clone())) {
+                    locale_diff.added.push(ContentRef { kind: kind.clone(), id: id.clone(), name: new_content.name.clone() });
+                }
+            }
+
+            if !locale_diff.added.is_empty() || !locale_diff.removed.is_empty() || !locale_diff.changed.is_empty() || !locale_diff.rankings.is_empty() {
+                diff.locales.push(locale_diff);
+            }
+        }
+
+        let media_diff = MediaDiff {
+            region: region.clone(),
+            added_urls: new_media_urls.difference(&old_media_urls).cloned().collect(),
+            removed_urls: old_media_urls.difference(&new_media_urls).cloned().collect(),
+        };
+        if !media_diff.added_urls.is_empty() || !media_diff.removed_urls.is_empty() {
+            diff.media.push(media_diff);
+        }
+    }
+    Ok(diff)
+}
+
+fn position_text(position: Option<usize>) -> String {
+    position.map(|position| (position + 1).to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+fn print_text(diff: &ArchiveDiff) {
+    println!("Changes from {} to {}:", diff.old, diff.new);
+    for locale in &diff.locales {
+        println!("\n{}/{}:", locale.region, locale.language);
+        for content in &locale.added {
+            println!("  + {} {} \"{}\"", content.kind, content.id, content.name);
+        }
+        for content in &locale.removed {
+            println!("  - {} {} \"{}\"", content.kind, content.id, content.name);
+        }
+        for change in &locale.changed {
+            let value_text = |value: &Option<String>| value.as_ref().map(|value| format!("{:?}", value)).unwrap_or_else(|| "(none)".to_string());
+            println!("  ~ {} {} {}: {} -> {}", change.kind, change.id, change.field, value_text(&change.old), value_text(&change.new));
+        }
+        for ranking in &locale.rankings {
+            println!("  ranking {}:", ranking.ranking_id);
+            for entry in &ranking.moves {
+                println!("    {} {}: {} -> {}", entry.kind, entry.id, position_text(entry.old_position), position_text(entry.new_position));
+            }
+        }
+    }
+    for media in &diff.media {
+        println!("\n{} media:", media.region);
+        for url in &media.added_urls {
+            println!("  + {}", url);
+        }
+        for url in &media.removed_urls {
+            println!("  - {}", url);
+        }
+    }
+    if diff.locales.is_empty() && diff.media.is_empty() {
+        println!("No changes");
+    }
+}
+
+// Reports the differences between two archive roots or snapshots for the given regions
+pub fn diff(old: &str, new: &str, regions: &[String], format: DiffFormat) -> Result<(), SaveShopError> {
+    let diff = diff_archives(old, new, regions)?;
+    match format {
+        DiffFormat::Text => print_text(&diff),
+        DiffFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+
+use crate::retry::FetchError;
+
+#[derive(Debug)]
+pub enum SaveShopError {
+    // A request failed even after retrying
+    Network(FetchError),
+    // Errors from the HTTP client that aren't covered by the retry logic (e.g. invalid certificates)
+    Http(reqwest::Error),
+    // Errors from the built-in HTTP server
+    Server(hyper::Error),
+    Xml(quick_xml::DeError),
+    Json(serde_json::Error),
+    Filesystem(std::io::Error),
+    Database(rusqlite::Error),
+    // Resource URL pointing to a server we don't know how to map to a local path
+    UnknownHost(String),
+    // Server data didn't match what we expected (e.g. inconsistent pagination)
+    SchemaMismatch(String),
+    // FFmpeg failed to convert a video
+    Conversion(String),
+    // Previously fetched data refers to metadata that isn't available locally
+    MissingMetadata(String),
+    // Invalid configuration file or command-line options
+    Config(String),
+    // Archive root still using the layout of an earlier version, which needs to be migrated first
+    OutdatedLayout(String),
+}
+
+impl fmt::Display for SaveShopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveShopError::Network(err) => write!(f, "Network error: {}", err),
+            SaveShopError::Http(err) => write!(f, "HTTP error: {}", err),
+            SaveShopError::Server(err) => write!(f, "Server error: {}", err),
+            SaveShopError::Xml(err) => write!(f, "Failed to parse XML: {}", err),
+            SaveShopError::Json(err) => write!(f, "Failed to parse JSON: {}", err),
+            SaveShopError::Filesystem(err) => write!(f, "Filesystem error: {}", err),
+            SaveShopError::Database(err) => write!(f, "Database error: {}", err),
+            SaveShopError::UnknownHost(url) => write!(f, "Unrecognized resource URL \"{}\"", url),
+            SaveShopError::SchemaMismatch(what) => write!(f, "Unexpected server data: {}", what),
+            SaveShopError::Conversion(output) => write!(f, "FFmpeg failed: {}", output),
+            SaveShopError::MissingMetadata(what) => write!(f, "Missing metadata: {}", what),
+            SaveShopError::Config(what) => write!(f, "Invalid configuration: {}", what),
+            SaveShopError::OutdatedLayout(root) =>
+                write!(f, "Archive at {} uses the layout of an earlier version of saveShop. Run the migrate subcommand to update it", root),
+        }
+    }
+}
+
+impl std::error::Error for SaveShopError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SaveShopError::Network(err) => Some(err),
+            SaveShopError::Http(err) => Some(err),
+            SaveShopError::Server(err) => Some(err),
+            SaveShopError::Xml(err) => Some(err),
+            SaveShopError::Json(err) => Some(err),
+            SaveShopError::Filesystem(err) => Some(err),
+            SaveShopError::Database(err) => Some(err),
+            SaveShopError::UnknownHost(_) | SaveShopError::SchemaMismatch(_) | SaveShopError::Conversion(_)
+            | SaveShopError::MissingMetadata(_) | SaveShopError::Config(_) | SaveShopError::OutdatedLayout(_) => None,
+        }
+    }
+}
+
+impl From<FetchError> for SaveShopError {
+    fn from(err: FetchError) -> Self { SaveShopError::Network(err) }
+}
+
+impl From<reqwest::Error> for SaveShopError {
+    fn from(err: reqwest::Error) -> Self { SaveShopError::Http(err) }
+}
+
+impl From<hyper::Error> for SaveShopError {
+    fn from(err: hyper::Error) -> Self { SaveShopError::Server(err) }
+}
+
+impl From<quick_xml::DeError> for SaveShopError {
+    fn from(err: quick_xml::DeError) -> Self { SaveShopError::Xml(err) }
+}
+
+impl From<quick_xml::Error> for SaveShopError {
+    fn from(err: quick_xml::Error) -> Self { SaveShopError::Xml(err.into()) }
+}
+
+impl From<serde_json::Error> for SaveShopError {
+    fn from(err: serde_json::Error) -> Self { SaveShopError::Json(err) }
+}
+
+impl From<std::io::Error> for SaveShopError {
+    fn from(err: std::io::Error) -> Self { SaveShopError::Filesystem(err) }
+}
+
+impl From<rusqlite::Error> for SaveShopError {
+    fn from(err: rusqlite::Error) -> Self { SaveShopError::Database(err) }
+}
+
+// Returns an error of type SchemaMismatch if the given condition doesn't hold
+macro_rules! ensure_schema {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err($crate::error::SaveShopError::SchemaMismatch(format!($($arg)+)));
+        }
+    };
+}
+pub(crate) use ensure_schema;
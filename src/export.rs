@@ -0,0 +1,556 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::error::SaveShopError;
+use crate::prices::{self, PriceValue};
+use crate::title_ids::{self, ContentLock};
+mod sqlite;
+pub use sqlite::export_sqlite;
+
+use crate::{
+    contained_files, ec_info_path, movie_url_to_filename, platform_name, read_document, record_failure, samurai_dir, url_to_filename,
+    DemoDocument, DemoTitle, DirectoryDocument, EcInfoDocument, Locale, MediaReferences, MovieDocument, NodeContents, NodeFeature,
+    NodeMovie, NodeRatingInfo, NodeTitle, NodeTitleOrMovie, RankingDocument, TitleDocument,
+};
+
+// Bump when making incompatible changes to the exported documents
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, JsonSchema)]
+struct Named {
+    id: Option<String>,
+    name: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct MediaAsset {
+    /// E.g. "icon", "banner" or "upper screenshot"
+    kind: String,
+    url: String,
+    /// Location in the archive, relative to its root. None if the URL isn't recognized
+    path: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct MovieFile {
+    url: String,
+    /// "2d" or "3d"
+    dimension: String,
+    path: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Thumbnail {
+    url: String,
+    #[serde(rename = "type")]
+    thumbnail_type: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct RatingIcon {
+    url: String,
+    /// "small" or "large"
+    #[serde(rename = "type")]
+    icon_type: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ContentDescriptor {
+    #[serde(rename = "type")]
+    descriptor_type: Option<String>,
+    name: String,
+    icon_url: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Rating {
+    id: Option<String>,
+    /// E.g. "ESRB"
+    system: Option<String>,
+    system_id: Option<String>,
+    name: Option<String>,
+    age: Option<String>,
+    icons: Vec<RatingIcon>,
+    /// Names of the content descriptors, which are listed in full in content_descriptors
+    descriptors: Vec<String>,
+    content_descriptors: Vec<ContentDescriptor>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct StarRating {
+    score: Option<f32>,
+    votes: Option<u32>,
+    /// Number of votes for 1 to 5 stars
+    votes_per_star: Vec<Option<u32>>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Language {
+    iso_code: String,
+    name: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Feature {
+    id: Option<String>,
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Price {
+    amount: String,
+    currency: String,
+    raw_value: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Discount {
+    #[serde(flatten)]
+    price: Price,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Prices {
+    sales_status: Option<String>,
+    /// Whether the title is on sale and has a price
+    purchasable: bool,
+    regular: Option<Price>,
+    discount: Option<Discount>,
+}
+
+/// Information about the installable title behind a content item
+#[derive(Serialize, JsonSchema)]
+struct EcInfo {
+    /// 16 hex digits, e.g. "0004000000030800"
+    title_id: String,
+    content_size: Option<u64>,
+    title_version: Option<u32>,
+    disable_download: Option<bool>,
+    content_lock: Option<ContentLock>,
+}
+
+/// XML element that isn't covered by this schema, kept verbatim
+#[derive(Serialize, JsonSchema)]
+struct UnknownElement {
+    name: String,
+    xml: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Title {
+    id: String,
+    name: String,
+    formal_name: Option<String>,
+    product_code: Option<String>,
+    description: Option<String>,
+    catch_copy: Option<String>,
+    platform: Option<Named>,
+    /// E.g. "CTR" or "WUP"
+    platform_device: Option<String>,
+    publisher: Option<Named>,
+    display_genre: Option<String>,
+    genres: Vec<Named>,
+    keywords: Vec<String>,
+    release_date_on_eshop: Option<String>,
+    release_date_on_original: Option<String>,
+    retail_sales: Option<bool>,
+    eshop_sales: Option<bool>,
+    in_app_purchase: Option<bool>,
+    /// Whether the eShop marks the title as new
+    new: Option<bool>,
+    aoc_available: bool,
+    /// Wii U only: Whether the title can be played on the GamePad screen alone
+    off_tv_play: Option<bool>,
+    /// Wii U only: Whether the title has a Miiverse community
+    miiverse: Option<bool>,
+    rating: Option<Rating>,
+    alternate_rating_image_url: Option<String>,
+    star_rating: Option<StarRating>,
+    languages: Vec<Language>,
+    number_of_players: Option<String>,
+    features: Vec<Feature>,
+    network_features: Vec<Feature>,
+    data_size: Option<String>,
+    disclaimer: Option<String>,
+    copyright: Option<String>,
+    copyright_image_url: Option<String>,
+    /// Also listed in media
+    thumbnails: Vec<Thumbnail>,
+    demo_ids: Vec<String>,
+    movie_ids: Vec<String>,
+    /// None if no price information was fetched
+    prices: Option<Prices>,
+    /// None if no ec_info was fetched
+    ec_info: Option<EcInfo>,
+    media: Vec<MediaAsset>,
+    movie_files: Vec<MovieFile>,
+    unknown_elements: Vec<UnknownElement>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Demo {
+    id: String,
+    name: String,
+    rating: Option<Rating>,
+    ec_info: Option<EcInfo>,
+    media: Vec<MediaAsset>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Movie {
+    id: String,
+    name: String,
+    rating: Option<Rating>,
+    media: Vec<MediaAsset>,
+    movie_files: Vec<MovieFile>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ListEntryKind {
+    Title,
+    Movie,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ListEntry {
+    kind: ListEntryKind,
+    id: String,
+    /// Position in the list as reported by the server
+    index: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Directory {
+    id: String,
+    name: String,
+    description: Option<String>,
+    media: Vec<MediaAsset>,
+    contents: Vec<ListEntry>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Ranking {
+    id: String,
+    name: Option<String>,
+    contents: Vec<ListEntry>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Item {
+    Title(Box<Title>),
+    Demo(Demo),
+    Movie(Movie),
+    Directory(Directory),
+    Ranking(Ranking),
+}
+
+/// A single exported content item
+#[derive(Serialize, JsonSchema)]
+struct ItemDocument {
+    /// Incremented on incompatible changes to this schema
+    schema_version: u32,
+    /// Platform the item was fetched for, as given to --platform (e.g. "3ds" or "wiiu")
+    platform: String,
+    region: String,
+    language: String,
+    #[serde(flatten)]
+    item: Item,
+}
+
+impl Item {
+    fn id_and_name(&self) -> (&str, Option<&str>) {
+        match self {
+            Item::Title(title) => (&title.id, Some(&title.name)),
+            Item::Demo(demo) => (&demo.id, Some(&demo.name)),
+            Item::Movie(movie) => (&movie.id, Some(&movie.name)),
+            Item::Directory(directory) => (&directory.id, Some(&directory.name)),
+            Item::Ranking(ranking) => (&ranking.id, ranking.name.as_deref()),
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+struct IndexEntry {
+    /// One of "title", "demo", "movie", "directory" or "ranking"
+    kind: String,
+    region: String,
+    language: String,
+    id: String,
+    name: Option<String>,
+    /// Location of the item document, relative to the index
+    path: String,
+}
+
+/// List of all exported content items
+#[derive(Serialize, JsonSchema)]
+struct Index {
+    /// Incremented on incompatible changes to this schema
+    schema_version: u32,
+    /// Platform the items were fetched for, as given to --platform (e.g. "3ds" or "wiiu")
+    platform: String,
+    items: Vec<IndexEntry>,
+}
+
+fn media_assets(media: MediaReferences) -> (Vec<MediaAsset>, Vec<MovieFile>) {
+    let assets = media.resources.into_iter()
+        .map(|(kind, url)| MediaAsset { path: url_to_filename(&url).ok(), kind, url })
+        .collect();
+    let movie_files = media.movie_files.into_iter()
+        .map(|file| MovieFile { path: movie_url_to_filename(&file.movie_url).ok(), url: file.movie_url, dimension: file.dimension })
+        .collect();
+    (assets, movie_files)
+}
+
+fn rating(rating_info: &Option<NodeRatingInfo>) -> Option<Rating> {
+    rating_info.as_ref().map(|info| Rating {
+        id: info.rating.id.clone(),
+        system: info.rating_system.as_ref().map(|system| system.name.clone()),
+        system_id: info.rating_system.as_ref().and_then(|system| system.id.clone()),
+        name: info.rating.name.clone(),
+        age: info.rating.age.clone(),
+        icons: info.rating.icons.icon.iter().map(|icon| RatingIcon { url: icon.url.clone(), icon_type: icon.icon_type.clone() }).collect(),
+        descriptors: info.content_descriptors.iter().flat_map(|d| &d.content_descriptor).map(|d| d.name.clone()).collect(),
+        content_descriptors: info.content_descriptors.iter().flat_map(|d| &d.content_descriptor).map(|d| ContentDescriptor {
+            descriptor_type: d.descriptor_type.clone(),
+            name: d.name.clone(),
+            icon_url: d.icon_url.clone(),
+        }).collect(),
+    })
+}
+
+fn features(features: &[NodeFeature]) -> Vec<Feature> {
+    features.iter().map(|f| Feature { id: f.id.clone(), name: f.name.clone(), description: f.description.clone() }).collect()
+}
+
+fn list_entries(contents: Option<NodeContents>) -> Vec<ListEntry> {
+    contents.into_iter().flat_map(|c| c.content).map(|content| match content.title_or_movie {
+        NodeTitleOrMovie::Title(title) => ListEntry { kind: ListEntryKind::Title, id: title.id, index: content.index },
+        NodeTitleOrMovie::Movie(movie) => ListEntry { kind: ListEntryKind::Movie, id: movie.id, index: content.index },
+    }).collect()
+}
+
+fn read_prices(locale: &Locale, title_id: &str) -> Result<Option<Prices>, SaveShopError> {
+    let price_of = |value: PriceValue| Price { amount: value.amount, currency: value.currency, raw_value: value.raw_value };
+    Ok(prices::read_title_price(locale, title_id)?.map(|price| Prices {
+        purchasable: price.is_purchasable(),
+        sales_status: price.sales_status,
+        regular: price.regular.map(price_of),
+        discount: price.discount.map(|discount| Discount { price: price_of(discount.price), start: discount.start, end: discount.end }),
+    }))
+}
+
+fn read_ec_info(locale: &Locale, content_id: &str) -> Result<Option<EcInfo>, SaveShopError> {
+    let path = ec_info_path(locale, content_id);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let ec_info = read_document::<EcInfoDocument>(Path::new(&path))?.title_ec_info;
+    Ok(Some(EcInfo {
+        title_id: ec_info.title_id,
+        content_size: ec_info.content_size,
+        title_version: ec_info.title_version,
+        disable_download: ec_info.disable_download,
+        content_lock: ec_info.content_lock.map(|lock| ContentLock {
+            seed_published: lock.seed_published,
+            external_seed: lock.external_seed,
+            playable_date: lock.playable_date,
+        }),
+    }))
+}
+
+fn export_title(title: NodeTitle, locale: &Locale) -> Result<Title, SaveShopError> {
+    let mut media = MediaReferences::default();
+    media.add_title(&title);
+    let (media, movie_files) = media_assets(media);
+
+    Ok(Title {
+        prices: read_prices(locale, &title.id)?,
+        ec_info: read_ec_info(locale, &title.id)?,
+        name: title.name,
+        formal_name: title.formal_name,
+        product_code: title.product_code,
+        description: title.description,
+        catch_copy: title.catch_copy,
+        platform: title.platform.name.map(|name| Named { id: title.platform.id, name }),
+        platform_device: title.platform.device,
+        publisher: title.publisher.map(|p| Named { id: p.id, name: p.name }),
+        display_genre: title.display_genre,
+        genres: title.genres.into_iter().flat_map(|g| g.genre).map(|g| Named { id: g.id, name: g.name }).collect(),
+        keywords: title.keywords.map(|k| k.keyword).unwrap_or_default(),
+        release_date_on_eshop: title.release_date_on_eshop,
+        release_date_on_original: title.release_date_on_original,
+        retail_sales: title.retail_sales,
+        eshop_sales: title.eshop_sales,
+        in_app_purchase: title.in_app_purchase,
+        new: title.new,
+        aoc_available: title.aoc_available,
+        off_tv_play: title.off_tv_play,
+        miiverse: title.miiverse,
+        rating: rating(&title.rating_info),
+        alternate_rating_image_url: title.alternate_rating_image_url,
+        star_rating: title.star_rating_info.map(|s| StarRating {
+            score: s.score,
+            votes: s.votes,
+            votes_per_star: vec![s.star1, s.star2, s.star3, s.star4, s.star5],
+        }),
+        languages: title.languages.into_iter().flat_map(|l| l.language)
+                        .map(|l| Language { iso_code: l.iso_code, name: l.name }).collect(),
+        number_of_players: title.number_of_players,
+        features: features(title.features.as_ref().map(|f| &f.feature[..]).unwrap_or_default()),
+        network_features: features(title.network_feature_info.as_ref().map(|f| &f.network_feature[..]).unwrap_or_default()),
+        data_size: title.data_size,
+        disclaimer: title.disclaimer,
+        copyright_image_url: title.copyright.as_ref().and_then(|c| c.image_url.clone()),
+        copyright: title.copyright.and_then(|c| c.text),
+        thumbnails: title.thumbnails.thumbnail.into_iter().map(|t| Thumbnail {
+            url: t.url,
+            thumbnail_type: t.thumbnail_type,
+            width: t.width,
+            height: t.height,
+        }).collect(),
+        demo_ids: title.demo_titles.into_iter().flat_map(|d| d.demo_title).map(|d| d.id).collect(),
+        movie_ids: title.movies.into_iter().flat_map(|m| m.movie).map(|m| m.id).collect(),
+        media,
+        movie_files,
+        unknown_elements: title.unknown_elements.into_iter().map(|e| UnknownElement { name: e.name, xml: e.xml }).collect(),
+        id: title.id,
+    })
+}
+
+fn export_demo(demo: DemoTitle, locale: &Locale) -> Result<Demo, SaveShopError> {
+    let mut media = MediaReferences::default();
+    media.add_demo(&demo);
+    Ok(Demo {
+        rating: rating(&demo.rating_info),
+        ec_info: read_ec_info(locale, &demo.id)?,
+        media: media_assets(media).0,
+        id: demo.id,
+        name: demo.name,
+    })
+}
+
+fn export_movie(movie: NodeMovie) -> Movie {
+    let mut media = MediaReferences::default();
+    media.add_movie(&movie);
+    let (media, movie_files) = media_assets(media);
+    Movie { rating: rating(&movie.rating_info), media, movie_files, id: movie.id, name: movie.name }
+}
+
+fn parse_item(kind: &str, path: &Path, locale: &Locale) -> Result<Item, SaveShopError> {
+    Ok(match kind {
+        "title" => Item::Title(Box::new(export_title(TitleDocument::read(path)?.title, locale)?)),
+        "demo" => Item::Demo(export_demo(read_document::<DemoDocument>(path)?.content.demo, locale)?),
+        "movie" => Item::Movie(export_movie(read_document::<MovieDocument>(path)?.movie)),
+        "directory" => {
+            let directory = read_document::<DirectoryDocument>(path)?.directory;
+            let mut media = MediaReferences::default();
+            media.add_directory(&directory);
+            Item::Directory(Directory {
+                media: media_assets(media).0,
+                contents: list_entries(directory.contents),
+                id: directory.id,
+                name: directory.name,
+                description: directory.description,
+            })
+        },
+        _ => {
+            let ranking = read_document::<RankingDocument>(path)?.ranking;
+            Item::Ranking(Ranking { contents: list_entries(ranking.contents), id: ranking.id, name: ranking.name })
+        },
+    })
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), SaveShopError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+// Parses all fetched metadata for the given regions and passes each item to the given function along with the path
+// of its document. Items that fail to parse are recorded as failures and skipped
+fn for_each_item<F>(regions: &[String], mut f: F) -> Result<(), SaveShopError>
+        where F: FnMut(&Locale, &str, &Path, Item) -> Result<(), SaveShopError> {
+    for region in regions {
+        let dir_entries = fs::read_dir(samurai_dir(region)).into_iter().flatten().flatten();
+        for subdir in dir_entries.filter(|f| f.file_type().is_ok_and(|t| t.is_dir())) {
+            let locale = Locale { region: region.clone(), language: subdir.file_name().to_string_lossy().into_owned() };
+            println!("Exporting region {} / language {}", locale.region, locale.language);
+
+            for kind in ["title", "demo", "movie", "directory", "ranking"] {
+                let mut paths: Vec<PathBuf> = contained_files(subdir.path().join(kind)).map(|f| f.path()).collect();
+                paths.sort_unstable();
+                for path in paths {
+                    match parse_item(kind, &path, &locale) {
+                        Ok(item) => f(&locale, kind, &path, item)?,
+                        Err(err) => record_failure(path.display().to_string(), err),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Fails if the output already holds an export of a different platform, which would otherwise be overwritten in parts
+fn check_export_platform(output: &Path, exported_platform: Option<String>) -> Result<(), SaveShopError> {
+    match exported_platform {
+        Some(exported_platform) if exported_platform != platform_name() =>
+            Err(SaveShopError::Config(format!("{} holds an export for platform {}. Use a different --output for platform {}",
+                                              output.display(), exported_platform, platform_name()))),
+        _ => Ok(()),
+    }
+}
+
+// Exports all fetched metadata for the given regions to JSON files in the output directory, along with an index,
+// the title ID mapping and JSON schemas describing each
+pub fn export_json(regions: &[String], output: &Path) -> Result<(), SaveShopError> {
+    // Exports written before the platform was recorded are assumed to be replaceable
+    let previous_index = fs::read_to_string(output.join("index.json")).ok()
+        .and_then(|index| serde_json::from_str::<serde_json::Value>(&index).ok());
+    check_export_platform(output, previous_index.and_then(|index| index["platform"].as_str().map(str::to_string)))?;
+
+    let mut index = Index { schema_version: SCHEMA_VERSION, platform: platform_name().to_string(), items: Vec::new() };
+
+    for_each_item(regions, |locale, kind, _, item| {
+        let (id, name) = item.id_and_name();
+        let (id, name) = (id.to_string(), name.map(str::to_string));
+
+        let item_path = format!("{}/{}/{}/{}.json", locale.region, locale.language, kind, id);
+        write_json(&output.join(&item_path), &ItemDocument {
+            schema_version: SCHEMA_VERSION,
+            platform: platform_name().to_string(),
+            region: locale.region.clone(),
+            language: locale.language.clone(),
+            item,
+        })?;
+        index.items.push(IndexEntry {
+            kind: kind.to_string(),
+            region: locale.region.clone(),
+            language: locale.language.clone(),
+            id,
+            name,
+            path: item_path,
+        });
+        Ok(())
+    })?;
+
+    write_json(&output.join("index.json"), &index)?;
+    write_json(&output.join("item.schema.json"), &schemars::schema_for!(ItemDocument))?;
+    write_json(&output.join("index.schema.json"), &schemars::schema_for!(Index))?;
+    write_json(&output.join("title_ids.json"), &title_ids::collect(regions)?)?;
+    write_json(&output.join("title_ids.schema.json"), &schemars::schema_for!(title_ids::TitleIdMapping))?;
+    println!("Exported {} items to {}", index.items.len(), output.display());
+    Ok(())
+}
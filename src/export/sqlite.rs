@@ -0,0 +1,337 @@
+use std::fs;
+use std::path::Path;
+
+use rusqlite::{params, Connection, Transaction};
+
+use super::{check_export_platform, for_each_item, EcInfo, Item, ListEntry, ListEntryKind, MediaAsset, MovieFile, Prices, Rating, SCHEMA_VERSION};
+use crate::error::SaveShopError;
+use crate::{platform_name, Locale};
+
+// Items are keyed by (region, language, id), since names and descriptions differ across locales
+const SCHEMA: &str = "
+CREATE TABLE metadata (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+CREATE TABLE titles (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    formal_name TEXT,
+    product_code TEXT,
+    description TEXT,
+    catch_copy TEXT,
+    platform_id TEXT,
+    platform_name TEXT,
+    publisher_id TEXT,
+    publisher_name TEXT,
+    display_genre TEXT,
+    release_date_on_eshop TEXT,
+    release_date_on_original TEXT,
+    retail_sales INTEGER,
+    eshop_sales INTEGER,
+    in_app_purchase INTEGER,
+    aoc_available INTEGER NOT NULL,
+    off_tv_play INTEGER,
+    miiverse INTEGER,
+    rating_system TEXT,
+    rating_name TEXT,
+    rating_age TEXT,
+    star_rating_score REAL,
+    star_rating_votes INTEGER,
+    number_of_players TEXT,
+    data_size TEXT,
+    disclaimer TEXT,
+    copyright TEXT,
+    -- Location of the samurai document, relative to the archive root
+    path TEXT NOT NULL,
+    PRIMARY KEY (region, language, id)
+);
+
+CREATE TABLE title_demos (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    title_id TEXT NOT NULL,
+    demo_id TEXT NOT NULL
+);
+
+CREATE TABLE title_movies (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    title_id TEXT NOT NULL,
+    movie_id TEXT NOT NULL
+);
+
+CREATE TABLE demos (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    rating_system TEXT,
+    rating_name TEXT,
+    rating_age TEXT,
+    path TEXT NOT NULL,
+    PRIMARY KEY (region, language, id)
+);
+
+CREATE TABLE movies (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    rating_system TEXT,
+    rating_name TEXT,
+    rating_age TEXT,
+    path TEXT NOT NULL,
+    PRIMARY KEY (region, language, id)
+);
+
+CREATE TABLE directories (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    description TEXT,
+    path TEXT NOT NULL,
+    PRIMARY KEY (region, language, id)
+);
+
+CREATE TABLE directory_contents (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    directory_id TEXT NOT NULL,
+    -- 0-based position in the list
+    position INTEGER NOT NULL,
+    -- 'title' or 'movie'
+    kind TEXT NOT NULL,
+    content_id TEXT NOT NULL,
+    PRIMARY KEY (region, language, directory_id, position)
+);
+
+CREATE TABLE rankings (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    id TEXT NOT NULL,
+    name TEXT,
+    path TEXT NOT NULL,
+    PRIMARY KEY (region, language, id)
+);
+
+CREATE TABLE ranking_contents (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    ranking_id TEXT NOT NULL,
+    -- 0-based position in the list. The index reported by the server restarts on every page
+    position INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    content_id TEXT NOT NULL,
+    PRIMARY KEY (region, language, ranking_id, position)
+);
+
+CREATE TABLE prices (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    title_id TEXT NOT NULL,
+    sales_status TEXT,
+    regular_amount TEXT,
+    regular_currency TEXT,
+    regular_raw_value TEXT,
+    discount_amount TEXT,
+    discount_currency TEXT,
+    discount_raw_value TEXT,
+    discount_start TEXT,
+    discount_end TEXT,
+    PRIMARY KEY (region, language, title_id)
+);
+
+CREATE TABLE ec_info (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    -- ID of a title or demo
+    content_id TEXT NOT NULL,
+    title_id TEXT NOT NULL,
+    content_size INTEGER,
+    title_version INTEGER,
+    disable_download INTEGER,
+    -- NULL unless the content was locked for pre-purchase
+    seed_published INTEGER,
+    playable_date TEXT,
+    PRIMARY KEY (region, language, content_id)
+);
+CREATE INDEX ec_info_title_id ON ec_info (title_id);
+
+CREATE TABLE media_assets (
+    region TEXT NOT NULL,
+    language TEXT NOT NULL,
+    -- 'title', 'demo', 'movie' or 'directory'
+    owner_kind TEXT NOT NULL,
+    owner_id TEXT NOT NULL,
+    -- E.g. 'icon' or 'banner'. 'movie' for video files
+    kind TEXT NOT NULL,
+    -- '2d' or '3d' for video files
+    dimension TEXT,
+    url TEXT NOT NULL,
+    -- Location in the archive, relative to its root. NULL if the URL isn't recognized
+    path TEXT,
+    -- NULL if the file wasn't fetched
+    size INTEGER
+);
+CREATE INDEX media_assets_owner ON media_assets (region, language, owner_kind, owner_id);
+";
+
+fn rating_columns(rating: &Option<Rating>) -> (Option<&str>, Option<&str>, Option<&str>) {
+    match rating {
+        Some(rating) => (rating.system.as_deref(), rating.name.as_deref(), rating.age.as_deref()),
+        None => (None, None, None),
+    }
+}
+
+fn insert_media(tx: &Transaction, locale: &Locale, owner_kind: &str, owner_id: &str,
+                media: &[MediaAsset], movie_files: &[MovieFile]) -> Result<(), SaveShopError> {
+    let mut stmt = tx.prepare_cached("INSERT INTO media_assets VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+    let file_size = |path: &Option<String>| path.as_ref().and_then(|path| fs::metadata(path).ok()).map(|m| m.len());
+    for asset in media {
+        stmt.execute(params![locale.region, locale.language, owner_kind, owner_id, asset.kind, None::<&str>,
+                             asset.url, asset.path, file_size(&asset.path)])?;
+    }
+    for file in movie_files {
+        stmt.execute(params![locale.region, locale.language, owner_kind, owner_id, "movie", file.dimension,
+                             file.url, file.path, file_size(&file.path)])?;
+    }
+    Ok(())
+}
+
+fn insert_list(tx: &Transaction, table: &str, locale: &Locale, list_id: &str, contents: &[ListEntry]) -> Result<(), SaveShopError> {
+    let mut stmt = tx.prepare_cached(&format!("INSERT INTO {} VALUES (?, ?, ?, ?, ?, ?)", table))?;
+    for (position, entry) in contents.iter().enumerate() {
+        let kind = match entry.kind {
+            ListEntryKind::Title => "title",
+            ListEntryKind::Movie => "movie",
+        };
+        stmt.execute(params![locale.region, locale.language, list_id, position, kind, entry.id])?;
+    }
+    Ok(())
+}
+
+fn insert_prices(tx: &Transaction, locale: &Locale, title_id: &str, prices: &Prices) -> Result<(), SaveShopError> {
+    let regular = prices.regular.as_ref();
+    let discount = prices.discount.as_ref();
+    tx.execute("INSERT INTO prices VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", params![
+        locale.region, locale.language, title_id, prices.sales_status,
+        regular.map(|p| &p.amount), regular.map(|p| &p.currency), regular.and_then(|p| p.raw_value.as_ref()),
+        discount.map(|d| &d.price.amount), discount.map(|d| &d.price.currency), discount.and_then(|d| d.price.raw_value.as_ref()),
+        discount.and_then(|d| d.start.as_ref()), discount.and_then(|d| d.end.as_ref()),
+    ])?;
+    Ok(())
+}
+
+fn insert_ec_info(tx: &Transaction, locale: &Locale, content_id: &str, ec_info: &EcInfo) -> Result<(), SaveShopError> {
+    let content_lock = ec_info.content_lock.as_ref();
+    tx.execute("INSERT INTO ec_info VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)", params![
+        locale.region, locale.language, content_id, ec_info.title_id, ec_info.content_size, ec_info.title_version,
+        ec_info.disable_download, content_lock.and_then(|l| l.seed_published), content_lock.and_then(|l| l.playable_date.as_ref()),
+    ])?;
+    Ok(())
+}
+
+fn insert_item(tx: &Transaction, locale: &Locale, path: &str, item: &Item) -> Result<(), SaveShopError> {
+    match item {
+        Item::Title(title) => {
+            let (rating_system, rating_name, rating_age) = rating_columns(&title.rating);
+            let star_rating = title.star_rating.as_ref();
+            tx.execute("INSERT INTO titles VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", params![
+                locale.region, locale.language, title.id, title.name, title.formal_name, title.product_code,
+                title.description, title.catch_copy,
+                title.platform.as_ref().and_then(|p| p.id.as_ref()), title.platform.as_ref().map(|p| &p.name),
+                title.publisher.as_ref().and_then(|p| p.id.as_ref()), title.publisher.as_ref().map(|p| &p.name),
+                title.display_genre, title.release_date_on_eshop, title.release_date_on_original,
+                title.retail_sales, title.eshop_sales, title.in_app_purchase, title.aoc_available, title.off_tv_play, title.miiverse,
+                rating_system, rating_name, rating_age,
+                star_rating.and_then(|s| s.score), star_rating.and_then(|s| s.votes),
+                title.number_of_players, title.data_size, title.disclaimer, title.copyright, path,
+            ])?;
+            for demo_id in &title.demo_ids {
+                tx.execute("INSERT INTO title_demos VALUES (?, ?, ?, ?)", params![locale.region, locale.language, title.id, demo_id])?;
+            }
+            for movie_id in &title.movie_ids {
+                tx.execute("INSERT INTO title_movies VALUES (?, ?, ?, ?)", params![locale.region, locale.language, title.id, movie_id])?;
+            }
+            if let Some(prices) = &title.prices {
+                insert_prices(tx, locale, &title.id, prices)?;
+            }
+            if let Some(ec_info) = &title.ec_info {
+                insert_ec_info(tx, locale, &title.id, ec_info)?;
+            }
+            insert_media(tx, locale, "title", &title.id, &title.media, &title.movie_files)?;
+        },
+        Item::Demo(demo) => {
+            let (rating_system, rating_name, rating_age) = rating_columns(&demo.rating);
+            tx.execute("INSERT INTO demos VALUES (?, ?, ?, ?, ?, ?, ?, ?)", params![
+                locale.region, locale.language, demo.id, demo.name, rating_system, rating_name, rating_age, path,
+            ])?;
+            if let Some(ec_info) = &demo.ec_info {
+                insert_ec_info(tx, locale, &demo.id, ec_info)?;
+            }
+            insert_media(tx, locale, "demo", &demo.id, &demo.media, &[])?;
+        },
+        Item::Movie(movie) => {
+            let (rating_system, rating_name, rating_age) = rating_columns(&movie.rating);
+            tx.execute("INSERT INTO movies VALUES (?, ?, ?, ?, ?, ?, ?, ?)", params![
+                locale.region, locale.language, movie.id, movie.name, rating_system, rating_name, rating_age, path,
+            ])?;
+            insert_media(tx, locale, "movie", &movie.id, &movie.media, &movie.movie_files)?;
+        },
+        Item::Directory(directory) => {
+            tx.execute("INSERT INTO directories VALUES (?, ?, ?, ?, ?, ?)", params![
+                locale.region, locale.language, directory.id, directory.name, directory.description, path,
+            ])?;
+            insert_list(tx, "directory_contents", locale, &directory.id, &directory.contents)?;
+            insert_media(tx, locale, "directory", &directory.id, &directory.media, &[])?;
+        },
+        Item::Ranking(ranking) => {
+            tx.execute("INSERT INTO rankings VALUES (?, ?, ?, ?, ?)", params![
+                locale.region, locale.language, ranking.id, ranking.name, path,
+            ])?;
+            insert_list(tx, "ranking_contents", locale, &ranking.id, &ranking.contents)?;
+        },
+    }
+    Ok(())
+}
+
+// Exports all fetched metadata for the given regions to a new SQLite database.
+// The database is built under a temporary name and only replaces the output file once complete
+pub fn export_sqlite(regions: &[String], output: &Path) -> Result<(), SaveShopError> {
+    if output.exists() {
+        // Databases written before the platform was recorded (or by other tools) are assumed to be replaceable
+        let db = Connection::open_with_flags(output, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let exported_platform = db.query_row("SELECT value FROM metadata WHERE key = 'platform'", [], |row| row.get(0)).ok();
+        check_export_platform(output, exported_platform)?;
+    }
+
+    let temp_path = output.with_extension("part");
+    match fs::remove_file(&temp_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {},
+    }
+
+    let mut db = Connection::open(&temp_path)?;
+    let tx = db.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+    tx.execute("INSERT INTO metadata VALUES ('schema_version', ?)", [SCHEMA_VERSION.to_string()])?;
+    tx.execute("INSERT INTO metadata VALUES ('platform', ?)", [platform_name()])?;
+
+    let mut num_items = 0;
+    for_each_item(regions, |locale, _, path, item| {
+        insert_item(&tx, locale, &path.to_string_lossy(), &item)?;
+        num_items += 1;
+        Ok(())
+    })?;
+
+    tx.commit()?;
+    db.close().map_err(|(_, err)| err)?;
+    fs::rename(&temp_path, output)?;
+    println!("Exported {} items to {}", num_items, output.display());
+    Ok(())
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::error::SaveShopError;
+use crate::scheduler::HostClass;
+use crate::PLATFORMS;
+
+// Default servers by platform. The Wii U eShop has its own servers, while the 3DS ones also answer the remaining shop IDs
+const DEFAULT_SAMURAI_URL: &str = "https://samurai.ctr.shop.nintendo.net/samurai/ws";
+const DEFAULT_NINJA_URL: &str = "https://ninja.ctr.shop.nintendo.net/ninja/ws";
+const WUP_SAMURAI_URL: &str = "https://samurai.wup.shop.nintendo.net/samurai/ws";
+const WUP_NINJA_URL: &str = "https://ninja.wup.shop.nintendo.net/ninja/ws";
+
+// Servers to fetch data from, and how media URLs map to local paths.
+// Read from the file given by --config, with command-line options taking precedence
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostConfig {
+    // Base URLs without the region by platform (as given to --platform),
+    // e.g. "3ds" -> "https://samurai.ctr.shop.nintendo.net/samurai/ws"
+    samurai_urls: BTreeMap<String, String>,
+    ninja_urls: BTreeMap<String, String>,
+    // Servers to fetch the files of a CDN host from instead,
+    // e.g. "kanzashi-ctr.cdn.nintendo.net" -> "https://mirror.example.org/kanzashi-ctr"
+    cdn_urls: BTreeMap<String, String>,
+    // URL prefixes to treat like a CDN host when mapping URLs to local paths,
+    // e.g. "https://mirror.example.org/kanzashi-ctr" -> "kanzashi-ctr.cdn.nintendo.net"
+    host_aliases: BTreeMap<String, String>,
+}
+
+// Parses a "KEY=VALUE" command-line argument
+pub fn parse_mapping(value: &str) -> Result<(String, String), String> {
+    value.split_once('=')
+         .map(|(key, value)| (key.to_string(), value.to_string()))
+         .ok_or_else(|| format!("expected KEY=VALUE, got \"{}\"", value))
+}
+
+fn check_url(url: &str) -> Result<(), SaveShopError> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        Ok(())
+    } else {
+        Err(SaveShopError::Config(format!("\"{}\" is not an http(s) URL", url)))
+    }
+}
+
+fn check_host(host: &str) -> Result<(), SaveShopError> {
+    if host.is_empty() || host.contains(['/', ':']) {
+        Err(SaveShopError::Config(format!("\"{}\" is not a host name", host)))
+    } else {
+        Ok(())
+    }
+}
+
+// Returns the part of the URL after the given prefix, if the prefix ends at a path boundary
+fn strip_url_prefix<'a>(url: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    url.strip_prefix(prefix).filter(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+impl HostConfig {
+    pub fn load(path: &Path) -> Result<HostConfig, SaveShopError> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    // Applies the command-line options on top of the configuration file and checks the result.
+    // The samurai and ninja URLs given on the command line only apply to the selected platform
+    pub fn apply_overrides(&mut self, platform: &str, samurai_url: Option<String>, ninja_url: Option<String>,
+                           cdn_urls: &[(String, String)], host_aliases: &[(String, String)]) -> Result<(), SaveShopError> {
+        if let Some(samurai_url) = samurai_url {
+            self.samurai_urls.insert(platform.to_string(), samurai_url);
+        }
+        if let Some(ninja_url) = ninja_url {
+            self.ninja_urls.insert(platform.to_string(), ninja_url);
+        }
+        self.cdn_urls.extend(cdn_urls.iter().cloned());
+        self.host_aliases.extend(host_aliases.iter().cloned());
+
+        for (platform, url) in self.samurai_urls.iter().chain(&self.ninja_urls) {
+            if !PLATFORMS.contains(&platform.as_str()) {
+                return Err(SaveShopError::Config(format!("\"{}\" is not a platform (expected one of {})", platform, PLATFORMS.join(", "))));
+            }
+            check_url(url)?;
+        }
+        for (host, url) in &self.cdn_urls {
+            check_host(host)?;
+            check_url(url)?;
+        }
+        for (prefix, host) in &self.host_aliases {
+            check_url(prefix)?;
+            check_host(host)?;
+        }
+        Ok(())
+    }
+
+    pub fn samurai_url(&self, platform: &str) -> &str {
+        let default_url = if platform == "wiiu" { WUP_SAMURAI_URL } else { DEFAULT_SAMURAI_URL };
+        self.samurai_urls.get(platform).map_or(default_url, String::as_str).trim_end_matches('/')
+    }
+
+    pub fn ninja_url(&self, platform: &str) -> &str {
+        let default_url = if platform == "wiiu" { WUP_NINJA_URL } else { DEFAULT_NINJA_URL };
+        self.ninja_urls.get(platform).map_or(default_url, String::as_str).trim_end_matches('/')
+    }
+
+    // Returns the URL a media URL would have on the CDN host it's an alias for.
+    // URLs not covered by any alias are returned unchanged
+    pub fn canonical_url(&self, url: &str) -> String {
+        // Prefer the longest matching prefix
+        self.host_aliases.iter()
+            .filter_map(|(prefix, host)| strip_url_prefix(url, prefix).map(|path| (prefix.len(), host, path)))
+            .max_by_key(|(prefix_len, _, _)| *prefix_len)
+            .map(|(_, host, path)| format!("https://{}{}", host, path))
+            .unwrap_or_else(|| url.to_string())
+    }
+
+    // Determines the eShop server a request goes to, so that it's rate-limited as such.
+    // Requests to configured servers and aliased media hosts count towards the servers they replace
+    pub fn host_class(&self, url: &str) -> Option<HostClass> {
+        if PLATFORMS.iter().any(|platform| strip_url_prefix(url, self.samurai_url(platform)).is_some()) {
+            Some(HostClass::Samurai)
+        } else if PLATFORMS.iter().any(|platform| strip_url_prefix(url, self.ninja_url(platform)).is_some()) {
+            Some(HostClass::Ninja)
+        } else {
+            HostClass::from_url(&self.canonical_url(url))
+        }
+    }
+
+    // Returns the URL to fetch a media file from, taking into account CDN hosts served by a different server
+    pub fn media_request_url(&self, url: &str) -> String {
+        let canonical_url = self.canonical_url(url);
+        let mirror = canonical_url.strip_prefix("https://")
+            .and_then(|host_and_path| host_and_path.split_once('/'))
+            .and_then(|(host, path)| self.cdn_urls.get(host).map(|base_url| format!("{}/{}", base_url.trim_end_matches('/'), path)));
+        mirror.unwrap_or_else(|| url.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> HostConfig {
+        let mut config: HostConfig = serde_json::from_str(json).unwrap();
+        config.apply_overrides("3ds", None, None, &[], &[]).unwrap();
+        config
+    }
+
+    #[test]
+    fn canonical_url_maps_aliased_prefixes() {
+        let config = config(r#"{ "host_aliases": {
+            "https://mirror.test/cdn": "kanzashi-ctr.cdn.nintendo.net",
+            "https://mirror.test/cdn/movie/": "kanzashi-movie-ctr.cdn.nintendo.net"
+        } }"#);
+        assert_eq!(config.canonical_url("https://mirror.test/cdn/i/icon.jpg"), "https://kanzashi-ctr.cdn.nintendo.net/i/icon.jpg");
+        // The longest matching prefix wins, with or without a trailing slash
+        assert_eq!(config.canonical_url("https://mirror.test/cdn/movie/trailer.moflex"),
+                   "https://kanzashi-movie-ctr.cdn.nintendo.net/trailer.moflex");
+        assert_eq!(config.canonical_url("https://mirror.test/cdn"), "https://kanzashi-ctr.cdn.nintendo.net");
+    }
+
+    #[test]
+    fn canonical_url_only_matches_at_path_boundaries() {
+        let config = config(r#"{ "host_aliases": { "https://mirror.test/cdn": "kanzashi-ctr.cdn.nintendo.net" } }"#);
+        for url in ["https://mirror.test/cdn2/i/icon.jpg", "https://mirror.test.evil/cdn/i/icon.jpg", "https://other.test/cdn/i/icon.jpg"] {
+            assert_eq!(config.canonical_url(url), url);
+        }
+    }
+
+    #[test]
+    fn media_request_url_uses_cdn_mirrors() {
+        let config = config(r#"{
+            "cdn_urls": { "kanzashi-ctr.cdn.nintendo.net": "https://cdn.mirror.test/kanzashi-ctr/" },
+            "host_aliases": { "https://old.mirror.test/k": "kanzashi-ctr.cdn.nintendo.net" }
+        }"#);
+        assert_eq!(config.media_request_url("https://kanzashi-ctr.cdn.nintendo.net/i/icon.jpg"), "https://cdn.mirror.test/kanzashi-ctr/i/icon.jpg");
+        assert_eq!(config.media_request_url("https://old.mirror.test/k/i/icon.jpg"), "https://cdn.mirror.test/kanzashi-ctr/i/icon.jpg");
+        assert_eq!(config.media_request_url("https://kanzashi-wup.cdn.nintendo.net/i/icon.jpg"), "https://kanzashi-wup.cdn.nintendo.net/i/icon.jpg");
+    }
+
+    #[test]
+    fn server_urls_are_per_platform() {
+        let mut config: HostConfig = serde_json::from_str(r#"{ "samurai_urls": { "3ds": "https://samurai.mirror.test/ws/" } }"#).unwrap();
+        config.apply_overrides("wiiu", None, Some("https://ninja.mirror.test/ws".to_string()), &[], &[]).unwrap();
+        assert_eq!(config.samurai_url("3ds"), "https://samurai.mirror.test/ws");
+        assert_eq!(config.samurai_url("wiiu"), WUP_SAMURAI_URL);
+        assert_eq!(config.samurai_url("unknown3"), DEFAULT_SAMURAI_URL);
+        assert_eq!(config.ninja_url("3ds"), DEFAULT_NINJA_URL);
+        assert_eq!(config.ninja_url("wiiu"), "https://ninja.mirror.test/ws");
+    }
+
+    #[test]
+    fn apply_overrides_checks_values() {
+        for json in [r#"{ "samurai_urls": { "ctr": "https://samurai.mirror.test/ws" } }"#,
+                     r#"{ "ninja_urls": { "3ds": "ninja.mirror.test/ws" } }"#,
+                     r#"{ "cdn_urls": { "https://kanzashi-ctr.cdn.nintendo.net": "https://cdn.mirror.test" } }"#,
+                     r#"{ "host_aliases": { "https://cdn.mirror.test": "cdn.nintendo.net/k" } }"#] {
+            let mut config: HostConfig = serde_json::from_str(json).unwrap();
+            assert!(matches!(config.apply_overrides("3ds", None, None, &[], &[]), Err(SaveShopError::Config(_))), "{}", json);
+        }
+        assert!(serde_json::from_str::<HostConfig>(r#"{ "samurai_url": "https://samurai.mirror.test/ws" }"#).is_err());
+    }
+
+    #[test]
+    fn parses_mappings() {
+        assert_eq!(parse_mapping("a=b=c"), Ok(("a".to_string(), "b=c".to_string())));
+        assert!(parse_mapping("a").is_err());
+    }
+}
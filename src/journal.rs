@@ -0,0 +1,188 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crawl_state::sha256_hex;
+use crate::error::SaveShopError;
+
+// Bump when making incompatible changes to JournalEntry
+pub const JOURNAL_VERSION: u32 = 1;
+
+// Separator used between records of the legacy http_log format
+const HTTP_LOG_SEPARATOR: &str = "--------------------------------------------------\n";
+
+// A single request in the journal. Stored as one JSON object per line
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub version: u32,
+    pub url: String,
+    pub method: String,
+    // Unknown for entries migrated from http_log
+    pub status: Option<u16>,
+    // Seconds since the Unix epoch at which the request completed
+    pub timestamp: u64,
+    pub duration_ms: Option<u64>,
+    // Headers in the order sent by the server. Non-UTF-8 values are converted lossily
+    pub response_headers: Vec<(String, String)>,
+    // None if the body wasn't downloaded and no content-length was reported
+    pub body_size: Option<u64>,
+    // None if the body wasn't downloaded (e.g. because an identical file already existed on disk)
+    pub sha256: Option<String>,
+}
+
+impl JournalEntry {
+    // Creates an entry for a GET request. If the body wasn't downloaded, its size is taken from the content-length header
+    pub fn new(url: &str, status: reqwest::StatusCode, headers: &reqwest::header::HeaderMap, duration: Duration, body: Option<&[u8]>) -> JournalEntry {
+        let response_headers: Vec<_> = headers.iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect();
+        let content_length = headers.get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        JournalEntry {
+            version: JOURNAL_VERSION,
+            url: url.to_string(),
+            method: "GET".to_string(),
+            status: Some(status.as_u16()),
+            timestamp: unix_timestamp(),
+            duration_ms: Some(duration.as_millis() as u64),
+            response_headers,
+            body_size: body.map(|body| body.len() as u64).or(content_length),
+            sha256: body.map(sha256_hex),
+        }
+    }
+
+    // Returns the value of the first header with the given (case-insensitive) name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.response_headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Journal, SaveShopError> {
+        let file = fs::OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        truncate_partial_line(&file, path)?;
+        Ok(Journal { file: Mutex::new(file) })
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> Result<(), SaveShopError> {
+        let line = serde_json::to_string(entry)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+// Reads all entries from the journal at the given path. A missing journal is treated as empty
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, SaveShopError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut entries = Vec::new();
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Skip malformed lines (e.g. a partially written line from an interrupted run) instead of failing every later run
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => println!("  Skipping malformed line {} of {} ({})", line_number + 1, path.display(), err),
+        }
+    }
+    Ok(entries)
+}
+
+// Removes a partially written last line from an interrupted run, so that the next entry starts on a line of its own.
+// Used for all files that are appended to one JSON object per line. The file must be opened for reading as well
+pub(crate) fn truncate_partial_line(file: &File, path: &Path) -> Result<(), SaveShopError> {
+    let mut reader = file;
+    let length = reader.seek(SeekFrom::End(0))?;
+    if length == 0 {
+        return Ok(());
+    }
+    let mut last_byte = [0u8];
+    reader.seek(SeekFrom::End(-1))?;
+    reader.read_exact(&mut last_byte)?;
+    if last_byte[0] != b'\n' {
+        // Only happens after an interruption, so reading the whole file is fine
+        let mut contents = Vec::new();
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut contents)?;
+        let complete_length = contents.iter().rposition(|&byte| byte == b'\n').map_or(0, |pos| pos + 1);
+        println!("  Removing a partially written entry from {}", path.display());
+        file.set_len(complete_length as u64)?;
+    }
+    Ok(())
+}
+
+// Reads the entries of a legacy http_log file. A missing file is treated as empty
+pub fn read_http_log(http_log: &Path) -> Result<Vec<JournalEntry>, SaveShopError> {
+    let contents = match fs::read_to_string(http_log) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut entries = Vec::new();
+    for record in contents.split_terminator(HTTP_LOG_SEPARATOR) {
+        // Header values were written without escaping, so some records may not be valid JSON
+        let record: serde_json::Value = match serde_json::from_str(record) {
+            Ok(record) => record,
+            Err(err) => { println!("  Skipping malformed http_log record ({})", err); continue },
+        };
+        let url = match record["url"].as_str() {
+            Some(url) => url.to_string(),
+            None => continue,
+        };
+        let response_headers: Vec<_> = record["response_headers"].as_object().into_iter().flatten()
+            .map(|(name, value)| (name.clone(), value.as_str().unwrap_or_default().to_string()))
+            .collect();
+        let mut entry = JournalEntry {
+            version: JOURNAL_VERSION,
+            url,
+            method: "GET".to_string(),
+            status: None,
+            timestamp: 0,
+            duration_ms: None,
+            response_headers,
+            body_size: None,
+            sha256: None,
+        };
+        entry.body_size = entry.header("content-length").and_then(|size| size.parse().ok());
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Converts the legacy http_log file to journal entries and renames it afterwards, so that this only happens once.
+// Returns the number of migrated entries
+pub fn migrate_http_log(http_log: &Path, journal: &Journal) -> Result<usize, SaveShopError> {
+    if !http_log.exists() {
+        return Ok(0);
+    }
+
+    let entries = read_http_log(http_log)?;
+    for entry in &entries {
+        journal.append(entry)?;
+    }
+
+    fs::rename(http_log, http_log.with_extension("migrated"))?;
+    Ok(entries.len())
+}
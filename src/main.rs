@@ -1,31 +1,122 @@
 use std::fs;
-use std::fs::File;
-use std::io::Write;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
 use std::fmt;
 
-use std::thread;
 use std::time;
 
 use clap::Parser;
 
+use futures::stream::{self, StreamExt};
+
 use serde::Deserialize;
 use serde::de::DeserializeOwned;
 
 use std::sync::Mutex;
 use once_cell::sync::OnceCell;
 
+mod crawl_state;
+use crawl_state::{sha256_hex, CrawlState, JobKey};
+
+mod journal;
+use journal::{Journal, JournalEntry};
+
+mod error;
+use error::{ensure_schema, SaveShopError};
+
+mod retry;
+use retry::{check_status, FetchError, RetryPolicy};
+
+mod scheduler;
+use scheduler::{HostClass, Scheduler, SchedulerConfig};
+
+mod export;
+
+mod raw_xml;
+use raw_xml::RawElement;
+
+mod page_merge;
+use page_merge::PageMerger;
+
+mod media_store;
+use media_store::MediaStore;
+
+mod warc;
+use warc::WarcWriter;
+
+mod title_ids;
+
+mod prices;
+
+mod snapshot;
+use snapshot::{SnapshotScope, SnapshotStore};
+
+mod diff;
+
+mod server;
+
+mod hosts;
+use hosts::HostConfig;
+
+mod replay;
+use replay::ReplaySource;
+
+mod migrate;
+
+// Values of --platform, in order of their shop ID
+const PLATFORMS: [&str; 4] = ["3ds", "wiiu", "unknown3", "unknown4"];
+
 // 1=3DS, 2=Wii U
 static SHOP_ID: OnceCell<i32> = OnceCell::new();
 
 fn get_shop_id() -> i32 {
-    return *SHOP_ID.get().unwrap();
+    *SHOP_ID.get().unwrap()
+}
+
+fn platform_name() -> &'static str {
+    PLATFORMS[get_shop_id() as usize - 1]
+}
+
+// Directory the metadata, crawl state and request journal of a platform are stored in, relative to the archive root.
+// Media files are shared by all platforms
+fn platform_dir_of(shop_id: i32) -> &'static str {
+    match shop_id {
+        1 => "3ds/",
+        2 => "wiiu/",
+        3 => "unknown3/",
+        _ => "unknown4/",
+    }
+}
+
+fn platform_dir() -> &'static str {
+    platform_dir_of(get_shop_id())
+}
+
+fn request_journal_path() -> String {
+    format!("{}request_journal", platform_dir())
+}
+
+// Paces all requests to avoid rate-limiting. Configured through the command line.
+static SCHEDULER: OnceCell<Scheduler> = OnceCell::new();
+
+fn scheduler() -> &'static Scheduler {
+    SCHEDULER.get().unwrap()
 }
 
-// Used to avoid rate-limiting. Lower at your own risk.
-const FETCH_DELAY: time::Duration = time::Duration::from_secs(1);
+// Records which requests finished in previous runs, so that interrupted runs can be resumed
+static CRAWL_STATE: OnceCell<CrawlState> = OnceCell::new();
+
+fn crawl_state() -> &'static CrawlState {
+    CRAWL_STATE.get().unwrap()
+}
+
+// Controls how often and how long failed requests are retried. Configured through the command line.
+static RETRY_POLICY: OnceCell<RetryPolicy> = OnceCell::new();
+
+fn retry_policy() -> &'static RetryPolicy {
+    RETRY_POLICY.get().unwrap()
+}
 
 // List of countries that don't return an error on Samurai's news endpoint.
 // Many of these only report empty content listings, though.
@@ -43,12 +134,28 @@ const REGIONS: &[&str] =
        "ZA", "ZM", "ZW",
 ];
 
+// Servers to fetch from and aliases for media hosts. Configured through the command line or a config file
+static HOST_CONFIG: OnceCell<HostConfig> = OnceCell::new();
+
+fn host_config() -> &'static HostConfig {
+    HOST_CONFIG.get().unwrap()
+}
+
 fn samurai_baseurl(region: &str) -> String {
-    return "https://samurai.ctr.shop.nintendo.net/samurai/ws/".to_owned() + region;
+    format!("{}/{}", host_config().samurai_url(platform_name()), region)
 }
 
 fn ninja_baseurl(region: &str) -> String {
-    return "https://ninja.ctr.shop.nintendo.net/ninja/ws/".to_owned() + region;
+    format!("{}/{}", host_config().ninja_url(platform_name()), region)
+}
+
+// Local directories for the samurai and ninja documents of a region
+fn samurai_dir(region: &str) -> String {
+    format!("{}samurai/{}", platform_dir(), region)
+}
+
+fn ninja_dir(region: &str) -> String {
+    format!("{}ninja/{}", platform_dir(), region)
 }
 
 struct Locale {
@@ -56,105 +163,246 @@ struct Locale {
     language: String,
 }
 
-// There are many duplicate resource references across titles/languages/regions,
-// so cache the download urls already processed
-static HTTP_HEADERS_LOG: OnceCell<Mutex<File>> = OnceCell::new();
-static HTTP_HEADERS_SEPARATOR: &str = "--------------------------------------------------\n";
+impl Locale {
+    fn samurai_dir(&self) -> String {
+        format!("{}/{}", samurai_dir(&self.region), self.language)
+    }
+
+    fn ninja_dir(&self) -> String {
+        format!("{}/{}", ninja_dir(&self.region), self.language)
+    }
+}
+
+// Items that failed to process. These are skipped so that the rest of the run can continue,
+// and are listed again at the end of the run
+static FAILURES: OnceCell<Mutex<Vec<(String, SaveShopError)>>> = OnceCell::new();
+
+fn record_failure(item: String, err: SaveShopError) {
+    println!("  Failed to process {}, skipping ({})", item, err);
+    FAILURES.get_or_init(Default::default).lock().unwrap().push((item, err));
+}
+
+// Returns the number of failed items
+fn print_failure_summary() -> usize {
+    let failures = FAILURES.get_or_init(Default::default).lock().unwrap();
+    if failures.is_empty() {
+        return 0;
+    }
+
+    println!("\n{} items failed to process:", failures.len());
+    for (item, err) in failures.iter() {
+        println!("  {}: {}", item, err);
+    }
+    failures.len()
+}
+
+fn read_document<T: DeserializeOwned>(path: &std::path::Path) -> Result<T, SaveShopError> {
+    Ok(quick_xml::de::from_str(&fs::read_to_string(path)?)?)
+}
+
+// Journal of all requests made, including response headers and body hashes
+static REQUEST_JOURNAL: OnceCell<Journal> = OnceCell::new();
+
+fn log_request(entry: JournalEntry) {
+    if let Err(err) = REQUEST_JOURNAL.get().unwrap().append(&entry) {
+        println!("  WARNING: Failed to write request journal entry for {} ({})", entry.url, err);
+    }
+}
+
+// Optional web archive of all requests made. Enabled through the command line.
+static WARC_WRITER: OnceCell<WarcWriter> = OnceCell::new();
+
+fn archive_response(url: &str, version: reqwest::Version, status: reqwest::StatusCode, headers: &reqwest::header::HeaderMap, body: &[u8]) {
+    if let Some(warc_writer) = WARC_WRITER.get() {
+        if let Err(err) = warc_writer.write_exchange(url, version, status, headers, body) {
+            println!("  WARNING: Failed to write WARC records for {} ({})", url, err);
+        }
+    }
+}
+
+// Server to send all requests to instead of the eShop servers, e.g. the stand-in used by the integration tests.
+// Configured through the command line
+static MOCK_SERVER: OnceCell<String> = OnceCell::new();
 
-fn log_headers<U: reqwest::IntoUrl + Clone + std::fmt::Display>(url: U, headers: &reqwest::header::HeaderMap<reqwest::header::HeaderValue>) {
-    let json = format!(concat!(
-                "{{\n",
-                "  \"url\": \"{}\",\n",
-                "  \"response_headers\": {{\n",
-                "    {}\n",
-                "  }}\n",
-                "}}\n",
-                "{}"),
-                url,
-                headers.iter().map(|(name, value)| format!("\"{}\": \"{}\"", name, value.to_str().unwrap())).collect::<Vec<_>>().join(",\n    "),
-                HTTP_HEADERS_SEPARATOR);
-    let mut file = HTTP_HEADERS_LOG.get().unwrap().lock().unwrap();
-    write!(file, "{}", json).unwrap();
-    file.sync_data().unwrap();
+// Returns the URL to request the given eShop URL from. Requests for "https://<host>/<path>" are sent
+// to "<mock server>/<host>/<path>" if a mock server is configured.
+// Journal entries, WARC records and local paths always use the original URL
+fn request_url(url: &str) -> String {
+    match (MOCK_SERVER.get(), url.strip_prefix("https://")) {
+        (Some(server), Some(host_and_path)) => format!("{}/{}", server.trim_end_matches('/'), host_and_path),
+        _ => url.to_string(),
+    }
 }
 
-async fn get_with_retry<U: reqwest::IntoUrl + Clone + std::fmt::Display>(client: &reqwest::Client, url: U) -> Result<String, reqwest::Error> {
-    return get_with_retry_generic(&client.get(url.clone()), url, &|response: reqwest::Response| response.text()).await;
+// Archive to answer requests from instead of the network. Enabled through the command line
+static REPLAY: OnceCell<ReplaySource> = OnceCell::new();
+
+async fn get_with_retry<U: reqwest::IntoUrl + Clone + std::fmt::Display>(client: &reqwest::Client, url: U) -> Result<String, FetchError> {
+    get_with_retry_generic(&client.get(request_url(&url.to_string())), url, &|response: reqwest::Response| response.text()).await
 }
 
-async fn get_with_retry_generic<U, C, F, Output>(request: &reqwest::RequestBuilder, url: U, continuation: C) -> Result<Output, reqwest::Error>
+async fn get_with_retry_generic<U, C, F, Output>(request: &reqwest::RequestBuilder, url: U, continuation: C) -> Result<Output, FetchError>
     where   U: reqwest::IntoUrl + Clone + std::fmt::Display,
             C: Fn(reqwest::Response) -> F,
-            F: std::future::Future<Output = Result<Output, reqwest::Error>> {
-    return loop {
-        let err = match request.try_clone().unwrap().send().await {
-            Ok(response) => {
-                // Retry on error, unless the file just doesn't exist
-                if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
-                    format!("{}", response.status())
-                } else {
-                    let headers = response.headers().clone();
-                    match continuation(response).await {
-                        Ok(response_text) => {
-                            let resource_cache = RESOURCE_CACHE.get().unwrap();
-                            if matches!(resource_cache.lock().unwrap().get(&url.to_string()), None) {
-                                // Add dummy entry to resource cache to avoid logging the same request twice
-                                // (Note that logging itself happens in get_with_retry_generic)
-                                resource_cache.lock().unwrap().insert(url.to_string(), 1);
-                                log_headers(url, &headers);
-                            }
-                            break Ok(response_text)
-                        },
-                        Err(err) => err.to_string(),
-                    }
-                }
-            }
-            Err(err) => err.to_string(),
-        };
-        println!("  Got error {}, retrying in 10 seconds", err);
-        thread::sleep(time::Duration::from_secs(10));
+            F: std::future::Future<Output = Result<Output, reqwest::Error>>,
+            Output: AsRef<[u8]> {
+    let url_string = url.to_string();
+    if let Some(replay) = REPLAY.get() {
+        let response = replay.response(&url_string)?;
+        return continuation(response).await.map_err(|err| FetchError::NotReplayed { url: url_string.clone(), reason: err.to_string() });
+    }
+
+    retry_policy().run(&url_string, || async {
+        // Hold the request slot until the response body has been read
+        let _permit = scheduler().acquire(host_config().host_class(&url_string)).await;
+        let start_time = time::Instant::now();
+        let response = request.try_clone().unwrap().send().await?;
+
+        // Fail on error, unless the file just doesn't exist
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            check_status(&url_string, &response)?;
+        }
+
+        // Archive the body as received, before it's decoded (e.g. from a different charset)
+        let version = response.version();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let raw_body = response.bytes().await?;
+        archive_response(&url_string, version, status, &headers, &raw_body);
+
+        let mut raw_response = hyper::Response::new(raw_body);
+        *raw_response.status_mut() = status;
+        *raw_response.headers_mut() = headers.clone();
+        let response_text = continuation(reqwest::Response::from(raw_response)).await?;
+
+        // Avoid logging the same response twice, but do log responses that changed since the last run
+        let entry = JournalEntry::new(&url_string, status, &headers, start_time.elapsed(), Some(response_text.as_ref()));
+        let previous_hash = RESOURCE_CACHE.get().unwrap().lock().unwrap().insert(url_string.clone(), entry.sha256.clone());
+        if previous_hash.as_ref() != Some(&entry.sha256) {
+            log_request(entry);
+        }
+        Ok(response_text)
+    }).await
+}
+
+fn job_key(locale: &Locale, content_type: &str, id: &str, endpoint: &str) -> JobKey {
+    JobKey {
+        region: locale.region.clone(),
+        language: locale.language.clone(),
+        content_type: content_type.to_string(),
+        id: id.to_string(),
+        endpoint: endpoint.to_string(),
+    }
+}
+
+// Fetches a document and saves it to the given path.
+// If this already happened in a previous run, the document is read back from disk instead
+async fn fetch_document(client: &reqwest::Client, job: JobKey, url: String, path: String) -> Result<String, SaveShopError> {
+    let state = crawl_state();
+    let path = std::path::Path::new(&path);
+    if state.is_done(&job, path) {
+        return Ok(fs::read_to_string(path)?);
+    }
+
+    let data = match get_with_retry(client, url).await {
+        Ok(data) => data,
+        Err(err) => {
+            state.record_failed(job)?;
+            return Err(err.into());
+        },
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
+    fs::write(path, &data)?;
+    state.record_done(job, data.as_bytes())?;
+    Ok(data)
 }
 
-async fn fetch_endpoint(client: &reqwest::Client, endpoint: &str, locale: &Locale) -> Result<String, reqwest::Error> {
-    let resp = get_with_retry(client, format!("{}/{}?shop_id={}&lang={}", samurai_baseurl(&locale.region), endpoint, get_shop_id(), locale.language)).await?;
-    Ok(resp)
+async fn fetch_endpoint(client: &reqwest::Client, endpoint: &EndPoint, locale: &Locale) -> Result<String, SaveShopError> {
+    let filename = format!( "{}/{}", locale.samurai_dir(),
+                            if matches!(endpoint, EndPoint::PublisherContacts) { "publishers_/contacts".to_owned() } else { endpoint.to_string() });
+    fetch_document(client, job_key(locale, "endpoint", "", &endpoint.to_string()),
+                   format!("{}/{}?shop_id={}&lang={}", samurai_baseurl(&locale.region), endpoint, get_shop_id(), locale.language),
+                   filename).await
 }
 
 #[derive(Deserialize)]
 struct NodeThumbnail {
     #[serde(rename = "@url")]
     url: String,
+
+    #[serde(rename = "@type")]
+    thumbnail_type: Option<String>,
+    #[serde(rename = "@width")]
+    width: Option<u32>,
+    #[serde(rename = "@height")]
+    height: Option<u32>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct NodeThumbnails {
     thumbnail: Vec<NodeThumbnail>,
 }
 
-impl Default for NodeThumbnails {
-    fn default() -> Self { Self { thumbnail: Vec::new() } }
-}
-
 #[derive(Deserialize)]
 struct NodeRatingIcon {
     #[serde(rename = "@url")]
-    url: String
+    url: String,
+
+    // "small" or "large"
+    #[serde(rename = "@type")]
+    icon_type: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct NodeRatingIcons {
+    #[serde(default)]
     icon: Vec<NodeRatingIcon>
 }
 
 #[derive(Deserialize)]
 struct NodeRating {
+    #[serde(rename = "@id")]
+    id: Option<String>,
+
+    name: Option<String>,
+    age: Option<String>,
+
+    // Wii U ratings may not have any icons
+    #[serde(default)]
     icons: NodeRatingIcons
 }
 
+// E.g. ESRB or PEGI
+#[derive(Deserialize)]
+struct NodeRatingSystem {
+    #[serde(rename = "@id")]
+    id: Option<String>,
+
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct NodeContentDescriptor {
+    #[serde(rename = "@type")]
+    descriptor_type: Option<String>,
+
+    name: String,
+    icon_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NodeContentDescriptors {
+    #[serde(default)]
+    content_descriptor: Vec<NodeContentDescriptor>,
+}
+
 #[derive(Deserialize)]
 struct NodeRatingInfo {
-    rating: NodeRating
+    rating_system: Option<NodeRatingSystem>,
+    rating: NodeRating,
+    content_descriptors: Option<NodeContentDescriptors>,
 }
 
 #[derive(Deserialize)]
@@ -196,20 +444,92 @@ struct NodeScreenshot {
     thumbnail_url: Vec<NodeScreenshotImageUrl>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct NodeScreenshots {
     screenshot: Vec<NodeScreenshot>,
 }
 
-impl Default for NodeScreenshots {
-    fn default() -> Self { Self { screenshot: Vec::new() } }
-}
-
 #[derive(Deserialize)]
 struct NodeTitlePlatform {
+    #[serde(rename = "@id")]
+    id: Option<String>,
+    // E.g. "CTR" or "WUP"
+    #[serde(rename = "@device")]
+    device: Option<String>,
+
+    name: Option<String>,
     icon_url: Option<String>
 }
 
+#[derive(Deserialize)]
+struct NodePublisher {
+    #[serde(rename = "@id")]
+    id: Option<String>,
+
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct NodeGenre {
+    #[serde(rename = "@id")]
+    id: Option<String>,
+
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct NodeGenres {
+    #[serde(default)]
+    genre: Vec<NodeGenre>,
+}
+
+#[derive(Deserialize)]
+struct NodeKeywords {
+    #[serde(default)]
+    keyword: Vec<String>,
+}
+
+// Used both for gameplay features (e.g. "3D", "StreetPass") and network features
+#[derive(Deserialize)]
+struct NodeFeature {
+    #[serde(rename = "@id")]
+    id: Option<String>,
+
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NodeFeatures {
+    #[serde(default)]
+    feature: Vec<NodeFeature>,
+}
+
+#[derive(Deserialize)]
+struct NodeNetworkFeatures {
+    #[serde(default)]
+    network_feature: Vec<NodeFeature>,
+}
+
+// User ratings on a scale of 1 to 5 stars
+#[derive(Deserialize)]
+struct NodeStarRatingInfo {
+    score: Option<f32>,
+    votes: Option<u32>,
+    // Number of votes for each score
+    star1: Option<u32>,
+    star2: Option<u32>,
+    star3: Option<u32>,
+    star4: Option<u32>,
+    star5: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct NodeCopyright {
+    text: Option<String>,
+    image_url: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct NodeTitle {
     #[serde(rename = "@id")]
@@ -240,6 +560,61 @@ struct NodeTitle {
     demo_titles: Option<DemoTitles>,
 
     movies: Option<NodeMovies>,
+
+    // The remaining fields are only present in detail views
+    product_code: Option<String>,
+    formal_name: Option<String>,
+    description: Option<String>,
+    // Marketing blurb
+    catch_copy: Option<String>,
+    publisher: Option<NodePublisher>,
+    display_genre: Option<String>,
+    genres: Option<NodeGenres>,
+    keywords: Option<NodeKeywords>,
+
+    retail_sales: Option<bool>,
+    eshop_sales: Option<bool>,
+    in_app_purchase: Option<bool>,
+    new: Option<bool>,
+
+    // Wii U only: Whether the title can be played on the GamePad screen alone, and whether it has a Miiverse community
+    off_tv_play: Option<bool>,
+    miiverse: Option<bool>,
+
+    release_date_on_original: Option<String>,
+    release_date_on_eshop: Option<String>,
+
+    star_rating_info: Option<NodeStarRatingInfo>,
+    alternate_rating_image_url: Option<String>,
+
+    languages: Option<NodeLanguages>,
+    // Free-form text, e.g. "1-4"
+    number_of_players: Option<String>,
+    features: Option<NodeFeatures>,
+    network_feature_info: Option<NodeNetworkFeatures>,
+
+    // Installation size, as reported by the server
+    data_size: Option<String>,
+    disclaimer: Option<String>,
+    copyright: Option<NodeCopyright>,
+
+    // Child elements not covered by the fields above. Only filled by TitleDocument::from_xml, so this is always empty for
+    // titles embedded in other documents (e.g. contents, directory and ranking lists), which are only used for their ID
+    #[serde(skip)]
+    unknown_elements: Vec<RawElement>,
+}
+
+impl NodeTitle {
+    // Child elements of <title> covered by NodeTitle
+    const KNOWN_ELEMENTS: &'static [&'static str] = &[
+        "name", "icon_url", "banner_url", "thumbnails", "platform", "rating_info", "screenshots", "aoc_available",
+        "demo_available", "demo_titles", "movies",
+        "product_code", "formal_name", "description", "catch_copy", "publisher", "display_genre", "genres", "keywords",
+        "retail_sales", "eshop_sales", "in_app_purchase", "new", "off_tv_play", "miiverse",
+        "release_date_on_original", "release_date_on_eshop",
+        "star_rating_info", "alternate_rating_image_url", "languages", "number_of_players", "features",
+        "network_feature_info", "data_size", "disclaimer", "copyright",
+    ];
 }
 
 #[derive(Deserialize)]
@@ -247,7 +622,7 @@ struct NodeMovies {
     movie: Vec<NodeMovie>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 struct NodeMovieFile {
     movie_url: String,
 
@@ -284,14 +659,17 @@ struct NodeDirectory {
     id: String,
 
     name: String,
+    description: Option<String>,
     icon_url: Option<String>,
-    banner_url: String,
+    // Not present for some Wii U directories
+    banner_url: Option<String>,
 
     contents: Option<NodeContents>,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
 enum NodeTitleOrMovie {
     Title(NodeTitle),
     Movie(NodeMovie),
@@ -302,6 +680,19 @@ struct TitleDocument {
     title: NodeTitle,
 }
 
+impl TitleDocument {
+    // Parses a title document, keeping child elements of <title> that aren't part of NodeTitle
+    fn from_xml(xml: &str) -> Result<TitleDocument, SaveShopError> {
+        let mut doc: TitleDocument = quick_xml::de::from_str(xml)?;
+        doc.title.unknown_elements = raw_xml::unknown_children(xml, "title", NodeTitle::KNOWN_ELEMENTS)?;
+        Ok(doc)
+    }
+
+    fn read(path: &std::path::Path) -> Result<TitleDocument, SaveShopError> {
+        TitleDocument::from_xml(&fs::read_to_string(path)?)
+    }
+}
+
 #[derive(Deserialize)]
 struct DemoDocument {
     // Demo pages wrap the demo node in a "<content>" tag
@@ -356,6 +747,8 @@ struct NodeRanking {
     #[serde(rename = "@id")]
     id: String,
 
+    name: Option<String>,
+
     contents: Option<NodeContents>,
 }
 
@@ -364,6 +757,164 @@ struct RankingDocument {
     ranking: NodeRanking,
 }
 
+#[derive(Deserialize)]
+struct NodePriceValue {
+    // Formatted for display, e.g. "$4.99"
+    amount: String,
+    currency: String,
+    // E.g. "4.99"
+    raw_value: Option<String>,
+
+    // Only present for discounts
+    start_datetime: Option<String>,
+    end_datetime: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NodePrice {
+    regular_price: Option<NodePriceValue>,
+    discount_price: Option<NodePriceValue>,
+}
+
+#[derive(Deserialize)]
+struct NodeOnlinePrice {
+    title_id: String,
+    // E.g. "onsale"
+    eshop_sales_status: Option<String>,
+    price: Option<NodePrice>,
+}
+
+#[derive(Deserialize)]
+struct NodeOnlinePrices {
+    // Empty for titles that aren't purchasable
+    #[serde(default)]
+    online_price: Vec<NodeOnlinePrice>,
+}
+
+#[derive(Deserialize)]
+struct OnlinePricesDocument {
+    online_prices: NodeOnlinePrices,
+}
+
+// Per-title price file, as if fetched with a single title[] parameter
+fn online_prices_path(locale: &Locale, title_id: &str) -> String {
+    format!("{}/titles/online_prices%3Ftitle%5B%5D%3D{}", locale.ninja_dir(), title_id)
+}
+
+// Prices of all titles of a locale
+fn combined_online_prices_path(locale: &Locale) -> String {
+    format!("{}/titles/online_prices", locale.ninja_dir())
+}
+
+fn online_prices_batch_path(locale: &Locale, title_ids: &[String]) -> String {
+    format!("{}/titles/online_prices_batches/{}-{}", locale.ninja_dir(),
+            title_ids.first().map(String::as_str).unwrap_or_default(), title_ids.last().map(String::as_str).unwrap_or_default())
+}
+
+// Builds a document in the format returned by the server for the given <online_price> nodes
+fn online_prices_document(online_prices: &str) -> String {
+    // NOTE: The server just returns "<eshop><online_prices/></eshop>" for titles that aren't purchasable
+    if online_prices.is_empty() {
+        return "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?><eshop><online_prices/></eshop>".to_string();
+    }
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?><eshop><online_prices>{}</online_prices></eshop>", online_prices)
+}
+
+// Returns the <online_price> nodes of a price document along with their title ids, in document order
+fn online_prices_by_title(document: &str) -> Result<Vec<(String, String)>, SaveShopError> {
+    let mut online_prices = Vec::new();
+    for element in raw_xml::children(document, "online_prices")? {
+        let online_price: NodeOnlinePrice = quick_xml::de::from_str(&element.xml)?;
+        online_prices.push((online_price.title_id, element.xml));
+    }
+    Ok(online_prices)
+}
+
+// Splits a batched price response into per-title files. Returns the <online_price> nodes contained in the response
+fn save_online_prices(locale: &Locale, title_ids: &[String], response: &str) -> Result<Vec<(String, String)>, SaveShopError> {
+    let online_prices = online_prices_by_title(response)?;
+    for title_id in title_ids {
+        let online_price = online_prices.iter().find(|(id, _)| id == title_id).map(|(_, xml)| xml.as_str()).unwrap_or_default();
+        fs::write(online_prices_path(locale, title_id), online_prices_document(online_price))?;
+    }
+    Ok(online_prices)
+}
+
+// Fetches prices for the given titles using one request per batch of titles
+async fn fetch_online_prices(client: &reqwest::Client, locale: &Locale, title_ids: &[String], args: &Args, batch_size: usize) {
+    let batches: Vec<&[String]> = title_ids.chunks(batch_size.max(1)).collect();
+    let num_batches = batches.len();
+    let responses: Vec<Option<String>> = stream::iter(batches.iter().enumerate())
+        .map(|(index, batch)| async move {
+            println!("Fetching prices for {} titles ({} out of {})", batch.len(), index + 1, num_batches);
+            let title_params: String = batch.iter().map(|title_id| format!("&title[]={}", title_id)).collect();
+            fetch_document(client, job_key(locale, "title", &batch.join(","), "online_prices"),
+                           format!("{}/titles/online_prices?shop_id={}&lang={}{}", ninja_baseurl(&locale.region), get_shop_id(), &locale.language, title_params),
+                           online_prices_batch_path(locale, batch)).await
+                .map_err(|err| record_failure(format!("prices for titles {} ({}/{})", batch.join(","), locale.region, locale.language), err))
+                .ok()
+        })
+        .buffered(args.jobs)
+        .collect().await;
+
+    let mut online_prices = Vec::new();
+    let mut failed_title_ids = HashSet::new();
+    for (batch, response) in batches.iter().zip(responses) {
+        let batch_prices = match response.map(|response| save_online_prices(locale, batch, &response)) {
+            Some(Ok(batch_prices)) => batch_prices,
+            Some(Err(err)) => {
+                record_failure(format!("prices for titles {} ({}/{})", batch.join(","), locale.region, locale.language), err);
+                failed_title_ids.extend(batch.iter().cloned());
+                continue
+            },
+            None => { failed_title_ids.extend(batch.iter().cloned()); continue },
+        };
+        online_prices.extend(batch_prices);
+    }
+
+    // Keep the previously fetched prices of titles whose batch failed, rather than replacing a complete file with a partial one
+    let combined_path = combined_online_prices_path(locale);
+    if !failed_title_ids.is_empty() {
+        match fs::read_to_string(&combined_path).map_err(SaveShopError::from).and_then(|previous| online_prices_by_title(&previous)) {
+            Ok(previous_prices) => online_prices.extend(previous_prices.into_iter().filter(|(title_id, _)| failed_title_ids.contains(title_id))),
+            Err(_) => println!("  No previous prices to keep for the titles of the failed batches"),
+        }
+    }
+
+    let online_prices: String = online_prices.into_iter().map(|(_, xml)| xml).collect();
+    if let Err(err) = fs::write(combined_path, online_prices_document(&online_prices)) {
+        record_failure(format!("prices ({}/{})", locale.region, locale.language), err.into());
+    }
+}
+
+// Present for titles that were available for pre-purchase, whose content is locked until release
+#[derive(Deserialize)]
+struct NodeContentLock {
+    // Whether the seed required to decrypt the content has been published
+    seed_published: Option<bool>,
+    external_seed: Option<String>,
+    playable_date: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NodeTitleEcInfo {
+    // 16 hex digits, e.g. "0004000000030800"
+    title_id: String,
+    content_size: Option<u64>,
+    title_version: Option<u32>,
+    disable_download: Option<bool>,
+    content_lock: Option<NodeContentLock>,
+}
+
+#[derive(Deserialize)]
+struct EcInfoDocument {
+    title_ec_info: NodeTitleEcInfo,
+}
+
+fn ec_info_path(locale: &Locale, content_id: &str) -> String {
+    format!("{}/title/{}/ec_info", locale.ninja_dir(), content_id)
+}
+
 #[derive(Deserialize)]
 struct NodeLanguage {
     iso_code: String,
@@ -372,6 +923,7 @@ struct NodeLanguage {
 
 #[derive(Deserialize)]
 struct NodeLanguages {
+    #[serde(default)]
     language: Vec<NodeLanguage>,
 }
 
@@ -442,9 +994,14 @@ enum ContentType {
     Demo,
 }
 
-async fn fetch_directory_list(client: &reqwest::Client, locale: &Locale) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let resp = get_with_retry(client, format!(  "{}/directories?shop_id={}&lang={}",
-                                    samurai_baseurl(&locale.region), get_shop_id(), &locale.language)).await?;
+// Directories that are contained in the listing but return an error page, as pairs of shop ID and directory ID
+const BROKEN_DIRECTORIES: &[(i32, &str)] = &[
+    (2, "1090749"),
+];
+
+// Reads back the directory list saved by fetch_metadata's endpoint pass, so that it's only fetched once per crawl
+async fn fetch_directory_list(client: &reqwest::Client, locale: &Locale) -> Result<Vec<String>, SaveShopError> {
+    let resp = fetch_endpoint(client, &EndPoint::Directories, locale).await?;
     let doc: Result<NodeEshopDirectories, _> = quick_xml::de::from_str(&resp);
     match doc {
         Ok(doc) =>
@@ -457,148 +1014,130 @@ async fn fetch_directory_list(client: &reqwest::Client, locale: &Locale) -> Resu
     }
 }
 
-async fn fetch_content_list(client: &reqwest::Client, endpoint: EndPoint, locale: &Locale)
-    -> Result<Vec<(ContentType, String)>, Box<dyn std::error::Error>> {
-    let mut content_list = Vec::new();
+// Checks that a page of paginated contents starts at the given offset and is consistent with itself
+fn check_page(contents: &NodeContents, offset: usize, check_index: bool) -> Result<(), SaveShopError> {
+    ensure_schema!(contents.offset.unwrap_or(0) == offset, "Page offset {:?} doesn't match requested offset {}", contents.offset, offset);
+    ensure_schema!(contents.content.len() == contents.length.unwrap_or(contents.total),
+                   "Page reports length {:?} but contains {} items", contents.length, contents.content.len());
+    ensure_schema!(!contents.content.is_empty() && offset + contents.content.len() <= contents.total,
+                   "Page at offset {} contains {} items, but total is {}", offset, contents.content.len(), contents.total);
+    if check_index {
+        ensure_schema!(contents.content[0].index == (offset + 1).to_string(),
+                       "First item on page has index {}, expected {}", contents.content[0].index, offset + 1);
+    }
+    Ok(())
+}
 
-    let mut offset = 0;
-    let mut full_list = Vec::new();
+// Documents containing a list of contents that may be split across several pages
+trait PaginatedDocument: DeserializeOwned {
+    // Whether item indices continue across pages, or restart at 1 on each page
+    const CONTINUOUS_INDEX: bool = true;
 
-    fs::create_dir_all(format!("samurai/{}/{}/paginated/", locale.region, locale.language)).unwrap();
+    // Returns None if the document has no <contents> node, which is treated like an empty list
+    fn contents(&self) -> Option<&NodeContents>;
+}
 
-    loop {
-        let resp = get_with_retry(client, format!(  "{}/{}?offset={}&shop_id={}&lang={}",
-                                        samurai_baseurl(&locale.region), endpoint, offset, get_shop_id(), &locale.language)).await?;
+impl PaginatedDocument for NodeEshop {
+    fn contents(&self) -> Option<&NodeContents> { Some(&self.contents) }
+}
 
-        let mut file = File::create(format!("samurai/{}/{}/paginated/contents%3Foffset%3D{}", locale.region, locale.language, offset)).unwrap();
-        write!(file, "{}", &resp)?;
+impl PaginatedDocument for DirectoryDocument {
+    fn contents(&self) -> Option<&NodeContents> { self.directory.contents.as_ref() }
+}
 
-        let doc: NodeEshop = quick_xml::de::from_str(&resp).unwrap();
+impl PaginatedDocument for RankingDocument {
+    // NOTE: For rankings, the reported "index" always starts at 1 even when results are reported across multiple pages
+    const CONTINUOUS_INDEX: bool = false;
 
-        if doc.contents.total == 0 {
-            println!("No contents available");
-            full_list.push(resp);
-            break;
-        }
+    fn contents(&self) -> Option<&NodeContents> { self.ranking.contents.as_ref() }
+}
 
-        println!("Contents {}-{}, {} total", offset, (offset + doc.contents.length.unwrap_or(doc.contents.total - offset)).saturating_sub(1), doc.contents.total);
-        assert_eq!(doc.contents.offset.unwrap_or(0), offset);
-        assert_eq!(doc.contents.content.len(), doc.contents.length.unwrap_or(doc.contents.total));
-        assert!(doc.contents.content.len() <= doc.contents.total);
-        if doc.contents.total > 0 {
-            assert_eq!(doc.contents.content[0].index, (offset + 1).to_string());
-        }
-        for content in &doc.contents.content {
-            match &content.title_or_movie {
-                NodeTitleOrMovie::Title(title) => {
-                    println!("  Title {}: {}", title.id, title.name.replace("\n", " ").replace("<br>", ""));
-                    content_list.push((ContentType::Title, title.id.clone()));
-                },
-                NodeTitleOrMovie::Movie(movie) => {
-                    println!("  Movie {}: {}", movie.id, movie.name.replace("\n", " ").replace("<br>", ""));
-                    content_list.push((ContentType::Movie, movie.id.clone()));
-                }
-            }
-        }
+// Location of a paginated list on the samurai servers and on disk
+struct PaginatedList {
+    // Identifies the list's pages in the crawl state
+    content_type: &'static str,
+    id: String,
+    // Path relative to the samurai base URL
+    url_path: String,
+    // Directory and file name for the merged document. Individual pages are stored in a "paginated" subdirectory
+    dir: String,
+    name: String,
+}
 
-        // Extract <contents> body and its surrounding bits, while dropping the opening <contents> tag.
-        // This makes it easy to merge the included <content> tags under a single, manually written <contents> node.
-        let (doc_header, contents_and_footer) = resp.split_at(resp.find("<contents ").unwrap());
-        let (_, contents_and_footer) = contents_and_footer.split_once(">").unwrap();
-        let (contents, doc_footer) = contents_and_footer.split_at(contents_and_footer.find("</contents>").unwrap());
-        if full_list.is_empty() {
-            full_list.push(doc_header.to_owned());
-            full_list.push(format!("<contents length=\"{}\" offset=\"0\" total=\"{}\">", doc.contents.total, doc.contents.total));
+impl PaginatedList {
+    fn contents(locale: &Locale) -> PaginatedList {
+        PaginatedList {
+            content_type: "contents",
+            id: String::new(),
+            url_path: "contents".to_string(),
+            dir: locale.samurai_dir(),
+            name: "contents".to_string(),
         }
-        full_list.push(contents.to_owned());
+    }
 
-        offset += doc.contents.content.len();
-        if offset == doc.contents.total {
-            full_list.push(doc_footer.to_owned());
-            break;
+    fn directory(locale: &Locale, directory_id: &str) -> PaginatedList {
+        PaginatedList {
+            content_type: "directory",
+            id: directory_id.to_string(),
+            url_path: format!("directory/{}", directory_id),
+            dir: format!("{}/directory", locale.samurai_dir()),
+            name: directory_id.to_string(),
         }
-        thread::sleep(FETCH_DELAY);
     }
 
-    let mut file = File::create(format!("samurai/{}/{}/contents", locale.region, locale.language)).unwrap();
-    for contents in full_list {
-        write!(file, "{}\n", contents)?;
+    fn ranking(locale: &Locale, ranking_id: &str) -> PaginatedList {
+        PaginatedList {
+            content_type: "ranking",
+            id: ranking_id.to_string(),
+            url_path: format!("ranking/{}", ranking_id),
+            dir: format!("{}/ranking", locale.samurai_dir()),
+            name: ranking_id.to_string(),
+        }
     }
 
-    Ok(content_list)
+    // Identifies a page in the crawl state. Pages of the contents list fetched with the server's default page size
+    // keep the key they had before all lists were paginated the same way, so existing crawl states still apply
+    fn page_job_key(&self, locale: &Locale, offset: usize, query: &str) -> JobKey {
+        if self.content_type == "contents" && query == format!("offset={}", offset) {
+            job_key(locale, "contents", &offset.to_string(), "contents")
+        } else {
+            job_key(locale, self.content_type, &self.id, &format!("{}?{}", self.content_type, query))
+        }
+    }
 }
 
-async fn handle_content<T: DeserializeOwned>(client: &reqwest::Client, content_id: &str, content_type: ContentType, locale: &Locale, omit_ninja: bool) -> Result<T, Box<dyn std::error::Error>> {
-    let content_type_name = match content_type {
-        ContentType::Title => "title",
-        ContentType::Movie => "movie",
-        ContentType::Demo => "demo",
-    };
-    let url = format!(  "{}/{}/{}?shop_id={}&lang={}", samurai_baseurl(&locale.region), content_type_name, content_id, get_shop_id(), &locale.language);
-    let resp = get_with_retry(client, url).await?;
+// Fetches all pages of the given list and merges them into a single document, which is saved to disk.
+// If no page size is given, the server default is used
+async fn fetch_paginated<T: PaginatedDocument>(client: &reqwest::Client, locale: &Locale, list: &PaginatedList, page_size: Option<usize>) -> Result<T, SaveShopError> {
+    fs::create_dir_all(format!("{}/paginated", list.dir))?;
 
-    fs::create_dir_all(format!("samurai/{}/{}/{}", locale.region, locale.language, content_type_name)).unwrap();
-    let mut file = File::create(format!("samurai/{}/{}/{}/{}", locale.region, locale.language, content_type_name, content_id)).unwrap();
-    write!(file, "{}", resp)?;
-
-    if !omit_ninja {
-        // Fetch mapping from content id to title id
-        if content_type == ContentType::Title ||
-           content_type == ContentType::Demo {
-            let ecinfo_resp = get_with_retry(client, format!(   "{}/title/{}/ec_info?shop_id={}&lang={}",
-                                                    ninja_baseurl(&locale.region), content_id, get_shop_id(), &locale.language)).await?;
-            // Both titles and demos are exposed through the "title" endpoint
-            fs::create_dir_all(format!("ninja/{}/{}/title/{}", locale.region, locale.language, content_id)).unwrap();
-            let mut file = File::create(format!("ninja/{}/{}/title/{}/ec_info", locale.region, locale.language, content_id)).unwrap();
-            write!(file, "{}", ecinfo_resp)?;
-        }
-
-        // Fetch price information
-        if content_type == ContentType::Title {
-            // NOTE: Just returns "<eshop><online_prices/></eshop>" for arguments that are title ids but not purchasable (e.g. movies)
-            let price_resp = get_with_retry(client, format!("{}/titles/online_prices?shop_id={}&lang={}&title[]={}",
-                                                    ninja_baseurl(&locale.region), get_shop_id(), &locale.language, content_id)).await?;
-            fs::create_dir_all(format!("ninja/{}/{}/titles", locale.region, locale.language)).unwrap();
-            let mut file = File::create(format!("ninja/{}/{}/titles/online_prices%3Ftitle%5B%5D%3D{}", locale.region, locale.language, content_id)).unwrap();
-            write!(file, "{}", price_resp)?;
-        }
-    }
-
-    Ok(quick_xml::de::from_str(&resp).unwrap())
-}
-
-async fn handle_directory_content(client: &reqwest::Client, directory_id: &str, locale: &Locale) -> Result<DirectoryDocument, Box<dyn std::error::Error>> {
-    fs::create_dir_all(format!("samurai/{}/{}/directory/paginated", locale.region, locale.language)).unwrap();
-
-    let mut directory_info = None;
+    let limit = page_size.map(|size| format!("&limit={}", size)).unwrap_or_default();
 
+    // Total number of items as reported by the first page
+    let mut expected_total = None;
     let mut offset = 0;
-    let mut full_list = Vec::new();
-    loop {
-        let resp = get_with_retry(client, format!(  "{}/directory/{}?offset={}&shop_id={}&lang={}",
-                                        samurai_baseurl(&locale.region), directory_id, offset, get_shop_id(), &locale.language)).await?;
-
-        let mut file = File::create(format!("samurai/{}/{}/directory/paginated/{}%3Foffset%3D{}", locale.region, locale.language, directory_id, offset)).unwrap();
-        write!(file, "{}", &resp)?;
-
-        let doc: DirectoryDocument = quick_xml::de::from_str(&resp)?;
-
-        if doc.directory.contents.as_ref().map(|c| c.total).unwrap_or(0) == 0 {
-            println!("No contents available");
-            full_list.push(resp);
-            directory_info = Some(doc);
-            break;
+    let mut merger = PageMerger::default();
+    let merged_xml = loop {
+        let query = format!("offset={}{}", offset, limit);
+        let resp = fetch_document(client, list.page_job_key(locale, offset, &query),
+                                  format!("{}/{}?{}&shop_id={}&lang={}", samurai_baseurl(&locale.region), list.url_path, query, get_shop_id(), &locale.language),
+                                  format!("{}/paginated/{}%3F{}", list.dir, list.name, query.replace('=', "%3D").replace('&', "%26"))).await?;
+
+        let doc: T = quick_xml::de::from_str(&resp)?;
+
+        let total_contents = doc.contents().map(|c| c.total).unwrap_or(0);
+        if total_contents == 0 {
+            ensure_schema!(expected_total.is_none(), "List {} became empty at offset {}", list.url_path, offset);
+            println!("  No contents available");
+            break resp;
         }
+        let expected_total = *expected_total.get_or_insert(total_contents);
+        ensure_schema!(expected_total == total_contents,
+                       "Total size of list {} changed from {} to {} during pagination", list.url_path, expected_total, total_contents);
 
-        let contents = doc.directory.contents.as_ref().unwrap();
-
-        println!("  Directory contents {}-{}, {} total", offset, offset + contents.length.unwrap_or(contents.total) - 1, contents.total);
-        assert_eq!(contents.offset.unwrap_or(0), offset);
-        assert_eq!(contents.content.len(), contents.length.unwrap_or(contents.total));
-        assert!(contents.content.len() <= contents.total);
-        if contents.total > 0 {
-            assert_eq!(contents.content[0].index, (offset + 1).to_string());
-        }
+        let contents = doc.contents().unwrap();
+        check_page(contents, offset, T::CONTINUOUS_INDEX)?;
+        println!("  Contents {}-{}, {} total", offset, offset + contents.content.len() - 1, total_contents);
         for content in &contents.content {
             match &content.title_or_movie {
                 NodeTitleOrMovie::Title(title) => {
@@ -609,173 +1148,162 @@ async fn handle_directory_content(client: &reqwest::Client, directory_id: &str,
                 }
             }
         }
-
         offset += contents.content.len();
-        let total_contents = contents.total;
-
-        // Extract <contents> body and its surrounding bits, while dropping the opening <contents> tag.
-        // This makes it easy to merge the included <content> tags under a single, manually written <contents> node.
-        let (doc_header, contents_and_footer) = resp.split_at(resp.find("<contents ").unwrap());
-        let (_, contents_and_footer) = contents_and_footer.split_once(">").unwrap();
-        let (contents, doc_footer) = contents_and_footer.split_at(contents_and_footer.find("</contents>").unwrap());
-        if full_list.is_empty() {
-            full_list.push(doc_header.to_owned());
-            full_list.push(format!("<contents length=\"{}\" offset=\"0\" total=\"{}\">", total_contents, total_contents));
-        }
-        full_list.push(contents.to_owned());
-
-        if directory_info.is_none() {
-            directory_info = Some(doc);
-        } else {
-            let previous_contents = directory_info.as_mut().unwrap().directory.contents.as_mut().unwrap();
-            previous_contents.content.extend(doc.directory.contents.unwrap().content.into_iter());
-        }
 
+        merger.add_page(&resp)?;
         if offset == total_contents {
-            full_list.push(doc_footer.to_owned());
-            break;
+            break merger.finish(total_contents)?;
         }
-        thread::sleep(FETCH_DELAY);
-    }
+    };
 
-    let mut file = File::create(format!("samurai/{}/{}/directory/{}", locale.region, locale.language, directory_id)).unwrap();
-    for contents in full_list {
-        write!(file, "{}\n", contents)?;
-    }
+    // Parse the merged document again to make sure it's consistent
+    let merged_doc: T = quick_xml::de::from_str(&merged_xml)?;
+    ensure_schema!(merged_doc.contents().map_or(0, |c| c.content.len()) == offset,
+                   "Merged list {} has {} items, expected {}", list.url_path, merged_doc.contents().map_or(0, |c| c.content.len()), offset);
+    fs::write(format!("{}/{}", list.dir, list.name), merged_xml)?;
 
-    Ok(directory_info.unwrap())
+    Ok(merged_doc)
 }
 
-async fn handle_ranking_content(client: &reqwest::Client, ranking_id: &str, locale: &Locale) -> Result<RankingDocument, Box<dyn std::error::Error>> {
-    fs::create_dir_all(format!("samurai/{}/{}/ranking/paginated", locale.region, locale.language)).unwrap();
+async fn fetch_content_list(client: &reqwest::Client, locale: &Locale, page_size: Option<usize>)
+    -> Result<Vec<(ContentType, String)>, SaveShopError> {
+    let doc: NodeEshop = fetch_paginated(client, locale, &PaginatedList::contents(locale), page_size).await?;
+    Ok(doc.contents.content.into_iter().map(|content| match content.title_or_movie {
+        NodeTitleOrMovie::Title(title) => (ContentType::Title, title.id),
+        NodeTitleOrMovie::Movie(movie) => (ContentType::Movie, movie.id),
+    }).collect())
+}
 
-    let mut ranking_info = None;
+async fn handle_content<T: DeserializeOwned>(client: &reqwest::Client, content_id: &str, content_type: ContentType, locale: &Locale, omit_ninja: bool) -> Result<T, SaveShopError> {
+    let content_type_name = match content_type {
+        ContentType::Title => "title",
+        ContentType::Movie => "movie",
+        ContentType::Demo => "demo",
+    };
+    let resp = fetch_document(client, job_key(locale, content_type_name, content_id, content_type_name),
+                              format!("{}/{}/{}?shop_id={}&lang={}", samurai_baseurl(&locale.region), content_type_name, content_id, get_shop_id(), &locale.language),
+                              format!("{}/{}/{}", locale.samurai_dir(), content_type_name, content_id)).await?;
 
-    let mut offset = 0;
-    let mut full_list = Vec::new();
-    loop {
-        let resp = get_with_retry(client, format!(  "{}/ranking/{}?offset={}&shop_id={}&lang={}",
-                                        samurai_baseurl(&locale.region), ranking_id, offset, get_shop_id(), &locale.language)).await?;
-
-        let mut file = File::create(format!("samurai/{}/{}/ranking/paginated/{}%3Foffset%3D{}", locale.region, locale.language, ranking_id, offset)).unwrap();
-        write!(file, "{}", &resp)?;
-
-        let doc: RankingDocument = quick_xml::de::from_str(&resp).unwrap();
-
-        let dummy = NodeContents { content: Vec::new(), length: Some(0), total: 0, offset: Some(0) };
-        let contents = doc.ranking.contents.as_ref().unwrap_or(&dummy);
-        if contents.total == 0 {
-            ranking_info = Some(doc);
-            full_list.push(resp);
-            break;
+    if !omit_ninja {
+        // Fetch mapping from content id to title id
+        if content_type == ContentType::Title ||
+           content_type == ContentType::Demo {
+            // Both titles and demos are exposed through the "title" endpoint
+            fetch_document(client, job_key(locale, content_type_name, content_id, "ec_info"),
+                           format!("{}/title/{}/ec_info?shop_id={}&lang={}", ninja_baseurl(&locale.region), content_id, get_shop_id(), &locale.language),
+                           ec_info_path(locale, content_id)).await?;
         }
+    }
 
-        println!("  Ranking contents {}-{}, {} total", offset, offset + contents.length.unwrap_or(contents.total) - 1, contents.total);
-        assert_eq!(contents.offset.unwrap_or(0), offset);
-        assert_eq!(contents.content.len(), contents.length.unwrap_or(contents.total));
-        assert!(contents.content.len() <= contents.total);
-        // NOTE: For rankings, the reported "index" always starts at 1 even when results are reported across multiple pages
-        for content in &contents.content {
-            match &content.title_or_movie {
-                NodeTitleOrMovie::Title(title) => {
-                    println!("    Title {}: {}", title.id, title.name.replace("\n", " ").replace("<br>", ""));
-                },
-                NodeTitleOrMovie::Movie(movie) => {
-                    println!("    Movie {}: {}", movie.id, movie.name.replace("\n", " ").replace("<br>", ""));
-                }
-            }
-        }
+    Ok(quick_xml::de::from_str(&resp)?)
+}
 
-        offset += contents.content.len();
-        let total_contents = contents.total;
-
-        // Extract <contents> body and its surrounding bits, while dropping the opening <contents> tag.
-        // This makes it easy to merge the included <content> tags under a single, manually written <contents> node.
-        let (doc_header, contents_and_footer) = resp.split_at(resp.find("<contents ").unwrap());
-        let (_, contents_and_footer) = contents_and_footer.split_once(">").unwrap();
-        let (contents, doc_footer) = contents_and_footer.split_at(contents_and_footer.find("</contents>").unwrap());
-        if full_list.is_empty() {
-            full_list.push(doc_header.to_owned());
-            full_list.push(format!("<contents length=\"{}\" offset=\"0\" total=\"{}\">", total_contents, total_contents));
-        }
-        full_list.push(contents.to_owned());
+// SHA-256 of the last response body downloaded from each URL, as recorded in the request journal
+static RESOURCE_CACHE: OnceCell<Mutex<HashMap<String, Option<String>>>> = OnceCell::new();
 
-        if ranking_info.is_none() {
-            ranking_info = Some(doc);
-        } else {
-            let previous_contents = ranking_info.as_mut().unwrap().ranking.contents.as_mut().unwrap();
-            previous_contents.content.extend(doc.ranking.contents.unwrap().content.into_iter());
-        }
+// Content-addressed storage for media files, shared across regions
+static MEDIA_STORE: OnceCell<MediaStore> = OnceCell::new();
 
-        if offset == total_contents {
-            full_list.push(doc_footer.to_owned());
-            break;
-        }
-        thread::sleep(FETCH_DELAY);
+fn media_store() -> &'static MediaStore {
+    MEDIA_STORE.get().unwrap()
+}
+
+// Resources currently being downloaded by one of the concurrent jobs
+static RESOURCES_IN_PROGRESS: OnceCell<Mutex<HashSet<String>>> = OnceCell::new();
+
+// Marks a resource as being downloaded until dropped
+struct ResourceInProgress(String);
+
+impl ResourceInProgress {
+    // Returns None if another job is already downloading the resource
+    fn start(url: &str) -> Option<ResourceInProgress> {
+        let in_progress = RESOURCES_IN_PROGRESS.get_or_init(Default::default);
+        in_progress.lock().unwrap().insert(url.to_string()).then(|| ResourceInProgress(url.to_string()))
     }
+}
 
-    let mut file = File::create(format!("samurai/{}/{}/ranking/{}", locale.region, locale.language, ranking_id)).unwrap();
-    for contents in full_list {
-        write!(file, "{}\n", contents)?;
+impl Drop for ResourceInProgress {
+    fn drop(&mut self) {
+        RESOURCES_IN_PROGRESS.get().unwrap().lock().unwrap().remove(&self.0);
     }
+}
+
+// If set, media files are only written to the WARC files rather than to the media store. Enabled through the command line
+static WARC_ONLY: OnceCell<bool> = OnceCell::new();
 
-    Ok(ranking_info.unwrap())
+fn warc_only() -> bool {
+    WARC_ONLY.get().copied().unwrap_or(false)
 }
 
-// There are many duplicate resource references across titles/languages/regions,
-// so cache the download urls and content sizes
-static RESOURCE_CACHE: OnceCell<Mutex<HashMap<String, u64>>> = OnceCell::new();
+// Links a previously stored media file to the given path, or imports it if it was downloaded before the media store existed.
+// Returns false if the file needs to be downloaded
+fn restore_media(url: &str, path: &std::path::Path) -> Result<bool, SaveShopError> {
+    let journal_hash = RESOURCE_CACHE.get().unwrap().lock().unwrap().get(url).cloned().flatten();
+    if warc_only() {
+        // Nothing is stored locally, so files downloaded in previous runs are only known from the request journal
+        return Ok(journal_hash.is_some() && !media_store().refetch());
+    }
+
+    if media_store().restore(url, path)? {
+        return Ok(true);
+    }
+    match journal_hash {
+        Some(hash) => media_store().import(url, path, &hash),
+        None => Ok(false),
+    }
+}
 
-async fn fetch_resource(client: &reqwest::Client, resource_name: &str, url: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let filename = url_to_filename(url);
+async fn fetch_resource(client: &reqwest::Client, resource_name: &str, url: &str) -> Result<(), SaveShopError> {
+    let filename = url_to_filename(url)?;
 
-    let resource_cache = RESOURCE_CACHE.get().unwrap();
-    let cached_size = *resource_cache.lock().unwrap().get(&url.to_string()).unwrap_or(&0);
-    println!("  Fetching {} from {}{}", resource_name, url, if cached_size != 0 { format!(" ({} KiB, cached)", cached_size / 1024) } else { "".to_string() });
-    if cached_size != 0 && Some(cached_size) == fs::metadata(&filename).map(|m| m.len()).ok() {
+    if restore_media(url, std::path::Path::new(&filename))? {
+        println!("  Fetching {} from {} (already stored)", resource_name, url);
         return Ok(());
     }
+    println!("  Fetching {} from {}", resource_name, url);
 
-    let data = loop {
-        let response = client.get(url).send().await;
-        let err = match response {
-            Ok(response) => {
-                let headers = response.headers().clone();
-                if let Ok(existing_file) = fs::metadata(&filename) {
-                    if Some(existing_file.len()) == response.content_length() {
-                        println!("    ... already exists on disk ({} KiB), skipping", response.content_length().unwrap() / 1024);
-                        log_headers(url, &headers);
-                        resource_cache.lock().unwrap().insert(url.to_string(), response.content_length().unwrap());
-                        return Ok(());
-                    }
-                }
+    // Skip if another job is already downloading this resource
+    let _in_progress = match ResourceInProgress::start(url) {
+        Some(in_progress) => in_progress,
+        None => return Ok(()),
+    };
 
-                match response.bytes().await {
-                    Ok(bytes) => {
-                        resource_cache.lock().unwrap().insert(url.to_string(), bytes.len() as u64);
-                        log_headers(url, &headers);
-                        break bytes
-                    },
-                    Err(err) => err,
-                }
-            },
-            Err(err) => err,
-        };
-        println!("  Got error {}, retrying in 10 seconds", err);
-        thread::sleep(time::Duration::from_secs(10));
+    let data = match REPLAY.get() {
+        Some(replay) => replay.body(url),
+        None => fetch_resource_data(client, url).await,
     };
+    store_media(url, &data?, std::path::Path::new(&filename))
+}
 
-    File::create(filename)?.write_all(&data)?;
+// Stores a downloaded media file and links it to the given path, unless media is only written to the WARC files
+fn store_media(url: &str, data: &[u8], path: &std::path::Path) -> Result<(), SaveShopError> {
+    match warc_only() {
+        true => Ok(()),
+        false => media_store().insert(url, data, path),
+    }
+}
 
-    thread::sleep(FETCH_DELAY);
+// Downloads a media file and records it in the request journal
+async fn fetch_resource_data(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, FetchError> {
+    retry_policy().run(url, || async {
+        let _permit = scheduler().acquire(host_config().host_class(url)).await;
+        let start_time = time::Instant::now();
+        let response = client.get(request_url(&host_config().media_request_url(url))).send().await?;
+        check_status(url, &response)?;
 
-    Ok(())
+        let version = response.version();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = response.bytes().await?;
+        let entry = JournalEntry::new(url, status, &headers, start_time.elapsed(), Some(&bytes));
+        RESOURCE_CACHE.get().unwrap().lock().unwrap().insert(url.to_string(), entry.sha256.clone());
+        log_request(entry);
+        archive_response(url, version, status, &headers, &bytes);
+        Ok(bytes.to_vec())
+    }).await
 }
 
 enum EndPoint {
-    Contents,
-    Titles,
-    Movies,
     News,
     Telops,
     Directories,
@@ -791,13 +1319,35 @@ enum EndPoint {
 #[derive(clap::Args)]
 #[clap(global_setting(clap::AppSettings::DeriveDisplayOrder))]
 struct FetchMetadataArgs {
-    /// Path to ctr-common-1 certificate in PEM format (see Readme)
+    /// Path to ctr-common-1 (3DS) or WIIU_COMMON_1 (Wii U) certificate in PEM format (see Readme)
     #[clap(long, group = "cert-group")]
     cert: Option<String>,
 
     /// Skip data provided from "ninja" servers (prices, title ids, ...)
     #[clap(long, action, group = "cert-group")]
     omit_ninja_contents: bool,
+
+    /// Number of items to request per page of paginated lists (uses the server default if not given)
+    #[clap(long, value_name = "N")]
+    page_size: Option<usize>,
+
+    /// Number of titles to request prices for at once
+    #[clap(long, value_name = "N", default_value_t = 20)]
+    price_batch_size: usize,
+
+    /// Record a snapshot of the metadata for the given regions when done
+    #[clap(long, action)]
+    snapshot: bool,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, PartialEq)]
+enum MissingDemoPolicy {
+    /// Print a warning and continue
+    Warn,
+    /// Fetch the missing demo metadata
+    Fetch,
+    /// Report it as a failure, making saveShop exit with an error once done
+    Fail,
 }
 
 #[derive(clap::Args)]
@@ -809,6 +1359,10 @@ struct FetchMediaArgs {
     /// Same as fetch-videos but needed to confirm unrestricted download of all videos
     #[clap(long, action, hide=true)]
     fetch_all_videos: bool,
+
+    /// What to do if a title references a demo without local metadata
+    #[clap(long, arg_enum, value_name = "POLICY", default_value = "warn")]
+    on_missing_demo: MissingDemoPolicy,
 }
 
 #[derive(clap::Args)]
@@ -827,6 +1381,88 @@ struct ConvertMediaArgs {
     filename: Option<String>
 }
 
+#[derive(clap::Args)]
+struct VerifyArgs {
+    /// Also check for video files
+    #[clap(long, action)]
+    videos: bool,
+}
+
+#[derive(clap::Args)]
+struct ExportJsonArgs {
+    /// Directory to write JSON files to
+    #[clap(long, value_name = "DIR", default_value = "json")]
+    output: std::path::PathBuf,
+}
+
+#[derive(clap::Args)]
+struct ExportSqliteArgs {
+    /// Database file to create. Replaced if it exists
+    #[clap(long, value_name = "FILE", default_value = "saveShop.sqlite")]
+    output: std::path::PathBuf,
+}
+
+#[derive(clap::Args)]
+struct LookupArgs {
+    /// 16-digit title ID or eShop content ID
+    #[clap(value_name = "ID")]
+    id: String,
+}
+
+#[derive(clap::Args)]
+struct ReportPricesArgs {
+    #[clap(long, arg_enum, default_value = "csv")]
+    format: prices::ReportFormat,
+
+    /// File to write the report to (defaults to standard output)
+    #[clap(long, value_name = "FILE")]
+    output: Option<std::path::PathBuf>,
+}
+
+#[derive(clap::Subcommand)]
+enum SnapshotAction {
+    /// Record the current metadata for the given regions
+    Create,
+    /// List recorded snapshots
+    List,
+    /// Recreate the metadata of a snapshot in a separate directory
+    Checkout {
+        #[clap(value_name = "SNAPSHOT")]
+        id: String,
+
+        /// Directory to write the metadata to
+        #[clap(long, value_name = "DIR")]
+        output: std::path::PathBuf,
+    },
+}
+
+#[derive(clap::Args)]
+struct SnapshotArgs {
+    #[clap(subcommand)]
+    action: SnapshotAction,
+}
+
+#[derive(clap::Args)]
+struct DiffArgs {
+    /// Archive root directory or snapshot ID of the older crawl
+    #[clap(value_name = "OLD")]
+    old: String,
+
+    /// Archive root directory or snapshot ID of the newer crawl
+    #[clap(value_name = "NEW")]
+    new: String,
+
+    #[clap(long, arg_enum, default_value = "text")]
+    format: diff::DiffFormat,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[clap(long, value_name = "ADDRESS", default_value = "127.0.0.1:8000")]
+    address: std::net::SocketAddr,
+}
+
 #[derive(clap::Subcommand)]
 enum SubCommand {
     /// Fetch general title information
@@ -837,6 +1473,24 @@ enum SubCommand {
     FetchAll(FetchAllArgs),
     /// Convert moflex video files to mp4
     ConvertMedia(ConvertMediaArgs),
+    /// Check previously fetched data for missing or corrupted files
+    Verify(VerifyArgs),
+    /// Export previously fetched metadata to JSON
+    ExportJson(ExportJsonArgs),
+    /// Export previously fetched metadata to an SQLite database
+    ExportSqlite(ExportSqliteArgs),
+    /// Look up eShop content IDs for a title ID or vice versa
+    Lookup(LookupArgs),
+    /// Compare previously fetched prices of each title across regions
+    ReportPrices(ReportPricesArgs),
+    /// Manage dated snapshots of previously fetched metadata
+    Snapshot(SnapshotArgs),
+    /// Show changes between two crawls
+    Diff(DiffArgs),
+    /// Serve the archive in the current directory together with the viewer web-app
+    Serve(ServeArgs),
+    /// Move the data of an archive created by an earlier version of saveShop into the directory of its platform
+    Migrate,
 }
 
 #[derive(Parser)]
@@ -860,17 +1514,94 @@ struct Args {
     #[clap(long, possible_values = REGIONS, global = true, use_delimiter = true)]
     regions: Vec<String>,
 
-    /// Platform to fetch data for (use a dedicated folder for each!)
-    #[clap(long, possible_values = ["3ds", "wiiu", "unknown3", "unknown4"], global = true, default_value_t = String::from("3ds"))]
+    /// Maximum number of requests in flight at any time
+    #[clap(long, value_name = "N", global = true, default_value_t = 4)]
+    jobs: usize,
+
+    /// Maximum number of requests per second across all servers (0 for no limit)
+    #[clap(long, value_name = "REQUESTS", global = true, default_value_t = 1.0)]
+    rate_limit: f64,
+
+    /// Maximum number of requests per second to samurai servers (0 for no limit)
+    #[clap(long, value_name = "REQUESTS", global = true, default_value_t = 0.0)]
+    samurai_rate_limit: f64,
+
+    /// Maximum number of requests per second to ninja servers (0 for no limit)
+    #[clap(long, value_name = "REQUESTS", global = true, default_value_t = 0.0)]
+    ninja_rate_limit: f64,
+
+    /// Maximum number of requests per second to kanzashi servers (0 for no limit)
+    #[clap(long, value_name = "REQUESTS", global = true, default_value_t = 0.0)]
+    kanzashi_rate_limit: f64,
+
+    /// Maximum number of requests per second to img-eshop servers (0 for no limit)
+    #[clap(long, value_name = "REQUESTS", global = true, default_value_t = 0.0)]
+    img_eshop_rate_limit: f64,
+
+    /// Maximum number of attempts for each request (0 to retry forever)
+    #[clap(long, value_name = "N", global = true, default_value_t = 10)]
+    max_attempts: u32,
+
+    /// Initial delay in seconds before retrying a failed request. Doubles with every attempt
+    #[clap(long, value_name = "SECONDS", global = true, default_value_t = 5.0)]
+    retry_delay: f64,
+
+    /// Upper bound in seconds for the delay between retries
+    #[clap(long, value_name = "SECONDS", global = true, default_value_t = 300.0)]
+    max_retry_delay: f64,
+
+    /// Fetch everything again, instead of skipping requests finished in previous runs
+    #[clap(long, action, global = true)]
+    refetch: bool,
+
+    /// Also write all requests and responses to WARC files in the given directory
+    #[clap(long, value_name = "DIR", global = true)]
+    warc: Option<std::path::PathBuf>,
+
+    /// Write media files only to the WARC files, instead of also storing them locally. Metadata is still saved, since fetching media is based on it
+    #[clap(long, action, global = true, requires = "warc")]
+    warc_only: bool,
+
+    /// Size in MiB after which a new WARC file is started
+    #[clap(long, value_name = "MIB", global = true, default_value_t = 1024)]
+    warc_max_size: u64,
+
+    /// JSON file with server URLs and host aliases (see Readme). Command-line options take precedence
+    #[clap(long, value_name = "FILE", global = true)]
+    config: Option<std::path::PathBuf>,
+
+    /// Base URL of the samurai servers for the selected platform, without the region
+    #[clap(long, value_name = "URL", global = true)]
+    samurai_url: Option<String>,
+
+    /// Base URL of the ninja servers for the selected platform, without the region
+    #[clap(long, value_name = "URL", global = true)]
+    ninja_url: Option<String>,
+
+    /// Fetch media of a CDN host from a different server, e.g. kanzashi-ctr.cdn.nintendo.net=https://mirror.example.org/kanzashi-ctr
+    #[clap(long, value_name = "HOST=URL", global = true, value_parser = hosts::parse_mapping)]
+    cdn_url: Vec<(String, String)>,
+
+    /// Store media from URLs starting with PREFIX at the same local paths as media from HOST
+    #[clap(long, value_name = "PREFIX=HOST", global = true, value_parser = hosts::parse_mapping)]
+    host_alias: Vec<(String, String)>,
+
+    /// Answer all requests from the previously fetched archive in the given directory instead of the network
+    #[clap(long, value_name = "ARCHIVE", global = true)]
+    replay: Option<std::path::PathBuf>,
+
+    /// Send all requests to the given server instead of the eShop servers (used for testing)
+    #[clap(long, value_name = "URL", global = true, hide = true)]
+    mock_server: Option<String>,
+
+    /// Platform to fetch data for. Its metadata is stored in a subdirectory of the same name, while media is shared
+    #[clap(long, possible_values = PLATFORMS, global = true, default_value_t = String::from("3ds"))]
     platform: String,
 }
 
 impl fmt::Display for EndPoint {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", match self {
-            EndPoint::Contents => "contents",
-            EndPoint::Titles => "titles",
-            EndPoint::Movies => "movies",
             EndPoint::News => "news",
             EndPoint::Telops => "telops",
             EndPoint::Directories => "directories",
@@ -885,71 +1616,102 @@ impl fmt::Display for EndPoint {
     }
 }
 
-fn url_to_filename(url: &str) -> String {
-    let url = url.strip_prefix("https://").unwrap();
-    let base_url = &url[0..url.find("/").unwrap() + 1];
-    let path = url.strip_prefix(base_url).unwrap();
+// NOTE: Aliases from the host configuration are resolved first, so that media fetched from mirrors is stored at the same paths
+fn url_to_filename(url: &str) -> Result<String, SaveShopError> {
+    let unknown_host = || SaveShopError::UnknownHost(url.to_string());
+    let canonical_url = host_config().canonical_url(url);
+    let (base_url, path) = canonical_url.strip_prefix("https://").and_then(|url| url.split_once('/')).ok_or_else(unknown_host)?;
     match base_url {
-        "kanzashi-ctr.cdn.nintendo.net/" => { format!("kanzashi/{}", path.strip_prefix("i/").unwrap()) },
-        "kanzashi-wup.cdn.nintendo.net/" => { format!("kanzashi/{}", path.strip_prefix("i/").unwrap()) },
-        "img-eshop.cdn.nintendo.net/" => { format!("img-eshop/{}", path.strip_prefix("i/").unwrap()) },
-        _ => panic!("Unrecognized resource URL \"{}\"", url)
-    }
+        "kanzashi-ctr.cdn.nintendo.net" => { path.strip_prefix("i/").map(|path| format!("kanzashi/{}", path)) },
+        "kanzashi-wup.cdn.nintendo.net" => { path.strip_prefix("i/").map(|path| format!("kanzashi-wup/{}", path)) },
+        "img-eshop.cdn.nintendo.net" => { path.strip_prefix("i/").map(|path| format!("img-eshop/{}", path)) },
+        _ => None
+    }.ok_or_else(unknown_host)
 }
 
-fn movie_url_to_filename(url: &str) -> String {
-    let url = url.strip_prefix("https://").unwrap();
-    let base_url = &url[0..url.find("/").unwrap() + 1];
-    let path = url.strip_prefix(base_url).unwrap();
+fn movie_url_to_filename(url: &str) -> Result<String, SaveShopError> {
+    let unknown_host = || SaveShopError::UnknownHost(url.to_string());
+    let canonical_url = host_config().canonical_url(url);
+    let (base_url, path) = canonical_url.strip_prefix("https://").and_then(|url| url.split_once('/')).ok_or_else(unknown_host)?;
     match base_url {
-        "kanzashi-movie-ctr.cdn.nintendo.net/" => { format!("kanzashi-movie/{}", path.strip_prefix("m/").unwrap()) },
-        "kanzashi-movie-wup.cdn.nintendo.net/" => { format!("kanzashi-movie/{}", path.strip_prefix("m/").unwrap()) },
-        _ => panic!("Unrecognized resource URL \"{}\"", url)
-    }
+        "kanzashi-movie-ctr.cdn.nintendo.net" => { path.strip_prefix("m/").map(|path| format!("kanzashi-movie/{}", path)) },
+        "kanzashi-movie-wup.cdn.nintendo.net" => { path.strip_prefix("m/").map(|path| format!("kanzashi-movie-wup/{}", path)) },
+        _ => None
+    }.ok_or_else(unknown_host)
 }
 
-async fn fetch_movie_file(client: &reqwest::Client, file: &NodeMovieFile) -> Result<(), Box<dyn std::error::Error>> {
-    let resource_cache = RESOURCE_CACHE.get().unwrap();
-    let cached_size = *resource_cache.lock().unwrap().get(&file.movie_url).unwrap_or(&0);
-    println!("  Fetching movie from {}{}", file.movie_url, if cached_size != 0 { format!(" ({} MiB, cached)", cached_size / 1024 / 1024) } else { "".to_string() });
-    if cached_size != 0 {
+async fn fetch_movie_file(client: &reqwest::Client, file: &NodeMovieFile) -> Result<(), SaveShopError> {
+    let filename = movie_url_to_filename(&file.movie_url)?;
+
+    if restore_media(&file.movie_url, std::path::Path::new(&filename))? {
+        println!("  Fetching movie from {} (already stored)", file.movie_url);
         return Ok(());
     }
+    println!("  Fetching movie from {}", file.movie_url);
+
+    // Skip if another job is already downloading this movie
+    let _in_progress = match ResourceInProgress::start(&file.movie_url) {
+        Some(in_progress) => in_progress,
+        None => return Ok(()),
+    };
 
-    let filename = movie_url_to_filename(&file.movie_url);
+    let movie_data = get_with_retry_generic(&client.get(request_url(&host_config().media_request_url(&file.movie_url))), file.movie_url.clone(), &|response: reqwest::Response| response.bytes()).await?;
+    store_media(&file.movie_url, &movie_data, std::path::Path::new(&filename))
+}
+
+// Fetches metadata for a single title and its demos. Returns the ids of movies referenced by the title
+async fn fetch_title_metadata(client: &reqwest::Client, title_id: &str, locale: &Locale, metadata_args: &FetchMetadataArgs) -> Result<Vec<String>, SaveShopError> {
+    let content: TitleDocument = handle_content(client, title_id, ContentType::Title, locale, metadata_args.omit_ninja_contents).await?;
+    let title = content.title;
+
+    if title.aoc_available {
+        println!("  Fetching DLC list");
+        fetch_document(client, job_key(locale, "title", title_id, "aocs"),
+                       format!("{}/title/{}/aocs?shop_id={}&lang={}", samurai_baseurl(&locale.region), title_id, get_shop_id(), &locale.language),
+                       format!("{}/title/aocs/{}", locale.samurai_dir(), title_id)).await?;
+    }
 
-    // Skip if content size matches the file on disk
-    if let Ok(existing_file) = fs::metadata(&filename) {
-        let response = client.get(&file.movie_url).send().await?;
-        let content_length = response.content_length();
-        if Some(existing_file.len()) == content_length {
-            println!("    ... already exists on disk ({} MiB), skipping", content_length.unwrap() / 1024 / 1024);
-            log_headers(&file.movie_url, &response.headers());
-            resource_cache.lock().unwrap().insert(file.movie_url.clone(), content_length.unwrap());
-            return Ok(())
+    if title.demo_available {
+        ensure_schema!(title.demo_titles.is_some(), "Title {} reports available demos but doesn't list any", title_id);
+        for demo_title in title.demo_titles.iter().flat_map(|d| &d.demo_title) {
+            println!("  Fetching metadata for demo {}", demo_title.id);
+            if let Err(err) = handle_content::<DemoDocument>(client, &demo_title.id, ContentType::Demo, locale, metadata_args.omit_ninja_contents).await {
+                record_failure(format!("demo {} ({}/{})", demo_title.id, locale.region, locale.language), err);
+            }
         }
     }
 
-    let movie_data = get_with_retry_generic(&client.get(&file.movie_url), file.movie_url.clone(), &|response: reqwest::Response| response.bytes()).await?;
-    File::create(&filename)?.write_all(&movie_data)?;
+    Ok(title.movies.into_iter().flat_map(|m| m.movie).map(|movie| movie.id).collect())
+}
+
+async fn fetch_languages(client: &reqwest::Client, region: &str) -> Result<Vec<String>, SaveShopError> {
+    let locale = Locale { region: region.to_string(), language: String::new() };
+    let data = fetch_document(client, job_key(&locale, "endpoint", "", &EndPoint::Languages.to_string()),
+                              format!("{}/{}?shop_id={}", samurai_baseurl(region), EndPoint::Languages, get_shop_id()),
+                              format!("{}/languages", samurai_dir(region))).await?;
+
+    let parsed_xml: LanguagesDocument = quick_xml::de::from_str(&data)?;
+    ensure_schema!(!parsed_xml.languages.language.is_empty(), "Could not find any supported languages for region {}", region);
 
-    thread::sleep(FETCH_DELAY * 10);
+    println!("Supported languages:");
+    for NodeLanguage { name, iso_code } in &parsed_xml.languages.language {
+        println!("  {} ({})", iso_code, name);
+    }
 
-    return Ok(());
+    Ok(parsed_xml.languages.language.into_iter().map(|lang| lang.iso_code).collect())
 }
 
-async fn fetch_metadata(client: &reqwest::Client, locale: &Locale, args: &Args, metadata_args: &FetchMetadataArgs) -> Result<(), Box<dyn std::error::Error>> {
+async fn fetch_metadata(client: &reqwest::Client, locale: &Locale, args: &Args, metadata_args: &FetchMetadataArgs) -> Result<(), SaveShopError> {
     let constrained_fetch = args.directory_id.is_some() || args.title_id.is_some() || args.movie_id.is_some();
 
     // NOTE: We're fetching languages *again* here since language names are localized
     if !constrained_fetch {
         for endpoint in vec![EndPoint::News, EndPoint::Telops, EndPoint::Directories, EndPoint::Genres, EndPoint::Publishers, EndPoint::PublisherContacts, EndPoint::Platforms, EndPoint::SearchCategory, EndPoint::Languages, EndPoint::Rankings] {
             println!("Fetching endpoint {}", endpoint);
-            let data = fetch_endpoint(&client, &format!("{}", endpoint), &locale).await?;
-            let filename = format!( "samurai/{}/{}/{}", locale.region, locale.language,
-                                    if matches!(endpoint, EndPoint::PublisherContacts) { "publishers_/contacts".to_owned() } else { endpoint.to_string() });
-            let mut file = File::create(filename).unwrap();
-            write!(file, "{}", data)?;
+            let data = match fetch_endpoint(client, &endpoint, locale).await {
+                Ok(data) => data,
+                Err(err) => { record_failure(format!("endpoint {} ({}/{})", endpoint, locale.region, locale.language), err); continue },
+            };
 
             if matches!(endpoint, EndPoint::Rankings) {
                 let parsed_xml: NodeEshopRankings = match quick_xml::de::from_str(&data) {
@@ -960,9 +1722,12 @@ async fn fetch_metadata(client: &reqwest::Client, locale: &Locale, args: &Args,
 
                 // The actual rankings aren't available for shop id 3 and 4
                 if get_shop_id() < 3 {
-                    for ranking in parsed_xml.rankings.ranking {
-                        let _: RankingDocument = handle_ranking_content(&client, &ranking.id, &locale).await?;
-                    }
+                    stream::iter(parsed_xml.rankings.ranking.iter())
+                        .for_each_concurrent(args.jobs, |ranking| async move {
+                            if let Err(err) = fetch_paginated::<RankingDocument>(client, locale, &PaginatedList::ranking(locale, &ranking.id), metadata_args.page_size).await {
+                                record_failure(format!("ranking {} ({}/{})", ranking.id, locale.region, locale.language), err);
+                            }
+                        }).await;
                 }
             }
         }
@@ -972,16 +1737,23 @@ async fn fetch_metadata(client: &reqwest::Client, locale: &Locale, args: &Args,
         (None, None, None) => {
             let mut title_ids = Vec::new();
             let mut movie_ids = Vec::new();
-            for content in fetch_content_list(&client, EndPoint::Contents, &locale).await? {
+            for content in fetch_content_list(client, locale, metadata_args.page_size).await? {
                 match content {
                     (ContentType::Title, id) => title_ids.push(id),
                     (ContentType::Movie, id) => movie_ids.push(id),
 
                     // "contents" endpoint only contains titles and movies
-                    (ContentType::Demo, _) => panic!("Unexpected demo title in contents list"),
+                    (ContentType::Demo, id) => return Err(SaveShopError::SchemaMismatch(format!("Unexpected demo {} in contents list", id))),
                 }
             }
-            let directory_ids = Vec::<_>::from_iter(fetch_directory_list(&client, &locale).await?.into_iter());
+            let mut directory_ids = Vec::<_>::from_iter(fetch_directory_list(client, locale).await?);
+            directory_ids.retain(|id| {
+                let broken = BROKEN_DIRECTORIES.contains(&(get_shop_id(), id.as_str()));
+                if broken {
+                    println!("Skipping directory {}, which is known to return an error page", id);
+                }
+                !broken
+            });
             (title_ids, movie_ids, directory_ids)
         },
         _ => (args.title_id.clone().into_iter().collect::<Vec<_>>(),
@@ -990,15 +1762,20 @@ async fn fetch_metadata(client: &reqwest::Client, locale: &Locale, args: &Args,
     };
 
     directory_ids.sort_unstable();
-    for (index, directory_id) in directory_ids.iter().enumerate() {
-        println!("Fetching metadata for directory {} ({} out of {})", directory_id, index + 1, directory_ids.len());
-        let directory: DirectoryDocument = match handle_directory_content(&client, &directory_id, &locale).await {
-            Ok(dir) => dir,
-            // NOTE: Wii U directory 1090749 is contained in the listing but returns an error page...
-            Err(err) => { println!("  Failed to parse metadata, skipping ({})", err); continue },
-        };
+    let num_directories = directory_ids.len();
+    let directories: Vec<_> = stream::iter(directory_ids.iter().enumerate())
+        .map(|(index, directory_id)| async move {
+            println!("Fetching metadata for directory {} ({} out of {})", directory_id, index + 1, num_directories);
+            match fetch_paginated::<DirectoryDocument>(client, locale, &PaginatedList::directory(locale, directory_id), metadata_args.page_size).await {
+                Ok(dir) => Some(dir),
+                Err(err) => { record_failure(format!("directory {} ({}/{})", directory_id, locale.region, locale.language), err); None },
+            }
+        })
+        .buffered(args.jobs)
+        .collect().await;
+    for directory in directories.into_iter().flatten() {
         let directory = directory.directory;
-        for content in directory.contents.into_iter().map(|c| c.content).flatten() {
+        for content in directory.contents.into_iter().flat_map(|c| c.content) {
             match content.title_or_movie {
                 NodeTitleOrMovie::Title(title) => if !title_ids.contains(&title.id) { title_ids.push(title.id) },
                 NodeTitleOrMovie::Movie(movie) => if !movie_ids.contains(&movie.id) { movie_ids.push(movie.id) },
@@ -1008,101 +1785,191 @@ async fn fetch_metadata(client: &reqwest::Client, locale: &Locale, args: &Args,
 
     title_ids.sort_unstable();
     title_ids.dedup();
-    for (index, title_id) in title_ids.iter().enumerate() {
-        println!("Fetching metadata for title {} ({} out of {})", title_id, index + 1, title_ids.len());
-        let content: TitleDocument = handle_content(&client, &title_id, ContentType::Title, &locale, metadata_args.omit_ninja_contents).await?;
-        let title = content.title;
-
-        if title.aoc_available {
-            println!("  Fetching DLC list");
-            let dlc_resp = get_with_retry(&client, format!("{}/title/{}/aocs?shop_id={}&lang={}",
-                                                    samurai_baseurl(&locale.region), title_id, get_shop_id(), &locale.language)).await?;
-            fs::create_dir_all(format!("samurai/{}/{}/title/aocs", locale.region, locale.language)).unwrap();
-            let mut file = File::create(format!("samurai/{}/{}/title/aocs/{}", locale.region, locale.language, title_id)).unwrap();
-            write!(file, "{}", dlc_resp)?;
-        }
+    let num_titles = title_ids.len();
+    let referenced_movies: Vec<Vec<String>> = stream::iter(title_ids.iter().enumerate())
+        .map(|(index, title_id)| async move {
+            println!("Fetching metadata for title {} ({} out of {})", title_id, index + 1, num_titles);
+            fetch_title_metadata(client, title_id, locale, metadata_args).await.unwrap_or_else(|err| {
+                record_failure(format!("title {} ({}/{})", title_id, locale.region, locale.language), err);
+                Vec::new()
+            })
+        })
+        .buffer_unordered(args.jobs)
+        .collect().await;
 
-        if title.demo_available {
-            assert!(title.demo_titles.is_some());
-            for demo_title in title.demo_titles.as_ref().unwrap().demo_title.iter() {
-                println!("  Fetching metadata for demo {}", demo_title.id);
-                let _: DemoDocument = handle_content(&client, &demo_title.id, ContentType::Demo, &locale, metadata_args.omit_ninja_contents).await?;
+    if !metadata_args.omit_ninja_contents && !title_ids.is_empty() {
+        fetch_online_prices(client, locale, &title_ids, args, metadata_args.price_batch_size).await;
+    }
+
+    // Add referenced movie trailers
+    movie_ids.extend(referenced_movies.into_iter().flatten());
+
+    movie_ids.sort_unstable();
+    movie_ids.dedup();
+    let num_movies = movie_ids.len();
+    stream::iter(movie_ids.iter().enumerate())
+        .for_each_concurrent(args.jobs, |(index, movie_id)| async move {
+            println!("Fetching metadata for movie {} ({} out of {})", movie_id, index + 1, num_movies);
+            if let Err(err) = handle_content::<MovieDocument>(client, movie_id, ContentType::Movie, locale, metadata_args.omit_ninja_contents).await {
+                record_failure(format!("movie {} ({}/{})", movie_id, locale.region, locale.language), err);
             }
+        }).await;
+
+    Ok(())
+}
+
+// Media referenced by metadata documents. Each URL is only included once
+#[derive(Default)]
+struct MediaReferences {
+    // Pairs of resource name and URL
+    resources: Vec<(String, String)>,
+    movie_files: Vec<NodeMovieFile>,
+    urls: HashSet<String>,
+}
+
+impl MediaReferences {
+    fn add(&mut self, resource_name: &str, url: &str) {
+        if self.urls.insert(url.to_string()) {
+            self.resources.push((resource_name.to_string(), url.to_string()));
         }
+    }
 
-        // Add referenced movie trailers
-        for movie in title.movies.iter().map(|m| &m.movie).flatten() {
-            movie_ids.push(movie.id.clone());
+    fn add_rating_icons(&mut self, rating_info: &Option<NodeRatingInfo>) {
+        for rating_icon in rating_info.iter().flat_map(|r| &r.rating.icons.icon) {
+            self.add("rating icon", &rating_icon.url);
         }
+    }
 
-        thread::sleep(FETCH_DELAY);
+    fn add_news(&mut self, news: &NodeNews) {
+        for image in news.news_entry.iter().flat_map(|n| &n.images).flat_map(|i| &i.image) {
+            self.add("news banner", &image.url);
+        }
     }
 
-    movie_ids.sort_unstable();
-    movie_ids.dedup();
-    for (index, movie_id) in movie_ids.iter().enumerate() {
-        println!("Fetching metadata for movie {} ({} out of {})", movie_id, index + 1, movie_ids.len());
-        let _: MovieDocument = handle_content(&client, &movie_id, ContentType::Movie, &locale, metadata_args.omit_ninja_contents).await?;
+    fn add_directory(&mut self, directory: &NodeDirectory) {
+        if let Some(icon_url) = &directory.icon_url {
+            self.add("icon", icon_url);
+        }
+        if let Some(banner_url) = &directory.banner_url {
+            self.add("banner", banner_url);
+        }
     }
 
-    Ok(())
-}
+    fn add_title(&mut self, title: &NodeTitle) {
+        if let Some(icon_url) = &title.icon_url {
+            self.add("icon", icon_url);
+        }
+        if let Some(banner_url) = &title.banner_url {
+            self.add("banner", banner_url);
+        }
+        for thumbnail in &title.thumbnails.thumbnail {
+            self.add("thumbnail", &thumbnail.url);
+        }
+        self.add_rating_icons(&title.rating_info);
+        if let Some(platform_icon) = &title.platform.icon_url {
+            self.add("platform icon", platform_icon);
+        }
 
-async fn fetch_media_resources(client: &reqwest::Client, region: &str, args: &Args, fetch_args: &FetchMediaArgs) -> Result<(), Box<dyn std::error::Error>> {
-    let dir_entries = std::fs::read_dir(format!("samurai/{}", region)).into_iter().flatten().flatten();
+        for screenshot in &title.screenshots.screenshot {
+            for image_url in &screenshot.image_url {
+                let resource_name = match &image_url.screen {
+                    None => "screenshot".to_string(),
+                    Some(screen) => format!("{} screenshot", screen),
+                };
+                self.add(&resource_name, &image_url.url);
+            }
+            for thumbnail in &screenshot.thumbnail_url {
+                self.add("thumbnail", &thumbnail.url);
+            }
+        }
+        // TODO: urls, alternate_rating_image_url
+
+        for movie in title.movies.iter().flat_map(|m| &m.movie) {
+            self.add_movie(movie);
+        }
+    }
 
-    for subdir in dir_entries.filter(|f| f.file_type().unwrap().is_dir()) {
-        println!("Gathering media resources for region {} / language {}", region, subdir.file_name().to_str().unwrap());
+    fn add_demo(&mut self, demo: &DemoTitle) {
+        if let Some(icon_url) = &demo.icon_url {
+            self.add("icon", icon_url);
+        }
+        self.add_rating_icons(&demo.rating_info);
+        // NOTE: There are no demos with associated videos, banners, or thumbnails
+    }
 
-        if let Ok(news_contents) = fs::read(subdir.path().join("news")) {
-            // NOTE: Shop ids 3 and 4 may return error pages for this
-            let parsed_xml: Result<NewsDocument,_> = quick_xml::de::from_str(&String::from_utf8(news_contents).unwrap());
-            for news_entry in parsed_xml.iter().map(|n| &n.news.news_entry).flatten() {
-                for image in news_entry.images.iter().map(|i| &i.image).flatten() {
-                    fetch_resource(&client, "news banner", &image.url).await?;
-                }
+    fn add_movie(&mut self, movie: &NodeMovie) {
+        if let Some(banner_url) = &movie.banner_url {
+            self.add("banner", banner_url);
+        }
+        if let Some(thumbnail_url) = &movie.thumbnail_url {
+            self.add("thumbnail", thumbnail_url);
+        }
+        self.add_rating_icons(&movie.rating_info);
+        // TODO: urls, alternate_rating_image_url
+
+        for file in &movie.files.file {
+            // Trailers are referenced both by their title and by their own movie document
+            if self.urls.insert(file.movie_url.clone()) {
+                self.movie_files.push(file.clone());
             }
         }
+    }
+}
 
-        let contained_files_iter = |path| {
-            std::fs::read_dir(path)
-                    .into_iter()
-                    .flatten()
-                    .flatten()
-                    .filter(|f| f.file_type().unwrap().is_file())
-        };
+fn contained_files(path: std::path::PathBuf) -> impl Iterator<Item = fs::DirEntry> {
+    std::fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|f| f.file_type().is_ok_and(|t| t.is_file()))
+}
+
+async fn fetch_media_resources(client: &reqwest::Client, region: &str, args: &Args, fetch_args: &FetchMediaArgs) -> Result<(), SaveShopError> {
+    // Data from ninja servers can only be fetched if a client certificate was provided for fetching metadata
+    let omit_ninja = !matches!(args.command, SubCommand::FetchAll(FetchAllArgs { metadata: FetchMetadataArgs { omit_ninja_contents: false, .. }, .. }));
 
-        let icons_from_rating_info = |rating_info: Option<NodeRatingInfo>| if rating_info.is_some() { rating_info.unwrap().rating.icons.icon } else { Vec::new() };
+    let dir_entries = std::fs::read_dir(samurai_dir(region)).into_iter().flatten().flatten();
+
+    for subdir in dir_entries.filter(|f| f.file_type().is_ok_and(|t| t.is_dir())) {
+        println!("Gathering media resources for region {} / language {}", region, subdir.file_name().to_string_lossy());
+
+        // Resources are collected first and then downloaded concurrently
+        let mut media = MediaReferences::default();
+
+        // NOTE: Shop ids 3 and 4 may return error pages for this
+        let parsed_xml: Result<NewsDocument, _> = read_document(&subdir.path().join("news"));
+        if let Ok(parsed_xml) = parsed_xml {
+            media.add_news(&parsed_xml.news);
+        }
 
         let constrained_fetch = args.directory_id.is_some() || args.title_id.is_some() || args.movie_id.is_some();
         let build_contents_list = |content_name, exclude_list: Vec<_>| {
             Vec::<_>::from_iter(
-                contained_files_iter(subdir.path().join(content_name))
-                .filter(|d| !constrained_fetch || exclude_list.iter().any(|item| *item == d.file_name().to_string_lossy().to_string()))
+                contained_files(subdir.path().join(content_name))
+                .filter(|d| !constrained_fetch || exclude_list.iter().any(|item| *item == d.file_name().to_string_lossy()))
                 .map(|d| d.path())
             )
         };
 
-        let mut title_set = Vec::<_>::from_iter(args.title_id.clone().into_iter());
-        let mut movie_set = Vec::<_>::from_iter(args.movie_id.clone().into_iter());
+        let mut title_set = Vec::<_>::from_iter(args.title_id.clone());
+        let mut movie_set = Vec::<_>::from_iter(args.movie_id.clone());
 
-        let mut directory_set = build_contents_list("directory", Vec::<_>::from_iter(args.directory_id.clone().into_iter()));
+        let mut directory_set = build_contents_list("directory", Vec::<_>::from_iter(args.directory_id.clone()));
         directory_set.sort_unstable();
         for (dir_index, directory) in directory_set.iter().enumerate() {
             println!(" Directory {} ({} out of {})", &directory.display(), dir_index + 1, directory_set.len());
-            let parsed_xml: DirectoryDocument = quick_xml::de::from_str(&String::from_utf8(fs::read(directory).unwrap()).unwrap()).unwrap();
+            let parsed_xml: DirectoryDocument = match read_document(directory) {
+                Ok(parsed_xml) => parsed_xml,
+                Err(err) => { record_failure(directory.display().to_string(), err); continue },
+            };
             let directory = parsed_xml.directory;
 
             println!("  Name: {}", &directory.name.replace("\n", " "));
-
-            if let Some(icon_url) = directory.icon_url {
-                fetch_resource(&client, "icon", &icon_url).await?;
-            }
-            fetch_resource(&client, "banner", &directory.banner_url).await?;
+            media.add_directory(&directory);
 
             // Include titles and movies referenced by this directory
             if constrained_fetch {
-                for content in directory.contents.into_iter().map(|c| c.content).flatten() {
+                for content in directory.contents.into_iter().flat_map(|c| c.content) {
                     match content.title_or_movie {
                         NodeTitleOrMovie::Title(title) => { title_set.push(title.id); },
                         NodeTitleOrMovie::Movie(movie) => { movie_set.push(movie.id); },
@@ -1117,73 +1984,35 @@ async fn fetch_media_resources(client: &reqwest::Client, region: &str, args: &Ar
         title_set.dedup();
         for (title_index, title) in title_set.iter().enumerate() {
             println!(" Title {} ({} out of {})", &title.display(), title_index + 1, title_set.len());
-            let parsed_xml: TitleDocument = quick_xml::de::from_str(&String::from_utf8(fs::read(title).unwrap()).unwrap()).unwrap();
+            let parsed_xml = match TitleDocument::read(title) {
+                Ok(parsed_xml) => parsed_xml,
+                Err(err) => { record_failure(title.display().to_string(), err); continue },
+            };
             let title = parsed_xml.title;
 
             println!("  Name: {}", &title.name.replace("\n", " "));
-
-            if let Some(icon_url) = title.icon_url {
-                fetch_resource(&client, "icon", &icon_url).await?;
-            }
-            if let Some(banner_url) = title.banner_url {
-                fetch_resource(&client, "banner", &banner_url).await?;
-            }
-            for thumbnail in title.thumbnails.thumbnail {
-                fetch_resource(&client, "thumbnail", &thumbnail.url).await?;
-            }
-            for rating_icon in icons_from_rating_info(title.rating_info) {
-                fetch_resource(&client, "rating icon", &rating_icon.url).await?;
-            }
-            if let Some(platform_icon) = title.platform.icon_url {
-                fetch_resource(&client, "platform icon", &platform_icon).await?;
-            }
-
-            for screenshot in title.screenshots.screenshot {
-                for image_url in screenshot.image_url {
-                    let resource_name = match image_url.screen {
-                        None => "screenshot".to_string(),
-                        Some(screen) => format!("{} screenshot", &screen),
-                    };
-                    fetch_resource(&client, &resource_name, &image_url.url).await?;
-                }
-                for thumbnail in screenshot.thumbnail_url {
-                    fetch_resource(&client, "thumbnail", &thumbnail.url).await?;
-                }
-            }
-            // TODO: urls, alternate_rating_image_url
-
-            for movie in title.movies.map(|c| c.movie).unwrap_or_default() {
-                if let Some(banner_url) = movie.banner_url {
-                    fetch_resource(&client, "banner", &banner_url).await?;
-                }
-                if let Some(thumbnail_url) = movie.thumbnail_url {
-                    fetch_resource(&client, "thumbnail", &thumbnail_url).await?;
-                }
-
-                for rating_icon in icons_from_rating_info(movie.rating_info) {
-                    fetch_resource(&client, "rating icon", &rating_icon.url).await?;
-                }
-
-                if fetch_args.fetch_videos {
-                    fs::create_dir_all(format!("kanzashi-movie")).unwrap();
-                    for file in movie.files.file {
-                        fetch_movie_file(&client, &file).await?;
-                    }
-                }
-            }
+            media.add_title(&title);
 
             if title.demo_available {
-                for demo_title in &title.demo_titles.as_ref().unwrap().demo_title {
+                for demo_title in title.demo_titles.iter().flat_map(|d| &d.demo_title) {
                     demo_set.push(demo_title.id.clone());
 
                     let demo_path = subdir.path().join("demo").join(&demo_title.id);
-                    if !demo_path.exists() {
-                        println!("  WARNING: Title references demo {}, but there is no metadata at {}", demo_title.id, demo_path.display());
-                        println!("  -------- Press Enter to continue --------");
-                        use std::io::Read;
-                        let _ = std::io::stdin().read(&mut [0u8]);
-                        println!("           Continuing in 5 seconds...");
-                        thread::sleep(time::Duration::from_secs(5));
+                    if demo_path.exists() {
+                        continue;
+                    }
+                    let message = format!("Title {} references demo {}, but there is no metadata at {}", title.id, demo_title.id, demo_path.display());
+                    match fetch_args.on_missing_demo {
+                        MissingDemoPolicy::Warn => println!("  WARNING: {}", message),
+                        MissingDemoPolicy::Fail => record_failure(format!("demo {}", demo_title.id), SaveShopError::MissingMetadata(message)),
+                        MissingDemoPolicy::Fetch => {
+                            println!("  Fetching missing metadata for demo {}", demo_title.id);
+                            let locale = Locale { region: region.to_string(), language: subdir.file_name().to_string_lossy().into_owned() };
+                            fs::create_dir_all(subdir.path().join("demo"))?;
+                            if let Err(err) = handle_content::<DemoDocument>(client, &demo_title.id, ContentType::Demo, &locale, omit_ninja).await {
+                                record_failure(format!("demo {} ({}/{})", demo_title.id, locale.region, locale.language), err);
+                            }
+                        },
                     }
                 }
             }
@@ -1195,16 +2024,11 @@ async fn fetch_media_resources(client: &reqwest::Client, region: &str, args: &Ar
         for (demo_index, demo) in demo_set.iter().enumerate() {
             println!(" Demo {} ({} out of {})", &demo.display(), demo_index + 1, demo_set.len());
 
-            let parsed_xml: DemoDocument = quick_xml::de::from_str(&String::from_utf8(fs::read(demo).unwrap()).unwrap()).unwrap();
-            let demo = parsed_xml.content.demo;
-
-            if let Some(icon_url) = demo.icon_url {
-                fetch_resource(&client, "icon", &icon_url).await?;
-            }
-            for rating_icon in icons_from_rating_info(demo.rating_info) {
-                fetch_resource(&client, "rating icon", &rating_icon.url).await?;
-            }
-            // NOTE: There are no demos with associated videos, banners, or thumbnails
+            let parsed_xml: DemoDocument = match read_document(demo) {
+                Ok(parsed_xml) => parsed_xml,
+                Err(err) => { record_failure(demo.display().to_string(), err); continue },
+            };
+            media.add_demo(&parsed_xml.content.demo);
         }
 
         let mut movie_set = build_contents_list("movie", movie_set);
@@ -1213,47 +2037,155 @@ async fn fetch_media_resources(client: &reqwest::Client, region: &str, args: &Ar
         for (movie_index, movie) in movie_set.iter().enumerate() {
             println!(" Movie {} ({} out of {})", &movie.display(), movie_index + 1, movie_set.len());
 
-            let parsed_xml: MovieDocument = quick_xml::de::from_str(&String::from_utf8(fs::read(movie).unwrap()).unwrap()).unwrap();
-            let movie = parsed_xml.movie;
+            let parsed_xml: MovieDocument = match read_document(movie) {
+                Ok(parsed_xml) => parsed_xml,
+                Err(err) => { record_failure(movie.display().to_string(), err); continue },
+            };
+            media.add_movie(&parsed_xml.movie);
+        }
+
+        if !fetch_args.fetch_videos {
+            media.movie_files.clear();
+        }
+
+        println!("Fetching {} media resources and {} movie files", media.resources.len(), media.movie_files.len());
+        stream::iter(media.resources.iter())
+            .for_each_concurrent(args.jobs, |(resource_name, url)| async move {
+                if let Err(err) = fetch_resource(client, resource_name, url).await {
+                    record_failure(format!("{} {}", resource_name, url), err);
+                }
+            }).await;
+        stream::iter(media.movie_files.iter())
+            .for_each_concurrent(args.jobs, |file| async move {
+                if let Err(err) = fetch_movie_file(client, file).await {
+                    record_failure(format!("movie file {}", file.movie_url), err);
+                }
+            }).await;
+    }
+
+    Ok(())
+}
+
+// Checks previously fetched metadata and media for problems. Returns the number of problems found
+fn verify_archive(args: &Args, verify_args: &VerifyArgs) -> Result<usize, SaveShopError> {
+    // Sizes of the response bodies as downloaded
+    let mut journal_sizes = HashMap::new();
+    for entry in journal::read_journal(std::path::Path::new(&request_journal_path()))? {
+        if let Some(size) = entry.body_size {
+            journal_sizes.insert(entry.url, size);
+        }
+    }
+
+    // Hashes of the media files as stored
+    let manifest = media_store::read_manifest(std::path::Path::new("media"))?;
 
-            if let Some(banner_url) = movie.banner_url {
-                fetch_resource(&client, "banner", &banner_url).await?;
+    let mut problems: Vec<(&str, String)> = Vec::new();
+    let mut media = MediaReferences::default();
+
+    for region in &args.regions {
+        let dir_entries = std::fs::read_dir(samurai_dir(region)).into_iter().flatten().flatten();
+        for subdir in dir_entries.filter(|f| f.file_type().is_ok_and(|t| t.is_dir())) {
+            println!("Verifying region {} / language {}", region, subdir.file_name().to_string_lossy());
+
+            for path in contained_files(subdir.path().join("directory")).map(|f| f.path()) {
+                match read_document::<DirectoryDocument>(&path) {
+                    Ok(parsed_xml) => media.add_directory(&parsed_xml.directory),
+                    Err(err) => problems.push(("Unparseable document", format!("{} ({})", path.display(), err))),
+                }
             }
-            if let Some(thumbnail_url) = movie.thumbnail_url {
-                fetch_resource(&client, "thumbnail", &thumbnail_url).await?;
+
+            for path in contained_files(subdir.path().join("title")).map(|f| f.path()) {
+                let title = match TitleDocument::read(&path) {
+                    Ok(parsed_xml) => parsed_xml.title,
+                    Err(err) => { problems.push(("Unparseable document", format!("{} ({})", path.display(), err))); continue },
+                };
+                media.add_title(&title);
+
+                for demo_title in title.demo_titles.iter().flat_map(|d| &d.demo_title) {
+                    if !subdir.path().join("demo").join(&demo_title.id).exists() {
+                        problems.push(("Dangling demo reference", format!("{} references demo {}", path.display(), demo_title.id)));
+                    }
+                }
             }
-            for rating_icon in icons_from_rating_info(movie.rating_info) {
-                fetch_resource(&client, "rating icon", &rating_icon.url).await?;
+
+            for path in contained_files(subdir.path().join("demo")).map(|f| f.path()) {
+                match read_document::<DemoDocument>(&path) {
+                    Ok(parsed_xml) => media.add_demo(&parsed_xml.content.demo),
+                    Err(err) => problems.push(("Unparseable document", format!("{} ({})", path.display(), err))),
+                }
             }
-            // TODO: urls, alternate_rating_image_url
 
-            if fetch_args.fetch_videos {
-                for file in movie.files.file {
-                    fetch_movie_file(&client, &file).await?;
+            for path in contained_files(subdir.path().join("movie")).map(|f| f.path()) {
+                match read_document::<MovieDocument>(&path) {
+                    Ok(parsed_xml) => media.add_movie(&parsed_xml.movie),
+                    Err(err) => problems.push(("Unparseable document", format!("{} ({})", path.display(), err))),
                 }
             }
         }
     }
 
-    Ok(())
+    if !verify_args.videos {
+        media.movie_files.clear();
+    }
+
+    println!("Checking {} media resources and {} movie files", media.resources.len(), media.movie_files.len());
+    let mut checked_urls = HashSet::new();
+    let files = media.resources.iter().map(|(_, url)| (url, url_to_filename(url)))
+                    .chain(media.movie_files.iter().map(|file| (&file.movie_url, movie_url_to_filename(&file.movie_url))));
+    for (url, filename) in files {
+        if !checked_urls.insert(url.clone()) {
+            continue;
+        }
+        let filename = match filename {
+            Ok(filename) => filename,
+            Err(err) => { problems.push(("Unrecognized URL", err.to_string())); continue },
+        };
+        match fs::metadata(&filename) {
+            Err(_) => problems.push(("Missing file", format!("{} (from {})", filename, url))),
+            Ok(metadata) if metadata.len() == 0 => problems.push(("Empty file", filename)),
+            Ok(metadata) => match journal_sizes.get(url) {
+                Some(&size) if size != metadata.len() => {
+                    problems.push(("Size mismatch", format!("{} has {} bytes, but {} bytes were downloaded", filename, metadata.len(), size)));
+                },
+                // Files of the right size may still be corrupted, so compare them to the hash recorded when storing them
+                _ => if let Some(entry) = manifest.get(url) {
+                    if fs::read(&filename).map(|data| sha256_hex(&data)).ok().as_ref() != Some(&entry.sha256) {
+                        problems.push(("Hash mismatch", format!("{} doesn't match the SHA-256 {} stored for {}", filename, entry.sha256, url)));
+                    }
+                },
+            },
+        }
+    }
+
+    problems.sort();
+    for (kind, what) in &problems {
+        println!("  {}: {}", kind, what);
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+    } else {
+        println!("Found {} problems", problems.len());
+    }
+
+    Ok(problems.len())
 }
 
-fn convert_moflex(args: &Args) {
+fn convert_moflex(args: &Args) -> Result<(), SaveShopError> {
     let mut movies_2d = HashSet::new();
     let mut movies_3d = HashSet::new();
 
     for region in &args.regions {
-        let dir_entries = std::fs::read_dir(format!("samurai/{}", region)).into_iter().flatten().flatten();
+        let dir_entries = std::fs::read_dir(samurai_dir(region)).into_iter().flatten().flatten();
 
-        for subdir in dir_entries.filter(|f| f.file_type().unwrap().is_dir()) {
-            println!("Gathering video metadata for region {} / language {}", region, subdir.file_name().to_str().unwrap());
+        for subdir in dir_entries.filter(|f| f.file_type().is_ok_and(|t| t.is_dir())) {
+            println!("Gathering video metadata for region {} / language {}", region, subdir.file_name().to_string_lossy());
 
             let contained_files_iter = |path| {
                 std::fs::read_dir(path)
                         .into_iter()
                         .flatten()
                         .flatten()
-                        .filter(|f| f.file_type().unwrap().is_file())
+                        .filter(|f| f.file_type().is_ok_and(|t| t.is_file()))
             };
 
             if args.directory_id.is_some() {
@@ -1265,19 +2197,22 @@ fn convert_moflex(args: &Args) {
             let build_contents_list = |content_name, exclude_list: Vec<_>| {
                 Vec::<_>::from_iter(
                     contained_files_iter(subdir.path().join(content_name))
-                    .filter(|d| !constrained_fetch || exclude_list.iter().any(|item| *item == d.file_name().to_string_lossy().to_string()))
+                    .filter(|d| !constrained_fetch || exclude_list.iter().any(|item| *item == d.file_name().to_string_lossy()))
                     .map(|d| d.path())
                 )
             };
 
-            let title_set = Vec::<_>::from_iter(args.title_id.clone().into_iter());
-            let movie_set = Vec::<_>::from_iter(args.movie_id.clone().into_iter());
+            let title_set = Vec::<_>::from_iter(args.title_id.clone());
+            let movie_set = Vec::<_>::from_iter(args.movie_id.clone());
 
             let mut title_set = build_contents_list("title", title_set);
             title_set.sort_unstable();
             title_set.dedup();
             for title in title_set.iter() {
-                let parsed_xml: TitleDocument = quick_xml::de::from_str(&String::from_utf8(fs::read(title).unwrap()).unwrap()).unwrap();
+                let parsed_xml = match TitleDocument::read(title) {
+                    Ok(parsed_xml) => parsed_xml,
+                    Err(err) => { record_failure(title.display().to_string(), err); continue },
+                };
                 let title = parsed_xml.title;
                 for movie in title.movies.map(|c| c.movie).unwrap_or_default() {
                     for file in movie.files.file {
@@ -1294,34 +2229,40 @@ fn convert_moflex(args: &Args) {
             movie_set.sort_unstable();
             movie_set.dedup();
             for movie in movie_set.iter() {
-                let parsed_xml: MovieDocument = quick_xml::de::from_str(&String::from_utf8(fs::read(movie).unwrap()).unwrap()).unwrap();
-                let movie = parsed_xml.movie;
-                for file in movie.files.file {
+                let parsed_xml: MovieDocument = match read_document(movie) {
+                    Ok(parsed_xml) => parsed_xml,
+                    Err(err) => { record_failure(movie.display().to_string(), err); continue },
+                };
+                for file in parsed_xml.movie.files.file {
                     if file.dimension == "3d" {
                         movies_3d.insert(file.movie_url);
                     } else if file.dimension == "2d" {
                         movies_2d.insert(file.movie_url);
                     } else {
-                        panic!("Unknown movie dimension {}", file.dimension);
+                        record_failure(movie.display().to_string(), SaveShopError::SchemaMismatch(format!("Unknown movie dimension {}", file.dimension)));
                     }
                 }
             }
         }
     }
 
-    assert!(movies_3d.intersection(&movies_2d).collect::<Vec<_>>().is_empty(), "Video referenced both as 2D and 3D");
+    for url in movies_3d.intersection(&movies_2d).cloned().collect::<Vec<_>>() {
+        record_failure(url.clone(), SaveShopError::SchemaMismatch("Video referenced both as 2D and 3D".to_string()));
+        movies_2d.remove(&url);
+        movies_3d.remove(&url);
+    }
 
     let mut all_videos = movies_2d.iter().collect::<Vec<_>>();
     all_videos.append(&mut (movies_3d.iter().collect::<Vec<_>>()));
     all_videos.sort_unstable();
 
     if let SubCommand::ConvertMedia(ConvertMediaArgs { filename: Some(filename) }) = &args.command {
-        if !filename.starts_with("kanzashi-movie/") {
-            println!("File path must start with kanzashi-movie (given filename: {})", filename);
+        if !filename.starts_with("kanzashi-movie/") && !filename.starts_with("kanzashi-movie-wup/") {
+            println!("File path must start with kanzashi-movie or kanzashi-movie-wup (given filename: {})", filename);
             std::process::exit(1);
         }
 
-        let url = all_videos.iter().find(|v| movie_url_to_filename(v) == *filename);
+        let url = all_videos.iter().find(|v| movie_url_to_filename(v).is_ok_and(|f| f == *filename));
         if url.is_none() {
             // Can't determine if it's a 3D video or not in this case
             println!("No video metadata found for file {} in the given regions", filename);
@@ -1337,14 +2278,15 @@ fn convert_moflex(args: &Args) {
     }
 
     for (index, url) in all_videos.iter().enumerate() {
-        let moflex = std::path::Path::new(&movie_url_to_filename(*url)).to_owned();
-        let filename = moflex.file_name().unwrap();
-        let filename = filename.to_string_lossy();
+        let moflex = match movie_url_to_filename(url) {
+            Ok(moflex) => std::path::PathBuf::from(moflex),
+            Err(err) => { record_failure(url.to_string(), err); continue },
+        };
+        let filename = moflex.file_name().unwrap_or_default().to_string_lossy();
 
         println!("Converting {} to MP4 ({} out of {})...", filename, index + 1, all_videos.len());
 
         let is_3d = movies_3d.contains(*url);
-        assert!(is_3d || movies_2d.contains(*url));
 
         // Skip conversion if an MP4 with non-zero size already exists on disk
         // NOTE: This may skip over partial files from a previously cancelled run.
@@ -1367,35 +2309,113 @@ fn convert_moflex(args: &Args) {
                     .output();
         match out {
             Err(e) => match e.kind() {
-                std::io::ErrorKind::NotFound => panic!("  ERROR: FFmpeg is not installed"),
-                _ => panic!("Unknown error while calling ffmpeg")
+                std::io::ErrorKind::NotFound => {
+                    println!("  ERROR: FFmpeg is not installed");
+                    return Err(e.into());
+                },
+                _ => return Err(e.into()),
             },
             Ok(out) => if !out.status.success() {
-                println!("  ERROR:");
-                std::io::stderr().write_all(&out.stderr).unwrap();
-                std::process::exit(1);
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                record_failure(moflex.display().to_string(), SaveShopError::Conversion(stderr.trim_end().to_string()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Records the metadata currently stored for the given regions as a new snapshot
+fn create_snapshot(regions: &[String]) -> Result<(), SaveShopError> {
+    let store = SnapshotStore::open(std::path::Path::new("snapshots"))?;
+    let dirs: Vec<std::path::PathBuf> = regions.iter()
+        .flat_map(|region| [samurai_dir(region).into(), ninja_dir(region).into()])
+        .collect();
+    let id = store.create(&SnapshotScope::new(platform_name(), regions), &dirs)?;
+    println!("Metadata recorded as snapshot {}", id);
+    Ok(())
+}
+
+// Opens the crawl state, media store, request journal and other state used while fetching
+fn prepare_fetch(args: &Args) -> Result<(), SaveShopError> {
+    if let Some(ref archive) = args.replay {
+        let replay = ReplaySource::open(archive)?;
+        REPLAY.get_or_init(|| replay);
+    }
+
+    // Requests are tracked separately for each platform, since crawl state entries don't include the shop ID
+    fs::create_dir_all(platform_dir())?;
+    let crawl_state = CrawlState::open(std::path::Path::new(&format!("{}crawl_state", platform_dir())), args.refetch)?;
+    CRAWL_STATE.get_or_init(|| crawl_state);
+
+    RESOURCE_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+
+    let media_store = MediaStore::open(std::path::Path::new("media"), args.refetch)?;
+    MEDIA_STORE.get_or_init(|| media_store);
+
+    if let Some(warc_dir) = args.warc.clone() {
+        let warc_writer = WarcWriter::new(warc_dir, args.warc_max_size * 1024 * 1024)?;
+        WARC_WRITER.get_or_init(|| warc_writer);
+        WARC_ONLY.get_or_init(|| args.warc_only);
+    }
+
+    let request_journal = Journal::open(std::path::Path::new(&request_journal_path()))?;
+    let num_migrated = journal::migrate_http_log(std::path::Path::new(&format!("{}http_log", platform_dir())), &request_journal)?;
+    if num_migrated != 0 {
+        println!("Migrated {} entries from http_log to request_journal", num_migrated);
+    }
+    REQUEST_JOURNAL.get_or_init(|| request_journal);
+
+    {
+        let mut resource_cache = RESOURCE_CACHE.get().unwrap().lock().unwrap();
+        for entry in journal::read_journal(std::path::Path::new(&request_journal_path()))? {
+            // Entries without a body hash (e.g. migrated from http_log) don't override earlier ones
+            let known_hash = resource_cache.entry(entry.url).or_default();
+            if entry.sha256.is_some() {
+                *known_hash = entry.sha256;
             }
         }
     }
+
+    fs::create_dir_all("img-eshop")?;
+    fs::create_dir_all("kanzashi")?;
+    fs::create_dir_all("kanzashi-movie")?;
+    fs::create_dir_all("kanzashi-wup")?;
+    fs::create_dir_all("kanzashi-movie-wup")?;
+
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), SaveShopError> {
     let mut args = Args::parse();
 
-    SHOP_ID.get_or_init(|| match args.platform.as_str() {
-        "3ds" => 1,
-        "wiiu" => 2,
-        "unknown3" => 3,
-        _ => 4
-    });
+    SHOP_ID.get_or_init(|| PLATFORMS.iter().position(|platform| *platform == args.platform).unwrap() as i32 + 1);
+
+    let mut host_config = match args.config {
+        Some(ref path) => HostConfig::load(path)?,
+        None => HostConfig::default(),
+    };
+    host_config.apply_overrides(&args.platform, args.samurai_url.clone(), args.ninja_url.clone(), &args.cdn_url, &args.host_alias)?;
+    HOST_CONFIG.get_or_init(|| host_config);
+
+    // Archives created by earlier versions must be migrated before anything else is done with them
+    if matches!(args.command, SubCommand::Migrate) {
+        return migrate::migrate(std::path::Path::new("."), get_shop_id());
+    }
+    if migrate::needs_migration(std::path::Path::new(".")) {
+        println!("This archive uses the layout of an earlier version of saveShop, which stored the data of a single platform in the archive root.");
+        println!("Run \"saveShop migrate\" to move it to {}, adding --platform if it isn't 3DS data.", platform_dir());
+        std::process::exit(1);
+    }
 
-    if args.regions.is_empty() {
+    if args.regions.is_empty() && !matches!(args.command, SubCommand::Serve(_)) {
         use clap::CommandFactory;
         let mut cmd = Args::command();
         cmd.error(clap::ErrorKind::MissingRequiredArgument, "The required argument --regions was not provided").exit();
     }
 
+    let replay_archive = args.replay.clone();
     let ssl_id = match args.command {
         SubCommand::FetchMetadata(ref args)
         | SubCommand::FetchAll(FetchAllArgs { metadata: ref args, media: _ }) => match args.cert {
@@ -1404,8 +2424,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Some(reqwest::Identity::from_pem(&cert_bytes)?)
             },
             None => {
-                if !args.omit_ninja_contents {
-                    println!("3DS client certificate required to download data from Ninja servers.");
+                // No certificate is needed when replaying
+                if !args.omit_ninja_contents && replay_archive.is_none() {
+                    // Shop IDs other than Wii U are served by the 3DS servers
+                    let platform = if get_shop_id() == 2 { "Wii U" } else { "3DS" };
+                    println!("{} client certificate required to download data from Ninja servers.", platform);
                     println!("Specify its location with --cert, or use --omit-ninja-contents to skip this data.");
                     println!("See Readme for details.");
                     std::process::exit(1);
@@ -1416,24 +2439,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         _ => None
     };
 
-    RESOURCE_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
-
-    {
-        let cache = fs::read_to_string("http_log");
-        if let Ok(cache) = cache {
-            let mut resource_cache = RESOURCE_CACHE.get().unwrap().lock().unwrap();
-            for entry in cache.split_terminator(HTTP_HEADERS_SEPARATOR) {
-                let entry: serde_json::Value = serde_json::from_str(&entry)?;
-                let num_bytes: u64 = match entry["response_headers"]["content-length"].as_str() {
-                    Some(num_bytes) => num_bytes.parse().unwrap(),
-                    None => 1 // Dummy size returned for transfer-encoding=chunked
-                };
+    SCHEDULER.get_or_init(|| Scheduler::new(SchedulerConfig {
+        max_in_flight: args.jobs,
+        global_rate: args.rate_limit,
+        host_rates: vec![
+            (HostClass::Samurai, args.samurai_rate_limit),
+            (HostClass::Ninja, args.ninja_rate_limit),
+            (HostClass::Kanzashi, args.kanzashi_rate_limit),
+            (HostClass::ImgEshop, args.img_eshop_rate_limit),
+        ],
+    }));
+
+    RETRY_POLICY.get_or_init(|| RetryPolicy {
+        max_attempts: args.max_attempts,
+        base_delay: time::Duration::from_secs_f64(args.retry_delay),
+        max_delay: time::Duration::from_secs_f64(args.max_retry_delay),
+    });
 
-                resource_cache.insert(entry["url"].as_str().unwrap().to_string(), num_bytes);
-            }
-        }
+    if let Some(mock_server) = args.mock_server.clone() {
+        MOCK_SERVER.get_or_init(|| mock_server);
     }
-    HTTP_HEADERS_LOG.get_or_init(|| Mutex::new(std::fs::OpenOptions::new().create(true).append(true).open("http_log").unwrap()));
 
     // Check if we should prompt for --fetch-all-videos to be added
     match args.command {
@@ -1462,63 +2487,56 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         _ => {}
     }
 
+    // Subcommands that only read the archive don't need (and shouldn't create) any of the state used for fetching
+    if matches!(args.command, SubCommand::FetchMetadata(_) | SubCommand::FetchMedia(_) | SubCommand::FetchAll(_)) {
+        prepare_fetch(&args)?;
+    }
+
     let mut client_builder = reqwest::Client::builder()
                             // Required to access eShop servers without a root CA
                             .danger_accept_invalid_certs(true)
                             // Required for SSL cert to be used
                             .use_rustls_tls();
-    if ssl_id.is_some() {
-        client_builder = client_builder.identity(ssl_id.unwrap());
+    if let Some(ssl_id) = ssl_id {
+        client_builder = client_builder.identity(ssl_id);
     }
     let client = client_builder.build()?;
 
-    fs::create_dir_all(format!("img-eshop")).unwrap();
-    fs::create_dir_all(format!("kanzashi")).unwrap();
-    fs::create_dir_all(format!("kanzashi-movie")).unwrap();
-
-    for region in &args.regions {
-        if !matches!(args.command, SubCommand::FetchMetadata(_)) && !matches!(args.command, SubCommand::FetchAll(_)) {
-            break;
-        }
-
-        println!("\nProcessing region {}", region);
-        fs::create_dir_all(format!("samurai/{}", region)).unwrap();
-
+    if let SubCommand::FetchMetadata(ref metadata_args)
+         | SubCommand::FetchAll(FetchAllArgs { metadata: ref metadata_args, media: _ }) = args.command {
         // Fetch list of languages first
-        let languages: Vec<_> = {
-            let data = get_with_retry(&client, format!("{}/{}?shop_id={}", samurai_baseurl(&region), EndPoint::Languages, get_shop_id())).await?;
-            let mut file = File::create(format!("samurai/{}/languages", region)).unwrap();
-            write!(file, "{}", data)?;
-
-            let parsed_xml: LanguagesDocument = quick_xml::de::from_str(&data).unwrap();
-
-            if parsed_xml.languages.language.is_empty() {
-                println!("Could not find any supported languages for region {}", &region);
-                std::process::exit(1);
+        let mut locales = Vec::new();
+        for region in &args.regions {
+            println!("\nProcessing region {}", region);
+            fs::create_dir_all(samurai_dir(region))?;
+
+            match fetch_languages(&client, region).await {
+                Ok(languages) => locales.extend(languages.into_iter().map(|language| Locale { region: region.to_string(), language })),
+                Err(err) => record_failure(format!("languages for region {}", region), err),
             }
+        }
 
-            println!("Supported languages:");
-            for NodeLanguage { name, iso_code } in &parsed_xml.languages.language {
-                println!("  {} ({})", iso_code, name);
-            }
-
-            parsed_xml.languages.language.into_iter().map(|lang| lang.iso_code).collect()
-        };
-
-        // Fetch content metadata
-        match args.command {
-            SubCommand::FetchMetadata(ref metadata_args)
-            | SubCommand::FetchAll(FetchAllArgs { metadata: ref metadata_args, media: _ }) => {
-                for language in languages {
-                    println!("Fetching metadata for language \"{}\" of region {}", language, region);
-                    let locale = Locale { region: region.to_string(), language: language.to_owned() };
-                    fs::create_dir_all(format!("samurai/{}/{}/publishers_", locale.region, locale.language)).unwrap();
-
-                    fetch_metadata(&client, &locale, &args, &metadata_args).await?;
+        // Fetch content metadata. Locales are processed concurrently, with the scheduler bounding the number of requests in flight
+        let (client, args) = (&client, &args);
+        stream::iter(locales)
+            .for_each_concurrent(args.jobs, |locale| async move {
+                println!("Fetching metadata for language \"{}\" of region {}", locale.language, locale.region);
+                let result = match fs::create_dir_all(format!("{}/publishers_", locale.samurai_dir())) {
+                    Ok(()) => fetch_metadata(client, &locale, args, metadata_args).await,
+                    Err(err) => Err(err.into()),
+                };
+                if let Err(err) = result {
+                    record_failure(format!("metadata for {}/{}", locale.region, locale.language), err);
                 }
-            },
-            _ => {},
-        };
+            }).await;
+    }
+
+    match args.command {
+        SubCommand::FetchMetadata(FetchMetadataArgs { snapshot: true, .. })
+        | SubCommand::FetchAll(FetchAllArgs { metadata: FetchMetadataArgs { snapshot: true, .. }, media: _ }) => {
+            create_snapshot(&args.regions)?;
+        },
+        _ => {},
     }
 
     // Fetch media
@@ -1526,14 +2544,74 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         SubCommand::FetchMedia(ref fetch_args)
         | SubCommand::FetchAll(FetchAllArgs { metadata: _, media: ref fetch_args }) => {
             for region in &args.regions {
-                fetch_media_resources(&client, &region, &args, &fetch_args).await?;
+                if let Err(err) = fetch_media_resources(&client, region, &args, fetch_args).await {
+                    record_failure(format!("media for region {}", region), err);
+                }
             }
         }
         _ => {},
     }
 
     if matches!(args.command, SubCommand::ConvertMedia(_)) {
-        convert_moflex(&args);
+        convert_moflex(&args)?;
+    }
+
+    if let SubCommand::ExportJson(ref export_args) = args.command {
+        export::export_json(&args.regions, &export_args.output)?;
+    }
+
+    if let SubCommand::ExportSqlite(ref export_args) = args.command {
+        export::export_sqlite(&args.regions, &export_args.output)?;
+    }
+
+    if let SubCommand::Snapshot(ref snapshot_args) = args.command {
+        let store = SnapshotStore::open(std::path::Path::new("snapshots"))?;
+        match snapshot_args.action {
+            SnapshotAction::Create => create_snapshot(&args.regions)?,
+            SnapshotAction::List => {
+                for id in store.list()? {
+                    println!("{}", id);
+                }
+            },
+            SnapshotAction::Checkout { ref id, ref output } => {
+                let num_files = store.checkout(id, output)?;
+                println!("Wrote {} files of snapshot {} to {}", num_files, id, output.display());
+            },
+        }
+    }
+
+    if let SubCommand::Serve(ref serve_args) = args.command {
+        server::serve(serve_args.address).await?;
+    }
+
+    if let SubCommand::Diff(ref diff_args) = args.command {
+        diff::diff(&diff_args.old, &diff_args.new, &args.regions, diff_args.format)?;
+    }
+
+    if let SubCommand::ReportPrices(ref report_args) = args.command {
+        prices::report_prices(&args.regions, report_args.format, report_args.output.as_deref())?;
+    }
+
+    let num_problems = match args.command {
+        SubCommand::Verify(ref verify_args) => verify_archive(&args, verify_args)?,
+        SubCommand::Lookup(ref lookup_args) => match title_ids::lookup(&args.regions, &lookup_args.id)? {
+            0 => 1,
+            _ => 0,
+        },
+        _ => 0,
+    };
+
+    let num_failures = print_failure_summary();
+
+    if let Some(replay) = REPLAY.get() {
+        if replay.misses() != 0 {
+            println!("\n{} requests could not be answered from the replayed archive", replay.misses());
+            std::process::exit(1);
+        }
+    }
+
+    if num_problems != 0 || num_failures != 0 {
+        std::process::exit(1);
     }
 
     Ok(())
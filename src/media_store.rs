@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crawl_state::sha256_hex;
+use crate::error::SaveShopError;
+use crate::journal::truncate_partial_line;
+
+// Maps a media URL to the content it was stored with. Stored as one JSON object per line
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestEntry {
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+// Location of a stored file in the media store at the given root
+pub fn object_path(root: &Path, sha256: &str) -> PathBuf {
+    root.join("objects").join(&sha256[..2]).join(sha256)
+}
+
+// Reads the manifest of the media store at the given root without modifying the store. A missing manifest is treated as empty
+pub fn read_manifest(root: &Path) -> Result<HashMap<String, ManifestEntry>, SaveShopError> {
+    let mut entries = HashMap::new();
+    if let Ok(file) = File::open(root.join("manifest")) {
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Ignore a partially written last line from an interrupted run
+            if let Ok(entry) = serde_json::from_str::<ManifestEntry>(&line) {
+                entries.insert(entry.url.clone(), entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Stores media files by their SHA-256, so that files shared across regions/titles are only stored once.
+// The usual kanzashi/img-eshop/kanzashi-movie paths are hard links to the stored objects.
+pub struct MediaStore {
+    root: PathBuf,
+    // Later manifest entries for the same URL override earlier ones
+    entries: Mutex<HashMap<String, ManifestEntry>>,
+    manifest: Mutex<File>,
+    // If set, stored media is fetched again
+    refetch: bool,
+}
+
+impl MediaStore {
+    pub fn open(root: &Path, refetch: bool) -> Result<MediaStore, SaveShopError> {
+        fs::create_dir_all(root.join("objects"))?;
+
+        let entries = read_manifest(root)?;
+        let manifest_path = root.join("manifest");
+        let manifest = fs::OpenOptions::new().create(true).read(true).append(true).open(&manifest_path)?;
+        truncate_partial_line(&manifest, &manifest_path)?;
+        Ok(MediaStore { root: root.to_path_buf(), entries: Mutex::new(entries), manifest: Mutex::new(manifest), refetch })
+    }
+
+    pub fn refetch(&self) -> bool {
+        self.refetch
+    }
+
+    pub fn object_path(&self, sha256: &str) -> PathBuf {
+        object_path(&self.root, sha256)
+    }
+
+    // URLs of all stored media files
+    pub fn urls(&self) -> Vec<String> {
+        self.entries.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn entry(&self, url: &str) -> Option<ManifestEntry> {
+        self.entries.lock().unwrap().get(url).cloned()
+    }
+
+    // Checks if the given URL was stored before and its object still has the recorded size.
+    // If so, (re-)creates the link at the given path. Contents are only hashed by verify, since this runs for every reference
+    pub fn restore(&self, url: &str, link_path: &Path) -> Result<bool, SaveShopError> {
+        if self.refetch {
+            return Ok(false);
+        }
+
+        let entry = match self.entry(url) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let object_path = self.object_path(&entry.sha256);
+        if !has_size(&object_path, entry.size) {
+            return Ok(false);
+        }
+        if !is_linked(&object_path, link_path) {
+            link(&object_path, link_path)?;
+        }
+        Ok(true)
+    }
+
+    // Imports a file downloaded before the media store existed, if it matches the expected hash
+    pub fn import(&self, url: &str, path: &Path, expected_sha256: &str) -> Result<bool, SaveShopError> {
+        if self.refetch {
+            return Ok(false);
+        }
+
+        match fs::read(path) {
+            Ok(data) if sha256_hex(&data) == expected_sha256 => {
+                self.insert(url, &data, path)?;
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    // Stores the data downloaded from the given URL and links it to the given path
+    pub fn insert(&self, url: &str, data: &[u8], link_path: &Path) -> Result<(), SaveShopError> {
+        let sha256 = sha256_hex(data);
+        let object_path = self.object_path(&sha256);
+
+        // Stored objects are trusted if they have the right size, unless the media is being fetched again (e.g. to repair it)
+        if self.refetch || !has_size(&object_path, data.len() as u64) {
+            // Write to a temporary file first so that no partial objects are left behind on interruption.
+            // Concurrent writers of the same content each use their own temporary file.
+            // Removing a truncated object also detaches it from links at other paths
+            fs::create_dir_all(object_path.parent().unwrap())?;
+            let temp_path = object_path.with_extension(format!("{}-{}.part", std::process::id(), NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)));
+            File::create(&temp_path)?.write_all(data)?;
+            let _ = fs::remove_file(&object_path);
+            fs::rename(&temp_path, &object_path)?;
+        }
+        if !is_linked(&object_path, link_path) {
+            link(&object_path, link_path)?;
+        }
+
+        let entry = ManifestEntry { url: url.to_string(), sha256, size: data.len() as u64 };
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            writeln!(manifest, "{}", serde_json::to_string(&entry)?)?;
+            manifest.sync_data()?;
+        }
+        self.entries.lock().unwrap().insert(entry.url.clone(), entry);
+        Ok(())
+    }
+}
+
+// Used to give each temporary file a unique name
+static NEXT_TEMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn has_size(path: &Path, size: u64) -> bool {
+    fs::metadata(path).map(|metadata| metadata.len() == size).unwrap_or(false)
+}
+
+// Checks if the file at link_path is a hard link to the given object
+#[cfg(unix)]
+fn is_linked(object_path: &Path, link_path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(object_path), fs::metadata(link_path)) {
+        (Ok(object), Ok(link)) => object.dev() == link.dev() && object.ino() == link.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_linked(_object_path: &Path, _link_path: &Path) -> bool {
+    false
+}
+
+// Makes the file at link_path refer to the given object. Falls back to copying if hard links aren't supported
+fn link(object_path: &Path, link_path: &Path) -> Result<(), SaveShopError> {
+    if let Some(parent) = link_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::remove_file(link_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {},
+    }
+    if fs::hard_link(object_path, link_path).is_err() {
+        fs::copy(object_path, link_path)?;
+    }
+    Ok(())
+}
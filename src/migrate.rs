@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use crate::crawl_state::sha256_hex;
+use crate::error::SaveShopError;
+use crate::journal::{self, Journal, JournalEntry};
+use crate::media_store::MediaStore;
+use crate::{movie_url_to_filename, platform_dir_of, url_to_filename};
+
+// Earlier versions of saveShop stored the data of a single platform directly in the archive root.
+// These files and directories are now kept in a directory per platform, along with the request journal
+const PLATFORM_ENTRIES: &[&str] = &["samurai", "ninja", "crawl_state", "http_log", "http_log.migrated"];
+
+// Whether the archive at the given root still uses the layout of earlier versions
+pub fn needs_migration(root: &Path) -> bool {
+    PLATFORM_ENTRIES.iter().chain(&["request_journal"]).any(|name| root.join(name).exists())
+}
+
+// Location earlier versions stored a media file at, which didn't distinguish between 3DS and Wii U media
+fn legacy_media_path(url: &str) -> Option<String> {
+    let (host, path) = url.strip_prefix("https://")?.split_once('/')?;
+    match host {
+        "kanzashi-ctr.cdn.nintendo.net" | "kanzashi-wup.cdn.nintendo.net" => path.strip_prefix("i/").map(|path| format!("kanzashi/{}", path)),
+        "img-eshop.cdn.nintendo.net" => path.strip_prefix("i/").map(|path| format!("img-eshop/{}", path)),
+        "kanzashi-movie-ctr.cdn.nintendo.net" | "kanzashi-movie-wup.cdn.nintendo.net" => path.strip_prefix("m/").map(|path| format!("kanzashi-movie/{}", path)),
+        _ => None,
+    }
+}
+
+// Shop ID given in the query of a samurai or ninja URL. Media URLs don't have one
+fn url_shop_id(url: &str) -> Option<i32> {
+    let (_, query) = url.split_once('?')?;
+    query.split('&').find_map(|param| param.strip_prefix("shop_id="))?.parse().ok()
+}
+
+// Moves the data of an archive created by an earlier version into the directory of the given platform.
+// Request journal entries of other platforms (e.g. from runs with a different --platform) are moved to the journals
+// of these platforms instead. Media files whose location changed since (e.g. Wii U media, which is now stored in
+// kanzashi-wup/) are linked to their new location, using the media store or, for archives created before it existed,
+// the requests recorded in the request journal and http_log
+pub fn migrate(root: &Path, shop_id: i32) -> Result<(), SaveShopError> {
+    if !needs_migration(root) {
+        println!("Nothing to migrate");
+        return Ok(());
+    }
+
+    let journal_path = root.join("request_journal");
+    let entries = journal::read_journal(&journal_path)?;
+    let mut http_log_entries = journal::read_http_log(&root.join("http_log"))?;
+    http_log_entries.extend(journal::read_http_log(&root.join("http_log.migrated"))?);
+
+    // Media URLs by the latest request that tells what the file should look like
+    let mut media_requests: BTreeMap<String, JournalEntry> = BTreeMap::new();
+    for entry in http_log_entries.iter().chain(&entries).filter(|entry| legacy_media_path(&entry.url).is_some()) {
+        match media_requests.get(&entry.url) {
+            Some(previous) if entry.sha256.is_none() && (previous.sha256.is_some() || entry.body_size.is_none()) => {},
+            _ => { media_requests.insert(entry.url.clone(), entry.clone()); },
+        }
+    }
+
+    // Refuse to move metadata that was fetched for a different platform
+    let shop_ids: BTreeSet<i32> = entries.iter().chain(&http_log_entries).filter_map(|entry| url_shop_id(&entry.url)).collect();
+    if root.join("samurai").exists() && !shop_ids.is_empty() && !shop_ids.contains(&shop_id) {
+        return Err(SaveShopError::Config(format!("The archive has no requests with shop ID {}, but some with {:?}. Use --platform to select its platform",
+                                                 shop_id, shop_ids)));
+    }
+
+    let platform_dir = root.join(platform_dir_of(shop_id));
+    let moves: Vec<_> = PLATFORM_ENTRIES.iter()
+        .map(|name| (root.join(name), platform_dir.join(name)))
+        .filter(|(from, _)| from.exists())
+        .collect();
+    if let Some((from, to)) = moves.iter().find(|(_, to)| to.exists()) {
+        return Err(SaveShopError::Config(format!("Can't move {} since {} already exists", from.display(), to.display())));
+    }
+
+    fs::create_dir_all(&platform_dir)?;
+    for (from, to) in &moves {
+        fs::rename(from, to)?;
+        println!("Moved {} to {}", from.display(), to.display());
+    }
+
+    if journal_path.exists() {
+        let mut entries_by_shop_id: BTreeMap<i32, Vec<JournalEntry>> = BTreeMap::new();
+        for entry in entries {
+            let entry_shop_id = url_shop_id(&entry.url).unwrap_or(shop_id);
+            entries_by_shop_id.entry(entry_shop_id).or_default().push(entry);
+        }
+        for (entry_shop_id, entries) in entries_by_shop_id {
+            let dir = root.join(platform_dir_of(entry_shop_id));
+            fs::create_dir_all(&dir)?;
+            let journal = Journal::open(&dir.join("request_journal"))?;
+            for entry in &entries {
+                journal.append(entry)?;
+            }
+            println!("Moved {} request journal entries to {}", entries.len(), dir.join("request_journal").display());
+        }
+        fs::remove_file(&journal_path)?;
+    }
+
+    let media_store = MediaStore::open(&root.join("media"), false)?;
+    let mut num_relinked = 0;
+    for url in media_store.urls() {
+        let path = match url_to_filename(&url).or_else(|_| movie_url_to_filename(&url)) {
+            Ok(path) => root.join(path),
+            Err(_) => continue,
+        };
+        if !path.exists() && media_store.restore(&url, &path)? {
+            num_relinked += 1;
+        }
+    }
+    for (url, entry) in &media_requests {
+        let (legacy_path, path) = match (legacy_media_path(url), url_to_filename(url).or_else(|_| movie_url_to_filename(url))) {
+            (Some(legacy_path), Ok(path)) => (root.join(legacy_path), root.join(path)),
+            _ => continue,
+        };
+        if path.exists() || !legacy_path.is_file() {
+            continue;
+        }
+        let data = fs::read(&legacy_path)?;
+        let matches = match (&entry.sha256, entry.body_size) {
+            (Some(sha256), _) => sha256_hex(&data) == *sha256,
+            (None, Some(size)) => data.len() as u64 == size,
+            (None, None) => true,
+        };
+        if !matches {
+            println!("  Not linking {} since it doesn't match the recorded response for {}", legacy_path.display(), url);
+            continue;
+        }
+        // The file stays at its old location as well, since it may be 3DS media with the same name
+        media_store.insert(url, &data, &path)?;
+        num_relinked += 1;
+    }
+    if num_relinked != 0 {
+        println!("Linked {} media files to their new location", num_relinked);
+    }
+    Ok(())
+}
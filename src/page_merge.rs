@@ -0,0 +1,143 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+
+use crate::error::{ensure_schema, SaveShopError};
+
+// A page of a paginated document, split around its list of contents
+struct Page<'a> {
+    // Everything before the <contents> start tag
+    header: &'a str,
+    start_tag: BytesStart<'a>,
+    // Everything between the <contents> start and end tags
+    items: &'a str,
+    // Everything from the </contents> end tag
+    footer: &'a str,
+}
+
+// Locates the first <contents> node of the document. Nested <contents> nodes (e.g. in titles listed by a directory)
+// are part of its items
+fn split_page(page: &str) -> Result<Page<'_>, SaveShopError> {
+    let mut reader = Reader::from_str(page);
+    reader.check_end_names(true);
+
+    let (start_tag, items_begin) = loop {
+        let tag_begin = reader.buffer_position();
+        match reader.read_event()? {
+            Event::Start(tag) if tag.name().as_ref() == b"contents" => break (tag, tag_begin),
+            Event::Eof => return Err(SaveShopError::SchemaMismatch("Paginated document has no <contents> node".to_string())),
+            _ => {},
+        }
+    };
+    let header = &page[..items_begin];
+    let items_begin = reader.buffer_position();
+
+    let mut depth = 0;
+    let items_end = loop {
+        let tag_begin = reader.buffer_position();
+        match reader.read_event()? {
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => break tag_begin,
+            Event::End(_) => depth -= 1,
+            Event::Eof => return Err(SaveShopError::SchemaMismatch("Paginated document has unterminated <contents> node".to_string())),
+            _ => {},
+        }
+    };
+
+    Ok(Page { header, start_tag, items: &page[items_begin..items_end], footer: &page[items_end..] })
+}
+
+// Combines the pages of a paginated document into a single document listing all contents.
+// Everything but the <contents> node is taken from the first page, except for the part following it, which is taken
+// from the last page. Items are copied verbatim
+#[derive(Default)]
+pub struct PageMerger {
+    header: String,
+    start_tag: Option<BytesStart<'static>>,
+    items: String,
+    footer: String,
+}
+
+impl PageMerger {
+    pub fn add_page(&mut self, page: &str) -> Result<(), SaveShopError> {
+        let page = split_page(page)?;
+        if self.start_tag.is_none() {
+            self.header = page.header.to_string();
+            self.start_tag = Some(page.start_tag.into_owned());
+        }
+        self.items.push_str(page.items);
+        self.footer = page.footer.to_string();
+        Ok(())
+    }
+
+    // Returns the merged document, with the pagination attributes updated to cover the entire list
+    pub fn finish(self, total: usize) -> Result<String, SaveShopError> {
+        let original_tag = match self.start_tag {
+            Some(tag) => tag,
+            None => return Err(SaveShopError::SchemaMismatch("No pages to merge".to_string())),
+        };
+
+        let mut start_tag = original_tag.clone();
+        start_tag.clear_attributes();
+        for attribute in original_tag.attributes() {
+            let attribute = attribute.map_err(quick_xml::Error::from)?;
+            if ![&b"length"[..], b"offset", b"total"].contains(&attribute.key.as_ref()) {
+                start_tag.push_attribute(attribute);
+            }
+        }
+        let total = total.to_string();
+        start_tag.push_attribute(("length", total.as_str()));
+        start_tag.push_attribute(("offset", "0"));
+        start_tag.push_attribute(("total", total.as_str()));
+
+        let mut writer = Writer::new(Vec::new());
+        writer.inner().extend_from_slice(self.header.as_bytes());
+        writer.write_event(Event::Start(start_tag))?;
+        writer.inner().extend_from_slice(self.items.as_bytes());
+        writer.inner().extend_from_slice(self.footer.as_bytes());
+
+        let merged = String::from_utf8(writer.into_inner()).map_err(|err| SaveShopError::SchemaMismatch(err.to_string()))?;
+        ensure_schema!(split_page(&merged)?.items.len() == self.items.len(), "Merged document is malformed");
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST_PAGE: &str = r#"<?xml version="1.0"?><eshop><directory id="1"><name>New</name><contents length="1" offset="0" total="2" kind="list"><content index="1"><title id="10"><contents><content index="1"/></contents></title></content></contents><footer>first</footer></directory></eshop>"#;
+    const SECOND_PAGE: &str = r#"<?xml version="1.0"?><eshop><directory id="1"><name>Renamed</name><contents length="1" offset="1" total="2" kind="list"><content index="2"><title id="20"/></content></contents><footer>last</footer></directory></eshop>"#;
+
+    #[test]
+    fn split_page_finds_outermost_contents() {
+        let page = split_page(FIRST_PAGE).unwrap();
+        assert_eq!(page.header, r#"<?xml version="1.0"?><eshop><directory id="1"><name>New</name>"#);
+        assert_eq!(page.start_tag.name().as_ref(), b"contents");
+        assert_eq!(page.items, r#"<content index="1"><title id="10"><contents><content index="1"/></contents></title></content>"#);
+        assert_eq!(page.footer, "</contents><footer>first</footer></directory></eshop>");
+    }
+
+    #[test]
+    fn split_page_rejects_documents_without_contents() {
+        assert!(matches!(split_page("<eshop><title/></eshop>"), Err(SaveShopError::SchemaMismatch(_))));
+        assert!(split_page("<eshop><contents><content>").is_err());
+    }
+
+    #[test]
+    fn merges_items_of_all_pages() {
+        let mut merger = PageMerger::default();
+        merger.add_page(FIRST_PAGE).unwrap();
+        merger.add_page(SECOND_PAGE).unwrap();
+        let merged = merger.finish(2).unwrap();
+        assert_eq!(merged, concat!(
+            r#"<?xml version="1.0"?><eshop><directory id="1"><name>New</name><contents kind="list" length="2" offset="0" total="2">"#,
+            r#"<content index="1"><title id="10"><contents><content index="1"/></contents></title></content>"#,
+            r#"<content index="2"><title id="20"/></content>"#,
+            "</contents><footer>last</footer></directory></eshop>"));
+    }
+
+    #[test]
+    fn finish_requires_a_page() {
+        assert!(PageMerger::default().finish(0).is_err());
+    }
+}
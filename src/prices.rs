@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::error::SaveShopError;
+use crate::{
+    combined_online_prices_path, contained_files, online_prices_path, read_document, record_failure, samurai_dir, Locale,
+    NodeOnlinePrice, NodePriceValue, OnlinePricesDocument, TitleDocument,
+};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PriceValue {
+    // Formatted for display, e.g. "$4.99"
+    pub amount: String,
+    // ISO 4217 code, e.g. "USD"
+    pub currency: String,
+    // Decimal value as given by the server, e.g. "4.99"
+    pub raw_value: Option<String>,
+}
+
+impl PriceValue {
+    fn from_node(node: NodePriceValue) -> PriceValue {
+        PriceValue { amount: node.amount, currency: node.currency, raw_value: node.raw_value }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Discount {
+    #[serde(flatten)]
+    pub price: PriceValue,
+    // Sale window as reported by the server, e.g. "2016-07-14T15:00:00Z"
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TitlePrice {
+    pub title_id: String,
+    // E.g. "onsale"
+    pub sales_status: Option<String>,
+    pub regular: Option<PriceValue>,
+    pub discount: Option<Discount>,
+}
+
+impl TitlePrice {
+    pub fn from_node(node: NodeOnlinePrice) -> TitlePrice {
+        let (regular, discount) = match node.price {
+            Some(price) => (
+                price.regular_price.map(PriceValue::from_node),
+                price.discount_price.map(|discount| Discount {
+                    start: discount.start_datetime.clone(),
+                    end: discount.end_datetime.clone(),
+                    price: PriceValue::from_node(discount),
+                }),
+            ),
+            None => (None, None),
+        };
+        TitlePrice { title_id: node.title_id, sales_status: node.eshop_sales_status, regular, discount }
+    }
+
+    // Whether the title can be bought
+    pub fn is_purchasable(&self) -> bool {
+        self.sales_status.as_deref() == Some("onsale") && self.regular.is_some()
+    }
+
+    // The price the title is offered at, i.e. the discount price if there is one
+    pub fn current(&self) -> Option<&PriceValue> {
+        self.discount.as_ref().map(|discount| &discount.price).or(self.regular.as_ref())
+    }
+}
+
+pub fn read_prices_document(path: &Path) -> Result<Vec<TitlePrice>, SaveShopError> {
+    let doc: OnlinePricesDocument = read_document(path)?;
+    Ok(doc.online_prices.online_price.into_iter().map(TitlePrice::from_node).collect())
+}
+
+// Reads the price of a single title. Returns None if no price information was fetched or the title isn't for sale
+pub fn read_title_price(locale: &Locale, title_id: &str) -> Result<Option<TitlePrice>, SaveShopError> {
+    let path = online_prices_path(locale, title_id);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    Ok(read_prices_document(Path::new(&path))?.into_iter().find(|price| price.title_id == title_id))
+}
+
+// Reads the prices of all titles of a locale, from the combined price file if available
+pub fn read_locale_prices(locale: &Locale) -> Result<HashMap<String, TitlePrice>, SaveShopError> {
+    let combined_path = combined_online_prices_path(locale);
+    let prices = if Path::new(&combined_path).exists() {
+        read_prices_document(Path::new(&combined_path))?
+    } else {
+        // Archives created before prices were batched only have per-title files
+        let mut prices = Vec::new();
+        for file in contained_files(format!("{}/titles", locale.ninja_dir()).into()) {
+            match read_prices_document(&file.path()) {
+                Ok(file_prices) => prices.extend(file_prices),
+                Err(err) => record_failure(file.path().display().to_string(), err),
+            }
+        }
+        prices
+    };
+    Ok(prices.into_iter().map(|price| (price.title_id.clone(), price)).collect())
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Serialize)]
+struct RegionalPrice {
+    content_id: String,
+    name: String,
+    product_code: Option<String>,
+    purchasable: bool,
+    #[serde(flatten)]
+    price: TitlePrice,
+}
+
+// Prices of the regional releases of one title
+#[derive(Serialize)]
+struct PriceComparison {
+    // Product code without its region character, e.g. "CTR-P-AXY". Region and content ID for titles without product code,
+    // or whose product code is shared with another title of the same region
+    key: String,
+    regions: BTreeMap<String, RegionalPrice>,
+}
+
+fn title_key(region: &str, content_id: &str) -> String {
+    format!("{}:{}", region, content_id)
+}
+
+// Product codes differ across regions only in the last character, e.g. "CTR-P-AXYE" and "CTR-P-AXYP"
+fn comparison_key(region: &str, content_id: &str, product_code: Option<&str>) -> String {
+    match product_code {
+        Some(code) if code.len() > 1 && code.is_ascii() => code[..code.len() - 1].to_string(),
+        _ => title_key(region, content_id),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(out: &mut dyn Write, regions: &[String], comparisons: &[PriceComparison]) -> Result<(), SaveShopError> {
+    let mut header = vec!["key".to_string(), "name".to_string()];
+    for region in regions {
+        for column in ["content_id", "status", "currency", "regular", "discount", "discount_start", "discount_end"] {
+            header.push(format!("{}_{}", region, column));
+        }
+    }
+    writeln!(out, "{}", header.join(","))?;
+
+    for comparison in comparisons {
+        // Use the name from the first region given on the command line
+        let name = regions.iter().find_map(|region| comparison.regions.get(region)).map(|price| price.name.as_str()).unwrap_or_default();
+        let mut row = vec![csv_field(&comparison.key), csv_field(name)];
+        for region in regions {
+            match comparison.regions.get(region) {
+                Some(price) => {
+                    let title_price = &price.price;
+                    let raw_value = |value: Option<&PriceValue>| value.and_then(|v| v.raw_value.clone()).unwrap_or_default();
+                    let discount = title_price.discount.as_ref();
+                    row.extend([
+                        price.content_id.clone(),
+                        title_price.sales_status.clone().unwrap_or_default(),
+                        title_price.current().map(|value| value.currency.clone()).unwrap_or_default(),
+                        raw_value(title_price.regular.as_ref()),
+                        raw_value(discount.map(|d| &d.price)),
+                        discount.and_then(|d| d.start.clone()).unwrap_or_default(),
+                        discount.and_then(|d| d.end.clone()).unwrap_or_default(),
+                    ].iter().map(|value| csv_field(value)));
+                },
+                None => row.extend(std::iter::repeat_n(String::new(), 7)),
+            }
+        }
+        writeln!(out, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+// Writes a table comparing the prices of each title across the given regions.
+// Prices are taken from the first language of each region that has them
+pub fn report_prices(regions: &[String], format: ReportFormat, output: Option<&Path>) -> Result<(), SaveShopError> {
+    let mut comparisons: BTreeMap<String, PriceComparison> = BTreeMap::new();
+
+    for region in regions {
+        let mut languages: Vec<String> = fs::read_dir(samurai_dir(region)).into_iter().flatten().flatten()
+            .filter(|f| f.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|f| f.file_name().to_string_lossy().into_owned())
+            .collect();
+        languages.sort_unstable();
+
+        for language in languages {
+            let locale = Locale { region: region.clone(), language };
+            let prices = read_locale_prices(&locale)?;
+
+            // In order of content ID, so that titles sharing a product code are always resolved the same way
+            let mut files: Vec<fs::DirEntry> = contained_files(format!("{}/title", locale.samurai_dir()).into()).collect();
+            files.sort_unstable_by_key(|file| file.file_name());
+            for file in files {
+                let content_id = file.file_name().to_string_lossy().into_owned();
+                let price = match prices.get(&content_id) {
+                    Some(price) => price,
+                    None => continue,
+                };
+                let title = match TitleDocument::read(&file.path()) {
+                    Ok(doc) => doc.title,
+                    Err(err) => { record_failure(file.path().display().to_string(), err); continue },
+                };
+
+                let mut key = comparison_key(region, &content_id, title.product_code.as_deref());
+                // Several titles of a region may share a product code apart from the last character, which can't be
+                // told apart from regional releases. List the later ones on their own instead of dropping them
+                let existing = comparisons.get(&key).and_then(|comparison| comparison.regions.get(region));
+                if let Some(existing) = existing.filter(|existing| existing.content_id != content_id) {
+                    let own_key = title_key(region, &content_id);
+                    if !comparisons.contains_key(&own_key) {
+                        println!("  {} shares product code {} with {} in region {}, listing it separately",
+                                 content_id, key, existing.content_id, region);
+                    }
+                    key = own_key;
+                }
+                let comparison = comparisons.entry(key.clone()).or_insert_with(|| PriceComparison { key, regions: BTreeMap::new() });
+                comparison.regions.entry(region.clone()).or_insert_with(|| RegionalPrice {
+                    content_id,
+                    name: title.name,
+                    product_code: title.product_code,
+                    purchasable: price.is_purchasable(),
+                    price: price.clone(),
+                });
+            }
+        }
+    }
+
+    let comparisons: Vec<PriceComparison> = comparisons.into_values().collect();
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    match format {
+        ReportFormat::Csv => write_csv(&mut out, regions, &comparisons)?,
+        ReportFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(&comparisons)?)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparison_key_drops_region_character() {
+        assert_eq!(comparison_key("US", "50010000000001", Some("CTR-N-JBPE")), "CTR-N-JBP");
+        assert_eq!(comparison_key("GB", "50010000000011", Some("CTR-N-JBPP")), "CTR-N-JBP");
+    }
+
+    #[test]
+    fn comparison_key_falls_back_to_content_id() {
+        assert_eq!(comparison_key("US", "50010000000001", None), "US:50010000000001");
+        assert_eq!(comparison_key("US", "50010000000001", Some("")), "US:50010000000001");
+        assert_eq!(comparison_key("US", "50010000000001", Some("E")), "US:50010000000001");
+        // Slicing off the last byte of a non-ASCII code could split a character
+        assert_eq!(comparison_key("JP", "50010000000021", Some("CTR-N-ぷ")), "JP:50010000000021");
+    }
+}
@@ -0,0 +1,94 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::error::SaveShopError;
+
+// An XML element kept verbatim, e.g. because it isn't covered by the typed document model
+#[derive(Clone, Debug)]
+pub struct RawElement {
+    pub name: String,
+    pub xml: String,
+}
+
+// Returns all direct children of the first element with the given name
+pub fn children(xml: &str, parent: &str) -> Result<Vec<RawElement>, SaveShopError> {
+    unknown_children(xml, parent, &[])
+}
+
+// Returns the direct children of the first element with the given name that aren't listed in known_children
+pub fn unknown_children(xml: &str, parent: &str, known_children: &[&str]) -> Result<Vec<RawElement>, SaveShopError> {
+    let mut reader = Reader::from_str(xml);
+    reader.check_end_names(true);
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(tag) if tag.name().as_ref() == parent.as_bytes() => break,
+            Event::Eof => return Ok(Vec::new()),
+            _ => {},
+        }
+    }
+
+    let mut children = Vec::new();
+    loop {
+        let child_begin = reader.buffer_position();
+        let (name, has_contents) = match reader.read_event()? {
+            Event::Start(tag) => (String::from_utf8_lossy(tag.name().as_ref()).into_owned(), true),
+            Event::Empty(tag) => (String::from_utf8_lossy(tag.name().as_ref()).into_owned(), false),
+            Event::End(_) | Event::Eof => break,
+            _ => continue,
+        };
+
+        if has_contents {
+            let mut depth = 0;
+            loop {
+                match reader.read_event()? {
+                    Event::Start(_) => depth += 1,
+                    Event::End(_) if depth == 0 => break,
+                    Event::End(_) => depth -= 1,
+                    Event::Eof => return Err(SaveShopError::SchemaMismatch(format!("Unterminated <{}> node", name))),
+                    _ => {},
+                }
+            }
+        }
+
+        if !known_children.contains(&name.as_str()) {
+            children.push(RawElement { xml: xml[child_begin..reader.buffer_position()].to_string(), name });
+        }
+    }
+    Ok(children)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TITLE: &str = r#"<eshop><title id="1"><name>Puzzle</name><new_field lang="en"><item>a</item><item/></new_field><flag/><!-- note --><name>Again</name></title><other/></eshop>"#;
+
+    #[test]
+    fn unknown_children_keeps_unlisted_elements_verbatim() {
+        let unknown = unknown_children(TITLE, "title", &["name"]).unwrap();
+        let names: Vec<&str> = unknown.iter().map(|element| element.name.as_str()).collect();
+        assert_eq!(names, ["new_field", "flag"]);
+        assert_eq!(unknown[0].xml, r#"<new_field lang="en"><item>a</item><item/></new_field>"#);
+        assert_eq!(unknown[1].xml, "<flag/>");
+    }
+
+    #[test]
+    fn children_returns_all_direct_children() {
+        let children = children(TITLE, "title").unwrap();
+        let names: Vec<&str> = children.iter().map(|element| element.name.as_str()).collect();
+        assert_eq!(names, ["name", "new_field", "flag", "name"]);
+    }
+
+    #[test]
+    fn missing_parent_has_no_children() {
+        assert!(children(TITLE, "movie").unwrap().is_empty());
+        assert!(children("<title></title>", "title").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        assert!(children("<title><name>Puzzle</title>", "title").is_err());
+        assert!(children("<title><name>Puzzle", "title").is_err());
+    }
+}
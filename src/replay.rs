@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::crawl_state::sha256_hex;
+use crate::error::SaveShopError;
+use crate::journal::{self, JournalEntry};
+use crate::media_store;
+use crate::retry::FetchError;
+use crate::snapshot::walk_files;
+use crate::{movie_url_to_filename, platform_dir, url_to_filename};
+
+// Answers requests from a previously fetched archive instead of the network.
+// The request journal of the archive tells which response body was last received for each URL,
+// and the body is then looked up among the stored files by its SHA-256
+pub struct ReplaySource {
+    root: PathBuf,
+    // Latest journal entry with a known response body for each URL
+    responses: HashMap<String, JournalEntry>,
+    // Metadata files of the archive by their SHA-256. Only built once needed, since this reads all files
+    metadata_files: OnceCell<HashMap<String, PathBuf>>,
+    misses: AtomicUsize,
+}
+
+impl ReplaySource {
+    pub fn open(root: &Path) -> Result<ReplaySource, SaveShopError> {
+        if crate::migrate::needs_migration(root) {
+            return Err(SaveShopError::OutdatedLayout(root.display().to_string()));
+        }
+        let journal_path = root.join(format!("{}request_journal", platform_dir()));
+        if !journal_path.exists() {
+            return Err(SaveShopError::MissingMetadata(format!("request journal at {}", journal_path.display())));
+        }
+
+        let mut responses = HashMap::new();
+        for entry in journal::read_journal(&journal_path)? {
+            if entry.sha256.is_some() {
+                responses.insert(entry.url.clone(), entry);
+            }
+        }
+        Ok(ReplaySource { root: root.to_path_buf(), responses, metadata_files: OnceCell::new(), misses: AtomicUsize::new(0) })
+    }
+
+    // Number of requests that couldn't be answered from the archive
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn metadata_files(&self) -> &HashMap<String, PathBuf> {
+        self.metadata_files.get_or_init(|| {
+            let mut files = Vec::new();
+            for dir in [format!("{}samurai", platform_dir()), format!("{}ninja", platform_dir())] {
+                if let Err(err) = walk_files(&self.root.join(&dir), &mut files) {
+                    println!("  WARNING: Failed to list replayed metadata in {} ({})", self.root.join(dir).display(), err);
+                }
+            }
+            files.into_iter()
+                 .filter_map(|path| fs::read(&path).ok().map(|data| (sha256_hex(&data), path)))
+                 .collect()
+        })
+    }
+
+    // Returns the stored file with the given content, checking the local path of media URLs and the media store first
+    fn find_body(&self, url: &str, sha256: &str) -> Option<Vec<u8>> {
+        let media_path = url_to_filename(url).or_else(|_| movie_url_to_filename(url)).ok().map(|path| self.root.join(path));
+        let object_path = media_store::object_path(&self.root.join("media"), sha256);
+        for path in media_path.into_iter().chain([object_path]) {
+            if let Ok(data) = fs::read(&path) {
+                if sha256_hex(&data) == sha256 {
+                    return Some(data);
+                }
+            }
+        }
+
+        let path = self.metadata_files().get(sha256)?;
+        fs::read(path).ok()
+    }
+
+    fn lookup(&self, url: &str) -> Result<(&JournalEntry, Vec<u8>), FetchError> {
+        let result = match self.responses.get(url) {
+            None => Err("not in the request journal".to_string()),
+            Some(entry) => {
+                let sha256 = entry.sha256.as_deref().unwrap();
+                match self.find_body(url, sha256) {
+                    Some(body) => Ok((entry, body)),
+                    None => Err(format!("no stored file with SHA-256 {}", sha256)),
+                }
+            },
+        };
+        result.map_err(|reason| {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            FetchError::NotReplayed { url: url.to_string(), reason }
+        })
+    }
+
+    // Recreates the response last received for the given URL
+    pub fn response(&self, url: &str) -> Result<reqwest::Response, FetchError> {
+        let (entry, body) = self.lookup(url)?;
+        let mut response = hyper::Response::builder().status(entry.status.unwrap_or(200));
+        // The stored body has already been decoded, so other headers (e.g. content-encoding) don't apply anymore
+        if let Some(content_type) = entry.header("content-type") {
+            response = response.header(hyper::header::CONTENT_TYPE, content_type);
+        }
+        response.body(body)
+                .map(reqwest::Response::from)
+                .map_err(|err| FetchError::NotReplayed { url: url.to_string(), reason: err.to_string() })
+    }
+
+    // Returns the body of the response last received for the given URL
+    pub fn body(&self, url: &str) -> Result<Vec<u8>, FetchError> {
+        Ok(self.lookup(url)?.1)
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+// Error returned once a request has failed for good
+#[derive(Debug)]
+pub enum FetchError {
+    // The server returned a status that retrying won't fix (e.g. 403)
+    Status { url: String, status: StatusCode },
+    // All attempts failed with retryable errors
+    RetriesExhausted { url: String, attempts: u32, last_error: String },
+    // No response for the URL was found in the archive given by --replay
+    NotReplayed { url: String, reason: String },
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Status { url, status } => write!(f, "Got fatal status {} for {}", status, url),
+            FetchError::RetriesExhausted { url, attempts, last_error } =>
+                write!(f, "Giving up on {} after {} attempts (last error: {})", url, attempts, last_error),
+            FetchError::NotReplayed { url, reason } => write!(f, "Can't replay {} ({})", url, reason),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+// Outcome of a single failed attempt
+pub enum AttemptError {
+    Retryable { reason: String, retry_after: Option<Duration> },
+    Fatal(FetchError),
+}
+
+impl From<reqwest::Error> for AttemptError {
+    // Transport errors (timeouts, connection resets, truncated bodies) are assumed to be temporary
+    fn from(err: reqwest::Error) -> Self {
+        AttemptError::Retryable { reason: err.to_string(), retry_after: None }
+    }
+}
+
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// Parses the Retry-After header, which is either a number of seconds or an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+// Maps non-successful responses to an AttemptError
+pub fn check_status(url: &str, response: &reqwest::Response) -> Result<(), AttemptError> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if is_retryable_status(status) {
+        Err(AttemptError::Retryable { reason: status.to_string(), retry_after: retry_after(response.headers()) })
+    } else {
+        Err(AttemptError::Fatal(FetchError::Status { url: url.to_string(), status }))
+    }
+}
+
+pub struct RetryPolicy {
+    // Zero for unlimited retries
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Exponential backoff with jitter, so that concurrent jobs don't retry in lockstep
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(1u32 << attempt.saturating_sub(1).min(16)).min(self.max_delay);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    // Runs the given request until it succeeds, fails fatally, or the retry budget is exhausted
+    pub async fn run<T, F, Fut>(&self, url: &str, mut attempt: F) -> Result<T, FetchError>
+        where   F: FnMut() -> Fut,
+                Fut: Future<Output = Result<T, AttemptError>> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (reason, retry_after) = match attempt().await {
+                Ok(result) => return Ok(result),
+                Err(AttemptError::Fatal(err)) => return Err(err),
+                Err(AttemptError::Retryable { reason, retry_after }) => (reason, retry_after),
+            };
+
+            if self.max_attempts != 0 && attempts >= self.max_attempts {
+                return Err(FetchError::RetriesExhausted { url: url.to_string(), attempts, last_error: reason });
+            }
+
+            // Servers may ask for long delays (or far-future dates), so Retry-After is capped like the backoff
+            let delay = retry_after.map(|delay| delay.min(self.max_delay)).unwrap_or_else(|| self.backoff(attempts));
+            println!("  Got error {}, retrying in {:.1} seconds", reason, delay.as_secs_f64());
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn policy(max_attempts: u32, max_delay: Duration) -> RetryPolicy {
+        RetryPolicy { max_attempts, base_delay: Duration::from_secs(1), max_delay }
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let policy = policy(0, Duration::from_secs(10));
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1), "{:?}", first);
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_secs(2) && third <= Duration::from_secs(4), "{:?}", third);
+            // Large attempt counts neither overflow nor exceed the maximum
+            let capped = policy.backoff(u32::MAX);
+            assert!(capped >= Duration::from_secs(5) && capped <= Duration::from_secs(10), "{:?}", capped);
+        }
+    }
+
+    #[test]
+    fn retry_after_parses_seconds_and_dates() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers(" 5 ")), Some(Duration::from_secs(5)));
+        assert_eq!(retry_after(&headers("Sun, 06 Nov 1994 08:49:37 GMT")), Some(Duration::ZERO));
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let delay = retry_after(&headers(&date)).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60), "{:?}", delay);
+
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn run_caps_retry_after_at_max_delay() {
+        let policy = policy(3, Duration::from_millis(10));
+        let mut attempts = 0;
+        let started = std::time::Instant::now();
+        let result: Result<(), FetchError> = policy.run("https://example.org/", || {
+            attempts += 1;
+            async { Err(AttemptError::Retryable { reason: "503".to_string(), retry_after: Some(Duration::from_secs(3600)) }) }
+        }).await;
+        assert!(matches!(result, Err(FetchError::RetriesExhausted { attempts: 3, .. })));
+        assert_eq!(attempts, 3);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
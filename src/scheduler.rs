@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+
+// Servers the eShop is spread across. Each gets its own rate limit,
+// since they are operated (and rate-limited) independently.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum HostClass {
+    Samurai,
+    Ninja,
+    Kanzashi,
+    ImgEshop,
+}
+
+impl HostClass {
+    // Classifies URLs of the eShop's own servers by their host name. See HostConfig::host_class for other servers
+    pub fn from_url(url: &str) -> Option<HostClass> {
+        let host = url.split("://").nth(1)?.split('/').next()?;
+        if host.starts_with("samurai.") {
+            Some(HostClass::Samurai)
+        } else if host.starts_with("ninja.") {
+            Some(HostClass::Ninja)
+        } else if host.starts_with("kanzashi") {
+            // Includes the kanzashi-movie hosts
+            Some(HostClass::Kanzashi)
+        } else if host.starts_with("img-eshop.") {
+            Some(HostClass::ImgEshop)
+        } else {
+            None
+        }
+    }
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Token bucket holding up to one second worth of requests.
+// A rate of zero disables the limit.
+pub struct RateLimiter {
+    requests_per_second: f64,
+    state: Mutex<BucketState>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> Self {
+        // Start with a single token so that bursts only build up over time
+        RateLimiter {
+            requests_per_second,
+            state: Mutex::new(BucketState { tokens: 1.0, last_refill: Instant::now() }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        if self.requests_per_second <= 0.0 {
+            return;
+        }
+
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = Instant::now();
+                let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+                state.tokens = (state.tokens + elapsed * self.requests_per_second).min(self.requests_per_second.max(1.0));
+                state.last_refill = now;
+
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - state.tokens) / self.requests_per_second)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+pub struct SchedulerConfig {
+    pub max_in_flight: usize,
+    pub global_rate: f64,
+    pub host_rates: Vec<(HostClass, f64)>,
+}
+
+// Bounds the number of concurrent requests and paces them according to
+// the global and per-host rate limits.
+pub struct Scheduler {
+    in_flight: Semaphore,
+    global: RateLimiter,
+    hosts: HashMap<HostClass, RateLimiter>,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Scheduler {
+            in_flight: Semaphore::new(config.max_in_flight.max(1)),
+            global: RateLimiter::new(config.global_rate),
+            hosts: config.host_rates.into_iter().map(|(host, rate)| (host, RateLimiter::new(rate))).collect(),
+        }
+    }
+
+    // Waits for a free request slot and for the rate limits of the given host.
+    // The returned permit must be held until the response body has been read.
+    pub async fn acquire(&self, host: Option<HostClass>) -> SemaphorePermit<'_> {
+        let permit = self.in_flight.acquire().await.unwrap();
+        if let Some(host_limiter) = host.and_then(|host| self.hosts.get(&host)) {
+            host_limiter.acquire().await;
+        }
+        self.global.acquire().await;
+        permit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosts::HostConfig;
+
+    fn mirror_config() -> HostConfig {
+        let mut config: HostConfig = serde_json::from_str(r#"{
+            "samurai_urls": { "3ds": "https://eshop.mirror.test/samurai/ws" },
+            "ninja_urls": { "wiiu": "https://eshop.mirror.test/ninja/ws" },
+            "host_aliases": { "https://cdn.mirror.test/kanzashi-ctr": "kanzashi-ctr.cdn.nintendo.net" }
+        }"#).unwrap();
+        config.apply_overrides("3ds", None, None, &[], &[]).unwrap();
+        config
+    }
+
+    #[test]
+    fn classifies_mirror_hosts_by_configuration() {
+        let config = mirror_config();
+        assert_eq!(config.host_class("https://eshop.mirror.test/samurai/ws/US/contents?offset=0"), Some(HostClass::Samurai));
+        assert_eq!(config.host_class("https://eshop.mirror.test/ninja/ws/US/titles/online_prices"), Some(HostClass::Ninja));
+        assert_eq!(config.host_class("https://cdn.mirror.test/kanzashi-ctr/i/icon.jpg"), Some(HostClass::Kanzashi));
+        assert_eq!(config.host_class("https://eshop.mirror.test/other/file"), None);
+        // Default servers are still recognized
+        assert_eq!(config.host_class("https://ninja.ctr.shop.nintendo.net/ninja/ws/US/titles/online_prices"), Some(HostClass::Ninja));
+        assert_eq!(config.host_class("https://img-eshop.cdn.nintendo.net/i/banner.jpg"), Some(HostClass::ImgEshop));
+    }
+
+    #[tokio::test]
+    async fn rate_limits_requests_to_mirror_hosts() {
+        let config = mirror_config();
+        let scheduler = Scheduler::new(SchedulerConfig {
+            max_in_flight: 4,
+            global_rate: 0.0,
+            host_rates: vec![(HostClass::Samurai, 20.0), (HostClass::Kanzashi, 0.0)],
+        });
+
+        // The first request may be sent right away, and each further one 50ms later
+        let started = Instant::now();
+        for _ in 0..4 {
+            drop(scheduler.acquire(config.host_class("https://eshop.mirror.test/samurai/ws/US/contents")).await);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(140), "{:?}", started.elapsed());
+
+        let started = Instant::now();
+        for _ in 0..4 {
+            drop(scheduler.acquire(config.host_class("https://cdn.mirror.test/kanzashi-ctr/i/icon.jpg")).await);
+        }
+        assert!(started.elapsed() < Duration::from_millis(100), "{:?}", started.elapsed());
+    }
+}
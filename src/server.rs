@@ -0,0 +1,245 @@
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::error::SaveShopError;
+use crate::{movie_url_to_filename, url_to_filename};
+
+// The viewer web-app, served at the root
+const VIEWER: &str = include_str!("../index.html");
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(pos + 1..pos + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            pos += 3;
+        } else {
+            decoded.push(bytes[pos]);
+            pos += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Returns the value of the given parameter of a URL query string
+fn query_parameter(query: &str, name: &str) -> Option<String> {
+    query.split('&').filter_map(|param| param.split_once('='))
+         .find(|(key, _)| *key == name)
+         .and_then(|(_, value)| percent_decode(&value.replace('+', " ")))
+}
+
+// Maps the URL of an eShop resource to the location of its local copy.
+// Videos are served in their converted mp4 form if available
+fn map_url(url: &str) -> Option<String> {
+    if let Ok(path) = url_to_filename(url) {
+        return Some(path);
+    }
+    let moflex = movie_url_to_filename(url).ok()?;
+    let mp4 = Path::new(&moflex).with_extension("mp4");
+    Some(if mp4.exists() { mp4.to_string_lossy().into_owned() } else { moflex })
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("bmp") => "image/bmp",
+        Some("mp4") => "video/mp4",
+        Some("json") => "application/json",
+        Some("moflex") => "application/octet-stream",
+        // Metadata documents are stored without an extension, possibly in a platform directory (e.g. wiiu/samurai)
+        None if path.iter().take(2).any(|dir| matches!(dir.to_str(), Some("samurai" | "ninja"))) => "application/xml; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(status.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+// Size of the chunks files are streamed in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(PartialEq, Eq, Debug)]
+enum RangeRequest {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Parses a "bytes=start-end" range header. Multiple ranges and malformed headers are ignored, so that the full file is served
+fn parse_range(range: &str, size: u64) -> RangeRequest {
+    let bounds = range.strip_prefix("bytes=")
+                      .filter(|spec| !spec.contains(','))
+                      .and_then(|spec| spec.split_once('-'))
+                      .and_then(|(start, end)| match (start.trim(), end.trim()) {
+                          // Suffix range, e.g. "bytes=-500" for the last 500 bytes
+                          ("", suffix) => suffix.parse::<u64>().ok().map(|suffix| (size.saturating_sub(suffix), None)),
+                          (start, "") => start.parse().ok().map(|start| (start, None)),
+                          (start, end) => Some((start.parse().ok()?, Some(end.parse::<u64>().ok()?))),
+                      });
+    let (start, end) = match bounds {
+        Some(bounds) => bounds,
+        None => return RangeRequest::Full,
+    };
+    match size.checked_sub(1) {
+        Some(last) if start <= last && end.is_none_or(|end| start <= end) => RangeRequest::Partial(start, end.map_or(last, |end| end.min(last))),
+        _ => RangeRequest::Unsatisfiable,
+    }
+}
+
+async fn serve_file(request: &Request<Body>, path: &Path, content_type: &'static str) -> Result<Response<Body>, std::io::Error> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(status_response(StatusCode::NOT_FOUND)),
+        Err(err) => return Err(err),
+    };
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+    let size = metadata.len();
+
+    let range = request.headers().get(header::RANGE).and_then(|range| range.to_str().ok());
+    let (status, start, end) = match range.map_or(RangeRequest::Full, |range| parse_range(range, size)) {
+        RangeRequest::Full => (StatusCode::OK, 0, size),
+        RangeRequest::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end + 1),
+        RangeRequest::Unsatisfiable => {
+            let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+            response.headers_mut().insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes */{}", size)).unwrap());
+            return Ok(response);
+        },
+    };
+
+    // Stream the file rather than reading it into memory, since videos may be large
+    let body = if request.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        file.seek(SeekFrom::Start(start)).await?;
+        Body::wrap_stream(futures::stream::try_unfold(file.take(end - start), |mut reader| async move {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let length = reader.read(&mut chunk).await?;
+            chunk.truncate(length);
+            Ok::<_, std::io::Error>((length != 0).then_some((chunk, reader)))
+        }))
+    };
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+    if status == StatusCode::PARTIAL_CONTENT {
+        headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end - 1, size)).unwrap());
+    }
+    Ok(response)
+}
+
+async fn handle_request(request: Request<Body>, root: PathBuf) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let path = match percent_decode(request.uri().path()) {
+        Some(path) => path,
+        None => return Ok(status_response(StatusCode::BAD_REQUEST)),
+    };
+
+    if path == "/" || path == "/index.html" {
+        let mut response = Response::new(Body::from(VIEWER));
+        response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        return Ok(response);
+    }
+
+    // Redirect from eShop resource URLs to their local copies
+    if path == "/resource" {
+        let local_path = request.uri().query().and_then(|query| query_parameter(query, "url")).and_then(|url| map_url(&url));
+        return Ok(match local_path {
+            Some(local_path) => {
+                let mut response = status_response(StatusCode::FOUND);
+                let location = format!("/{}", local_path.split('/').map(|segment| segment.replace('%', "%25")).collect::<Vec<_>>().join("/"));
+                match HeaderValue::from_str(&location) {
+                    Ok(location) => { response.headers_mut().insert(header::LOCATION, location); },
+                    Err(_) => return Ok(status_response(StatusCode::NOT_FOUND)),
+                }
+                response
+            },
+            None => status_response(StatusCode::NOT_FOUND),
+        });
+    }
+
+    // Only serve files below the archive root
+    let relative_path = PathBuf::from(path.trim_start_matches('/'));
+    if !relative_path.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+
+    Ok(match serve_file(&request, &root.join(&relative_path), content_type(&relative_path)).await {
+        Ok(response) => response,
+        Err(err) => {
+            println!("  Failed to serve {}: {}", relative_path.display(), err);
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        },
+    })
+}
+
+// Serves the archive in the current directory along with the viewer web-app until interrupted
+pub async fn serve(address: SocketAddr) -> Result<(), SaveShopError> {
+    let root = std::env::current_dir()?;
+    let make_service = make_service_fn(move |_| {
+        let root = root.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle_request(request, root.clone()))) }
+    });
+
+    let server = hyper::Server::try_bind(&address)?.serve(make_service);
+    println!("Serving archive at http://{}/", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), RangeRequest::Partial(0, 9));
+        assert_eq!(parse_range("bytes=90-", 100), RangeRequest::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-10", 100), RangeRequest::Partial(90, 99));
+        assert_eq!(parse_range("bytes= 5 - 5 ", 100), RangeRequest::Partial(5, 5));
+        // Ends beyond the file are clamped, as are suffixes longer than the file
+        assert_eq!(parse_range("bytes=50-1000", 100), RangeRequest::Partial(50, 99));
+        assert_eq!(parse_range("bytes=-1000", 100), RangeRequest::Partial(0, 99));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=100-200", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=10-5", 100), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_multiple_and_malformed_ranges() {
+        for range in ["bytes=0-1,5-6", "bytes=", "bytes=-", "bytes=a-b", "bytes=5", "items=0-9", "0-9"] {
+            assert_eq!(parse_range(range, 100), RangeRequest::Full, "{}", range);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crawl_state::sha256_hex;
+use crate::error::SaveShopError;
+use crate::warc::warc_date;
+
+// A file recorded in a snapshot. Stored as one JSON object per line of the snapshot manifest
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotEntry {
+    // Location in the archive, relative to its root
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+// What a snapshot covers. Stored as the first line of the snapshot manifest.
+// Manifests written before this was recorded don't have it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotScope {
+    pub platform: String,
+    // Sorted and without duplicates
+    pub regions: Vec<String>,
+}
+
+impl SnapshotScope {
+    pub fn new(platform: &str, regions: &[String]) -> SnapshotScope {
+        let mut regions = regions.to_vec();
+        regions.sort_unstable();
+        regions.dedup();
+        SnapshotScope { platform: platform.to_string(), regions }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ManifestLine {
+    Entry(SnapshotEntry),
+    Scope(SnapshotScope),
+}
+
+// Keeps dated copies of the metadata trees (samurai/, ninja/) across runs.
+// File contents are stored once by their SHA-256, and each snapshot is a manifest listing the files at that time
+pub struct SnapshotStore {
+    root: PathBuf,
+}
+
+pub fn walk_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), SaveShopError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+// Sort key of a snapshot ID, e.g. ("20161017T120000Z", 10) for "20161017T120000Z-10".
+// Serials are compared as numbers, so that the tenth snapshot of a second follows the second one
+fn id_order(id: &str) -> (&str, u32) {
+    match id.split_once('-') {
+        Some((timestamp, serial)) => (timestamp, serial.parse().unwrap_or(u32::MAX)),
+        None => (id, 1),
+    }
+}
+
+impl SnapshotStore {
+    pub fn open(root: &Path) -> Result<SnapshotStore, SaveShopError> {
+        fs::create_dir_all(root.join("objects"))?;
+        fs::create_dir_all(root.join("manifests"))?;
+        Ok(SnapshotStore { root: root.to_path_buf() })
+    }
+
+    pub fn object_path(&self, sha256: &str) -> PathBuf {
+        self.root.join("objects").join(&sha256[..2]).join(sha256)
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.root.join("manifests").join(id)
+    }
+
+    // IDs of all snapshots, oldest first
+    pub fn list(&self) -> Result<Vec<String>, SaveShopError> {
+        let mut ids: Vec<String> = fs::read_dir(self.root.join("manifests"))?.flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.ends_with(".part"))
+            .collect();
+        ids.sort_unstable_by(|a, b| id_order(a).cmp(&id_order(b)));
+        Ok(ids)
+    }
+
+    fn read_manifest_with_scope(&self, id: &str) -> Result<(Option<SnapshotScope>, Vec<SnapshotEntry>), SaveShopError> {
+        let file = match File::open(self.manifest_path(id)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound =>
+                return Err(SaveShopError::MissingMetadata(format!("snapshot {}", id))),
+            Err(err) => return Err(err.into()),
+        };
+        let mut scope = None;
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?)? {
+                ManifestLine::Entry(entry) => entries.push(entry),
+                ManifestLine::Scope(manifest_scope) => scope = Some(manifest_scope),
+            }
+        }
+        Ok((scope, entries))
+    }
+
+    pub fn read_manifest(&self, id: &str) -> Result<Vec<SnapshotEntry>, SaveShopError> {
+        Ok(self.read_manifest_with_scope(id)?.1)
+    }
+
+    // Records the current contents of the given directories as a new snapshot.
+    // Returns the ID of the new snapshot, or of the latest one with the same scope if nothing changed since
+    pub fn create(&self, scope: &SnapshotScope, dirs: &[PathBuf]) -> Result<String, SaveShopError> {
+        let mut files = Vec::new();
+        for dir in dirs {
+            walk_files(dir, &mut files)?;
+        }
+        files.sort_unstable();
+
+        let mut entries = Vec::new();
+        for file in files {
+            let data = fs::read(&file)?;
+            let sha256 = sha256_hex(&data);
+            let object_path = self.object_path(&sha256);
+            if !object_path.exists() {
+                fs::create_dir_all(object_path.parent().unwrap())?;
+                let temp_path = object_path.with_extension("part");
+                File::create(&temp_path)?.write_all(&data)?;
+                fs::rename(&temp_path, &object_path)?;
+            }
+            let path = file.to_string_lossy().replace('\\', "/");
+            entries.push(SnapshotEntry { path, sha256, size: data.len() as u64 });
+        }
+
+        // Snapshots of other regions or platforms are skipped, as are those whose scope wasn't recorded
+        for id in self.list()?.into_iter().rev() {
+            let (manifest_scope, manifest_entries) = self.read_manifest_with_scope(&id)?;
+            if manifest_scope.as_ref() == Some(scope) {
+                if manifest_entries == entries {
+                    return Ok(id);
+                }
+                break;
+            }
+        }
+
+        // E.g. "20161017T120000Z", with a suffix for multiple snapshots within the same second
+        let timestamp = warc_date(SystemTime::now()).replace(['-', ':'], "");
+        let mut id = timestamp.clone();
+        let mut serial = 1;
+        while self.manifest_path(&id).exists() {
+            serial += 1;
+            id = format!("{}-{}", timestamp, serial);
+        }
+
+        // Write to a temporary file first so that interrupted runs don't leave incomplete snapshots behind
+        let temp_path = self.manifest_path(&format!("{}.part", id));
+        let mut manifest = File::create(&temp_path)?;
+        writeln!(manifest, "{}", serde_json::to_string(scope)?)?;
+        for entry in &entries {
+            writeln!(manifest, "{}", serde_json::to_string(entry)?)?;
+        }
+        manifest.sync_all()?;
+        fs::rename(&temp_path, self.manifest_path(&id))?;
+        Ok(id)
+    }
+
+    // Recreates the files of a snapshot below the given directory, which can then be used like an archive root.
+    // Files are copied rather than linked, since re-fetching into the directory would otherwise modify stored objects
+    pub fn checkout(&self, id: &str, output: &Path) -> Result<usize, SaveShopError> {
+        let entries = self.read_manifest(id)?;
+        for entry in &entries {
+            let path = output.join(&entry.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(self.object_path(&entry.sha256), path)?;
+        }
+        Ok(entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_snapshots_in_creation_order() {
+        let root = std::env::temp_dir().join(format!("saveShop-snapshot-test-{}", std::process::id()));
+        let store = SnapshotStore::open(&root).unwrap();
+        for id in ["20161017T120000Z-10", "20161017T120001Z", "20161017T120000Z", "20161017T120000Z-2", "20161017T120000Z-3.part"] {
+            fs::write(store.manifest_path(id), "").unwrap();
+        }
+        let ids = store.list().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(ids, ["20161017T120000Z", "20161017T120000Z-2", "20161017T120000Z-10", "20161017T120001Z"]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::error::SaveShopError;
+use crate::{ec_info_path, ninja_dir, read_document, record_failure, DemoDocument, EcInfoDocument, Locale, TitleDocument};
+
+#[derive(Serialize, JsonSchema)]
+pub struct ContentLock {
+    /// Whether the seed needed to decrypt pre-purchased content has been published
+    pub seed_published: Option<bool>,
+    pub external_seed: Option<String>,
+    pub playable_date: Option<String>,
+}
+
+/// An eShop content item mapped to a title ID
+#[derive(Serialize, JsonSchema)]
+pub struct MappedContent {
+    pub region: String,
+    /// eShop content ID, e.g. "50010000000001"
+    pub content_id: String,
+    /// "title" or "demo"
+    pub kind: String,
+    /// None if no samurai metadata was fetched
+    pub name: Option<String>,
+    pub content_size: Option<u64>,
+    pub title_version: Option<u32>,
+    pub disable_download: Option<bool>,
+    pub content_lock: Option<ContentLock>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TitleIdEntry {
+    /// 16 hex digits, e.g. "0004000000030800"
+    pub title_id: String,
+    /// All content items across regions that refer to this title ID
+    pub contents: Vec<MappedContent>,
+}
+
+/// Mapping from title IDs to the eShop content items referring to them
+#[derive(Serialize, JsonSchema)]
+pub struct TitleIdMapping {
+    /// Incremented on incompatible changes to this schema
+    pub schema_version: u32,
+    pub titles: Vec<TitleIdEntry>,
+}
+
+// Returns the name given by the samurai metadata of a title or demo, along with the kind of content
+fn content_name(locale: &Locale, content_id: &str) -> (String, Option<String>) {
+    let title_path = format!("{}/title/{}", locale.samurai_dir(), content_id);
+    if let Ok(doc) = TitleDocument::read(Path::new(&title_path)) {
+        return ("title".to_string(), Some(doc.title.name));
+    }
+
+    let demo_path = format!("{}/demo/{}", locale.samurai_dir(), content_id);
+    match read_document::<DemoDocument>(Path::new(&demo_path)) {
+        Ok(doc) => ("demo".to_string(), Some(doc.content.demo.name)),
+        // Demos are only known as such from their samurai metadata
+        Err(_) if Path::new(&demo_path).exists() => ("demo".to_string(), None),
+        Err(_) => ("title".to_string(), None),
+    }
+}
+
+// Builds the title ID mapping from all ec_info documents fetched for the given regions.
+// Content items are listed once per region, using the first language that has their ec_info
+pub fn collect(regions: &[String]) -> Result<TitleIdMapping, SaveShopError> {
+    let mut titles: BTreeMap<String, Vec<MappedContent>> = BTreeMap::new();
+
+    for region in regions {
+        let mut languages: Vec<String> = fs::read_dir(ninja_dir(region)).into_iter().flatten().flatten()
+            .filter(|f| f.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|f| f.file_name().to_string_lossy().into_owned())
+            .collect();
+        languages.sort_unstable();
+
+        let mut seen_content_ids = HashSet::new();
+        for language in languages {
+            let locale = Locale { region: region.clone(), language };
+            let mut content_ids: Vec<String> = fs::read_dir(format!("{}/title", locale.ninja_dir()))
+                .into_iter().flatten().flatten()
+                .filter(|f| f.file_type().is_ok_and(|t| t.is_dir()))
+                .map(|f| f.file_name().to_string_lossy().into_owned())
+                .collect();
+            content_ids.sort_unstable();
+
+            for content_id in content_ids {
+                let path = ec_info_path(&locale, &content_id);
+                if seen_content_ids.contains(&content_id) || !Path::new(&path).exists() {
+                    continue;
+                }
+                let ec_info = match read_document::<EcInfoDocument>(Path::new(&path)) {
+                    Ok(doc) => doc.title_ec_info,
+                    Err(err) => { record_failure(path, err); continue },
+                };
+
+                let (kind, name) = content_name(&locale, &content_id);
+                titles.entry(ec_info.title_id.to_ascii_uppercase()).or_default().push(MappedContent {
+                    region: locale.region.clone(),
+                    kind,
+                    name,
+                    content_size: ec_info.content_size,
+                    title_version: ec_info.title_version,
+                    disable_download: ec_info.disable_download,
+                    content_lock: ec_info.content_lock.map(|lock| ContentLock {
+                        seed_published: lock.seed_published,
+                        external_seed: lock.external_seed,
+                        playable_date: lock.playable_date,
+                    }),
+                    content_id: content_id.clone(),
+                });
+                seen_content_ids.insert(content_id);
+            }
+        }
+    }
+
+    Ok(TitleIdMapping {
+        schema_version: crate::export::SCHEMA_VERSION,
+        titles: titles.into_iter().map(|(title_id, contents)| TitleIdEntry { title_id, contents }).collect(),
+    })
+}
+
+// Prints all content items matching the given title ID or content ID. Returns the number of matches
+pub fn lookup(regions: &[String], id: &str) -> Result<usize, SaveShopError> {
+    let mapping = collect(regions)?;
+
+    let mut num_matches = 0;
+    for entry in &mapping.titles {
+        let title_id_matches = entry.title_id.eq_ignore_ascii_case(id);
+        for content in entry.contents.iter().filter(|content| title_id_matches || content.content_id == id) {
+            println!("{}  {}  {} {}  {}  (version {}, {} bytes)",
+                     entry.title_id, content.region, content.kind, content.content_id,
+                     content.name.as_deref().unwrap_or("<unknown name>"),
+                     content.title_version.map(|v| v.to_string()).unwrap_or_else(|| "?".to_string()),
+                     content.content_size.map(|v| v.to_string()).unwrap_or_else(|| "?".to_string()));
+            num_matches += 1;
+        }
+    }
+
+    if num_matches == 0 {
+        println!("No title ID or content ID \"{}\" found in the given regions", id);
+    }
+    Ok(num_matches)
+}
@@ -0,0 +1,250 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use rand::Rng;
+
+use crate::crawl_state::sha256_hex;
+use crate::error::SaveShopError;
+
+const WARC_VERSION: &str = "WARC/1.1";
+
+struct WarcFile {
+    file: File,
+    size: u64,
+}
+
+// Writes request/response pairs to a series of WARC files.
+// A new file is started once the current one grows beyond the configured size
+pub struct WarcWriter {
+    dir: PathBuf,
+    max_size: u64,
+    // Used to name files uniquely across runs
+    run_timestamp: u64,
+    current: Mutex<Option<WarcFile>>,
+    next_serial: Mutex<u32>,
+}
+
+impl WarcWriter {
+    pub fn new(dir: PathBuf, max_size: u64) -> Result<WarcWriter, SaveShopError> {
+        fs::create_dir_all(&dir)?;
+        let run_timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        Ok(WarcWriter { dir, max_size, run_timestamp, current: Mutex::new(None), next_serial: Mutex::new(0) })
+    }
+
+    // Writes a request record and the matching response record for a GET request
+    pub fn write_exchange(&self, url: &str, version: reqwest::Version, status: reqwest::StatusCode,
+                          headers: &reqwest::header::HeaderMap, body: &[u8]) -> Result<(), SaveShopError> {
+        let (host, path) = url.split_once("://").map(|(_, rest)| rest).and_then(|rest| rest.find('/').map(|pos| rest.split_at(pos)))
+                               .ok_or_else(|| SaveShopError::UnknownHost(url.to_string()))?;
+        let http_version = match version {
+            reqwest::Version::HTTP_09 => "HTTP/0.9",
+            reqwest::Version::HTTP_10 => "HTTP/1.0",
+            reqwest::Version::HTTP_2 => "HTTP/2",
+            reqwest::Version::HTTP_3 => "HTTP/3",
+            _ => "HTTP/1.1",
+        };
+
+        let request = format!("GET {} {}\r\nHost: {}\r\n\r\n", path, http_version, host).into_bytes();
+
+        // The body was de-chunked while receiving it, so framing headers are replaced to match the stored body.
+        // Content-Encoding is kept as is, since reqwest is built without support for decompression
+        let mut response = format!("{} {}\r\n", http_version, status).into_bytes();
+        let framing_headers = [reqwest::header::TRANSFER_ENCODING, reqwest::header::CONTENT_LENGTH];
+        for (name, value) in headers.iter().filter(|(name, _)| !framing_headers.contains(name)) {
+            response.extend_from_slice(name.as_str().as_bytes());
+            response.extend_from_slice(b": ");
+            response.extend_from_slice(value.as_bytes());
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(format!("content-length: {}\r\n\r\n", body.len()).as_bytes());
+        response.extend_from_slice(body);
+
+        let date = warc_date(SystemTime::now());
+        let response_id = record_id();
+        let request_id = record_id();
+
+        let response_record = build_record(&[
+            ("WARC-Type", "response".to_string()),
+            ("WARC-Record-ID", response_id.clone()),
+            ("WARC-Date", date.clone()),
+            ("WARC-Target-URI", url.to_string()),
+            ("WARC-Concurrent-To", request_id.clone()),
+            ("WARC-Payload-Digest", format!("sha256:{}", sha256_hex(body))),
+            ("Content-Type", "application/http;msgtype=response".to_string()),
+        ], &response);
+        let request_record = build_record(&[
+            ("WARC-Type", "request".to_string()),
+            ("WARC-Record-ID", request_id),
+            ("WARC-Date", date),
+            ("WARC-Target-URI", url.to_string()),
+            ("WARC-Concurrent-To", response_id),
+            ("Content-Type", "application/http;msgtype=request".to_string()),
+        ], &request);
+
+        let mut current = self.current.lock().unwrap();
+        if current.as_ref().is_none_or(|warc| warc.size >= self.max_size) {
+            *current = Some(self.start_file()?);
+        }
+        let warc = current.as_mut().unwrap();
+        warc.file.write_all(&request_record)?;
+        warc.file.write_all(&response_record)?;
+        warc.size += (request_record.len() + response_record.len()) as u64;
+        Ok(())
+    }
+
+    fn start_file(&self) -> Result<WarcFile, SaveShopError> {
+        let serial = {
+            let mut next_serial = self.next_serial.lock().unwrap();
+            *next_serial += 1;
+            *next_serial - 1
+        };
+        let filename = format!("saveShop-{}-{:05}.warc", self.run_timestamp, serial);
+        let mut file = fs::OpenOptions::new().create_new(true).write(true).open(self.dir.join(&filename))?;
+
+        let info = format!("software: saveShop {}\r\nformat: WARC File Format 1.1\r\n", env!("CARGO_PKG_VERSION"));
+        let record = build_record(&[
+            ("WARC-Type", "warcinfo".to_string()),
+            ("WARC-Record-ID", record_id()),
+            ("WARC-Date", warc_date(SystemTime::now())),
+            ("WARC-Filename", filename),
+            ("Content-Type", "application/warc-fields".to_string()),
+        ], info.as_bytes());
+        file.write_all(&record)?;
+        Ok(WarcFile { file, size: record.len() as u64 })
+    }
+}
+
+fn build_record(fields: &[(&str, String)], block: &[u8]) -> Vec<u8> {
+    let mut record = format!("{}\r\n", WARC_VERSION).into_bytes();
+    for (name, value) in fields {
+        record.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    record.extend_from_slice(format!("WARC-Block-Digest: sha256:{}\r\n", sha256_hex(block)).as_bytes());
+    record.extend_from_slice(format!("Content-Length: {}\r\n\r\n", block.len()).as_bytes());
+    record.extend_from_slice(block);
+    record.extend_from_slice(b"\r\n\r\n");
+    record
+}
+
+// Random (version 4) UUID
+fn record_id() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("<urn:uuid:{}-{}-{}-{}-{}>", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+// Formats the given time as "YYYY-MM-DDThh:mm:ssZ"
+pub fn warc_date(time: SystemTime) -> String {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since 1970-01-01 to a civil date (see http://howardhinnant.github.io/date_algorithms.html)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+
+    // Splits a WARC file into its records, as (header, block) pairs
+    fn parse_records(mut data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut records = Vec::new();
+        while !data.is_empty() {
+            let header_end = data.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+            let header = String::from_utf8(data[..header_end].to_vec()).unwrap();
+            let length: usize = header.lines().find_map(|line| line.strip_prefix("Content-Length: ")).unwrap().parse().unwrap();
+            let block_begin = header_end + 4;
+            let block = data[block_begin..block_begin + length].to_vec();
+            assert_eq!(&data[block_begin + length..block_begin + length + 4], b"\r\n\r\n");
+            data = &data[block_begin + length + 4..];
+            records.push((header, block));
+        }
+        records
+    }
+
+    fn field<'a>(header: &'a str, name: &str) -> &'a str {
+        header.lines().find_map(|line| line.strip_prefix(&format!("{}: ", name))).unwrap_or_else(|| panic!("No {} in {}", name, header))
+    }
+
+    #[test]
+    fn writes_request_and_response_records() {
+        let dir = std::env::temp_dir().join(format!("saveShop-warc-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let writer = WarcWriter::new(dir.clone(), 1 << 20).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/xml; charset=utf-8"));
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("999"));
+        let body = "<eshop>caf\u{e9}</eshop>".as_bytes();
+        writer.write_exchange("https://samurai.ctr.shop.nintendo.net/samurai/ws/US/contents?offset=0", reqwest::Version::HTTP_11,
+                              reqwest::StatusCode::OK, &headers, body).unwrap();
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().flatten().collect();
+        assert_eq!(files.len(), 1);
+        let records = parse_records(&fs::read(files[0].path()).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(records.len(), 3);
+
+        let (info, _) = &records[0];
+        assert!(info.starts_with("WARC/1.1\r\n"));
+        assert_eq!(field(info, "WARC-Type"), "warcinfo");
+
+        let (request, request_block) = &records[1];
+        assert_eq!(field(request, "WARC-Type"), "request");
+        assert_eq!(String::from_utf8_lossy(request_block),
+                   "GET /samurai/ws/US/contents?offset=0 HTTP/1.1\r\nHost: samurai.ctr.shop.nintendo.net\r\n\r\n");
+
+        let (response, response_block) = &records[2];
+        assert_eq!(field(response, "WARC-Type"), "response");
+        assert_eq!(field(response, "WARC-Target-URI"), "https://samurai.ctr.shop.nintendo.net/samurai/ws/US/contents?offset=0");
+        assert_eq!(field(response, "WARC-Concurrent-To"), field(request, "WARC-Record-ID"));
+        assert_eq!(field(request, "WARC-Concurrent-To"), field(response, "WARC-Record-ID"));
+        assert_eq!(field(response, "WARC-Payload-Digest"), format!("sha256:{}", sha256_hex(body)));
+        assert_eq!(field(response, "WARC-Block-Digest"), format!("sha256:{}", sha256_hex(response_block)));
+
+        // The stored body is the raw one, with framing headers describing it
+        let mut expected = b"HTTP/1.1 200 OK\r\ncontent-type: application/xml; charset=utf-8\r\ncontent-length: 20\r\n\r\n".to_vec();
+        expected.extend_from_slice(body);
+        assert_eq!(response_block, &expected);
+    }
+
+    #[test]
+    fn rejects_urls_without_path() {
+        let dir = std::env::temp_dir().join(format!("saveShop-warc-test-invalid-{}", std::process::id()));
+        let writer = WarcWriter::new(dir.clone(), 1 << 20).unwrap();
+        let result = writer.write_exchange("https://example.org", reqwest::Version::HTTP_11, reqwest::StatusCode::OK, &HeaderMap::new(), b"");
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(SaveShopError::UnknownHost(_))));
+    }
+
+    #[test]
+    fn record_ids_are_version_4_uuids() {
+        let id = record_id();
+        assert_eq!(id.len(), "<urn:uuid:>".len() + 36);
+        assert_eq!(&id[24..25], "4");
+        assert!(matches!(&id[29..30], "8" | "9" | "a" | "b"), "{}", id);
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(warc_date(SystemTime::UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(warc_date(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(951_782_400 + 3661)), "2000-02-29T01:01:01Z");
+    }
+}
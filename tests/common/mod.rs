@@ -0,0 +1,267 @@
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use quick_xml::events::Event;
+use tokio::sync::oneshot;
+
+// Number of items per page if a paginated list is requested without a limit
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+pub fn fixture_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+// Stand-in for the samurai, ninja and kanzashi servers, serving recorded responses from tests/fixtures.
+// saveShop is pointed at it using --mock-server, which makes it request "<mock server>/<host>/<path>"
+// instead of "https://<host>/<path>".
+//
+// Fixtures are looked up as "<host>/<path>.xml" for XML documents or "<host>/<path>" for media files.
+// Paginated lists are stored in full and split into pages as requested. Prices are stored per title
+// in "<ninja base>/titles/online_price/<content id>.xml" and combined according to the requested titles
+pub struct MockServer {
+    address: SocketAddr,
+    // Path and query of all requests received, in order
+    requests: Arc<Mutex<Vec<String>>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    // Serves the given fixture directories, which are relative to tests/fixtures unless absolute.
+    // Files in earlier directories take precedence
+    pub fn start(fixture_dirs: &[&str]) -> MockServer {
+        let fixture_dirs: Arc<Vec<PathBuf>> = Arc::new(fixture_dirs.iter().map(|dir| fixture_path(dir)).collect());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+
+        let thread_requests = requests.clone();
+        let thread = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async move {
+                let make_service = make_service_fn(move |_| {
+                    let fixture_dirs = fixture_dirs.clone();
+                    let requests = thread_requests.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                            let path_and_query = request.uri().path_and_query().map(|p| p.to_string()).unwrap_or_default();
+                            requests.lock().unwrap().push(path_and_query);
+                            let response = respond(&fixture_dirs, request.uri().path(), request.uri().query().unwrap_or_default());
+                            async move { Ok::<_, Infallible>(response) }
+                        }))
+                    }
+                });
+                hyper::Server::from_tcp(listener).unwrap()
+                    .serve(make_service)
+                    .with_graceful_shutdown(async { shutdown_signal.await.ok(); })
+                    .await
+                    .unwrap();
+            });
+        });
+
+        MockServer { address, requests, shutdown: Some(shutdown), thread: Some(thread) }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    // Returns the requests received since the last call
+    pub fn take_requests(&self) -> Vec<String> {
+        std::mem::take(&mut *self.requests.lock().unwrap())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let hex = bytes.get(pos + 1..pos + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[pos], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => { decoded.push(byte); pos += 3; },
+            (byte, _) => { decoded.push(byte); pos += 1; },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn query_parameters(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+         .filter_map(|param| param.split_once('='))
+         .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+         .collect()
+}
+
+fn find_fixture(fixture_dirs: &[PathBuf], relative_path: &str) -> Option<Vec<u8>> {
+    fixture_dirs.iter().find_map(|dir| std::fs::read(dir.join(relative_path)).ok())
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(status.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn xml_response(xml: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(xml));
+    response.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/xml; charset=utf-8".parse().unwrap());
+    response
+}
+
+// Returns the top-level elements of the given XML fragment
+fn split_elements(xml: &str) -> Vec<&str> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut elements = Vec::new();
+    let mut depth = 0;
+    let mut element_start = 0;
+    loop {
+        let position = reader.buffer_position();
+        match reader.read_event().unwrap() {
+            Event::Start(_) => {
+                if depth == 0 {
+                    element_start = position;
+                }
+                depth += 1;
+            },
+            Event::End(_) => {
+                depth -= 1;
+                if depth == 0 {
+                    elements.push(&xml[element_start..reader.buffer_position()]);
+                }
+            },
+            Event::Empty(_) if depth == 0 => elements.push(&xml[position..reader.buffer_position()]),
+            Event::Eof => break,
+            _ => {},
+        }
+    }
+    elements
+}
+
+// Returns the requested page of a document with a <contents> list, numbering items the way the server does
+fn paginate(xml: &str, offset: usize, limit: usize, continuous_index: bool) -> String {
+    let contents_start = xml.find("<contents").expect("Paginated fixture has no <contents> list");
+    let items_start = contents_start + xml[contents_start..].find('>').unwrap() + 1;
+    let items_end = xml.rfind("</contents>").unwrap();
+
+    let items = split_elements(&xml[items_start..items_end]);
+    let total = items.len();
+    let page: Vec<&str> = items.into_iter().skip(offset).take(limit).collect();
+
+    let mut paginated = format!("{}<contents length=\"{}\" offset=\"{}\" total=\"{}\">", &xml[..contents_start], page.len(), offset, total);
+    for (index, item) in page.iter().enumerate() {
+        let index = if continuous_index { offset + index + 1 } else { index + 1 };
+        let item_body = &item[item.find('>').unwrap() + 1..];
+        paginated.push_str(&format!("<content index=\"{}\">{}", index, item_body));
+    }
+    paginated.push_str(&xml[items_end..]);
+    paginated
+}
+
+fn respond(fixture_dirs: &[PathBuf], path: &str, query: &str) -> Response<Body> {
+    let relative_path = percent_decode(path.trim_start_matches('/'));
+    if relative_path.split('/').any(|component| component == ".." || component.is_empty()) {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    let params = query_parameters(query);
+    let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+
+    // Reject requests the real servers wouldn't answer
+    let is_shop_server = relative_path.starts_with("samurai.") || relative_path.starts_with("ninja.");
+    if is_shop_server && (param("shop_id").is_none() || (param("lang").is_none() && !relative_path.ends_with("/languages"))) {
+        return status_response(StatusCode::BAD_REQUEST);
+    }
+
+    // Prices for several titles are requested at once
+    if let Some(ninja_base) = relative_path.strip_suffix("/titles/online_prices") {
+        let online_prices: String = params.iter()
+            .filter(|(key, _)| key == "title[]")
+            .filter_map(|(_, title_id)| find_fixture(fixture_dirs, &format!("{}/titles/online_price/{}.xml", ninja_base, title_id)))
+            .map(|fragment| String::from_utf8(fragment).unwrap().trim_end().to_string())
+            .collect();
+        return xml_response(format!("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?><eshop><online_prices>{}</online_prices></eshop>", online_prices));
+    }
+
+    if let Some(xml) = find_fixture(fixture_dirs, &format!("{}.xml", relative_path)) {
+        let xml = String::from_utf8(xml).unwrap();
+        return match param("offset") {
+            Some(offset) => {
+                let offset = offset.parse().unwrap();
+                let limit = param("limit").map_or(DEFAULT_PAGE_SIZE, |limit| limit.parse().unwrap());
+                // Rankings restart numbering at 1 on every page
+                let continuous_index = !relative_path.contains("/ranking/");
+                xml_response(paginate(&xml, offset, limit, continuous_index))
+            },
+            None => xml_response(xml),
+        };
+    }
+
+    match find_fixture(fixture_dirs, &relative_path) {
+        Some(data) => Response::new(Body::from(data)),
+        None => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+// Fresh working directory for a test
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Runs saveShop in the given directory. Requests are sent to the mock server if one is given
+pub fn run(dir: &Path, mock_server: Option<&MockServer>, args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_saveShop"));
+    command.current_dir(dir).args(args);
+    if let Some(mock_server) = mock_server {
+        command.args(["--mock-server", &mock_server.url(), "--rate-limit", "0", "--max-attempts", "2", "--retry-delay", "0"]);
+    }
+    command.output().unwrap()
+}
+
+// Like run, but fails the test if saveShop exits with an error status. Returns the standard output
+pub fn run_ok(dir: &Path, mock_server: Option<&MockServer>, args: &[&str]) -> String {
+    let output = run(dir, mock_server, args);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(output.status.success(), "saveShop {} failed:\n{}\n{}", args.join(" "), stdout, String::from_utf8_lossy(&output.stderr));
+    stdout
+}
+
+pub fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()));
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+pub fn read(dir: &Path, path: &str) -> String {
+    std::fs::read_to_string(dir.join(path)).unwrap_or_else(|err| panic!("Failed to read {}: {}", path, err))
+}
@@ -0,0 +1,677 @@
+// Runs saveShop's subcommands against a local stand-in for the eShop servers (see common/mod.rs)
+// and checks the resulting archive
+
+mod common;
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+use common::{copy_dir, fixture_path, read, run, run_ok, test_dir, MockServer};
+
+const BLOCK_PUZZLE: &str = "50010000000001";
+const BLOCK_PUZZLE_DEMO: &str = "50010000000101";
+const BLOCK_PUZZLE_TRAILER: &str = "20010000000201";
+const SKY_RACER: &str = "50010000000002";
+const SHOWCASE: &str = "20010000000202";
+const TANK_BRAWL: &str = "20010000100001";
+
+fn fetch_metadata(dir: &Path, server: &MockServer, regions: &str, extra_args: &[&str]) -> String {
+    let cert = fixture_path("client.pem");
+    let mut args = vec!["--regions", regions, "fetch-metadata", "--cert", cert.to_str().unwrap(), "--page-size", "2"];
+    args.extend(extra_args);
+    run_ok(dir, Some(server), &args)
+}
+
+#[test]
+fn fetch_metadata_builds_archive() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_metadata_builds_archive");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    for path in [
+        "3ds/samurai/US/languages".to_string(),
+        "3ds/samurai/US/en/news".to_string(),
+        "3ds/samurai/US/en/rankings".to_string(),
+        format!("3ds/samurai/US/en/title/{}", BLOCK_PUZZLE),
+        format!("3ds/samurai/US/en/title/{}", SKY_RACER),
+        format!("3ds/samurai/US/en/title/aocs/{}", BLOCK_PUZZLE),
+        format!("3ds/samurai/US/en/demo/{}", BLOCK_PUZZLE_DEMO),
+        format!("3ds/samurai/US/en/movie/{}", BLOCK_PUZZLE_TRAILER),
+        format!("3ds/samurai/US/en/movie/{}", SHOWCASE),
+        "3ds/samurai/US/en/directory/1001".to_string(),
+        "3ds/samurai/US/en/ranking/2001".to_string(),
+        format!("3ds/ninja/US/en/title/{}/ec_info", BLOCK_PUZZLE),
+        format!("3ds/ninja/US/en/title/{}/ec_info", BLOCK_PUZZLE_DEMO),
+        format!("3ds/ninja/US/en/titles/online_prices%3Ftitle%5B%5D%3D{}", SKY_RACER),
+        "3ds/ninja/US/en/titles/online_prices".to_string(),
+    ] {
+        assert!(dir.join(&path).is_file(), "{} is missing", path);
+    }
+
+    // The contents list has three items, so it's fetched as two pages and merged
+    assert!(dir.join("3ds/samurai/US/en/paginated/contents%3Foffset%3D0%26limit%3D2").is_file());
+    assert!(dir.join("3ds/samurai/US/en/paginated/contents%3Foffset%3D2%26limit%3D2").is_file());
+    let contents = read(&dir, "3ds/samurai/US/en/contents");
+    assert!(contents.contains("total=\"3\""), "{}", contents);
+    for id in [BLOCK_PUZZLE, SKY_RACER, SHOWCASE] {
+        assert!(contents.contains(id), "merged contents list lacks {}", id);
+    }
+
+    // Prices of both titles are requested at once and then split up
+    let requests = server.take_requests();
+    assert_eq!(requests.iter().filter(|request| request.contains("/titles/online_prices")).count(), 1);
+    assert!(read(&dir, "3ds/ninja/US/en/titles/online_prices").contains("<raw_value>9.99</raw_value>"));
+    assert!(read(&dir, &format!("3ds/ninja/US/en/titles/online_prices%3Ftitle%5B%5D%3D{}", BLOCK_PUZZLE)).contains("<raw_value>4.99</raw_value>"));
+
+    // The original URLs are recorded, rather than those of the mock server
+    let journal = read(&dir, "3ds/request_journal");
+    assert!(journal.contains(&format!("https://samurai.ctr.shop.nintendo.net/samurai/ws/US/title/{}?shop_id=1&lang=en", BLOCK_PUZZLE)));
+    assert!(!journal.contains(&server.url()));
+}
+
+#[test]
+fn fetch_metadata_resumes_from_crawl_state() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_metadata_resumes_from_crawl_state");
+    fetch_metadata(&dir, &server, "US", &[]);
+    server.take_requests();
+
+    // Everything, including the directory list, is read back from the crawl state
+    fetch_metadata(&dir, &server, "US", &[]);
+    let requests = server.take_requests();
+    assert!(requests.is_empty(), "{:?}", requests);
+
+    fetch_metadata(&dir, &server, "US", &["--refetch"]);
+    assert!(server.take_requests().iter().any(|request| request.contains(&format!("/title/{}?", BLOCK_PUZZLE))));
+
+    // Pages fetched with the default page size keep the keys of crawl states written by earlier versions
+    let dir = test_dir("fetch_metadata_resumes_from_old_crawl_state");
+    let cert = fixture_path("client.pem");
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-metadata", "--cert", cert.to_str().unwrap()]);
+    let crawl_state = read(&dir, "3ds/crawl_state");
+    assert!(crawl_state.contains(r#""content_type":"contents","id":"0","endpoint":"contents","status":"done""#), "{}", crawl_state);
+    assert!(crawl_state.contains(r#""content_type":"ranking","id":"2001","endpoint":"ranking?offset=0","status":"done""#), "{}", crawl_state);
+}
+
+#[test]
+fn fetch_metadata_recovers_from_truncated_journal() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_metadata_recovers_from_truncated_journal");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    // Simulate a run that was interrupted while writing a journal entry
+    let mut journal = std::fs::OpenOptions::new().append(true).open(dir.join("3ds/request_journal")).unwrap();
+    journal.write_all(b"{\"version\":1,\"url\":\"https://samurai.ctr").unwrap();
+    drop(journal);
+
+    fetch_metadata(&dir, &server, "US", &["--refetch"]);
+    fetch_metadata(&dir, &server, "US", &[]);
+    let journal = read(&dir, "3ds/request_journal");
+    assert!(journal.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()), "{}", journal);
+}
+
+#[test]
+fn fetch_metadata_recovers_from_truncated_crawl_state() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_metadata_recovers_from_truncated_crawl_state");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    // Simulate a run that was interrupted while recording a finished request
+    let mut crawl_state = std::fs::OpenOptions::new().append(true).open(dir.join("3ds/crawl_state")).unwrap();
+    crawl_state.write_all(b"{\"region\":\"US\",\"language\":\"en\",\"content_type\":\"ti").unwrap();
+    drop(crawl_state);
+
+    fetch_metadata(&dir, &server, "US", &["--refetch"]);
+    let crawl_state = read(&dir, "3ds/crawl_state");
+    assert!(crawl_state.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()), "{}", crawl_state);
+
+    // Nothing recorded after the interruption was lost
+    server.take_requests();
+    fetch_metadata(&dir, &server, "US", &[]);
+    let requests = server.take_requests();
+    assert!(requests.is_empty(), "{:?}", requests);
+}
+
+#[test]
+fn fetch_media_recovers_from_truncated_manifest() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_media_recovers_from_truncated_manifest");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    // Simulate a run that was interrupted while storing a media file
+    std::fs::create_dir_all(dir.join("media")).unwrap();
+    std::fs::write(dir.join("media/manifest"), b"{\"url\":\"https://kanzashi-ctr.cdn.nintendo.net/i/ti").unwrap();
+
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-media"]);
+    let manifest = read(&dir, "media/manifest");
+    assert!(manifest.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()), "{}", manifest);
+
+    // All stored files are reused and can be verified against their hashes
+    server.take_requests();
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-media"]);
+    assert_eq!(server.take_requests(), Vec::<String>::new());
+    run_ok(&dir, None, &["--regions", "US", "verify"]);
+}
+
+#[test]
+fn fetch_metadata_keeps_prices_of_failed_batches() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_metadata_keeps_prices_of_failed_batches");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    // Only the price batch is fetched again, from a ninja server that can't be reached
+    std::fs::remove_dir_all(dir.join("3ds/ninja/US/en/titles/online_prices_batches")).unwrap();
+    let cert = fixture_path("client.pem");
+    let output = run(&dir, Some(&server), &["--regions", "US", "fetch-metadata", "--cert", cert.to_str().unwrap(), "--page-size", "2",
+                                            "--ninja-url", "http://127.0.0.1:1/ninja/ws"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Failed to process prices for titles"));
+    let online_prices = read(&dir, "3ds/ninja/US/en/titles/online_prices");
+    for id in [BLOCK_PUZZLE, SKY_RACER] {
+        assert!(online_prices.contains(id), "{}", online_prices);
+    }
+}
+
+#[test]
+fn fetch_metadata_without_ninja() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_metadata_without_ninja");
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-metadata", "--omit-ninja-contents"]);
+
+    assert!(dir.join(format!("3ds/samurai/US/en/title/{}", BLOCK_PUZZLE)).is_file());
+    assert!(!dir.join("3ds/ninja").exists());
+    assert!(server.take_requests().iter().all(|request| !request.starts_with("/ninja.")));
+}
+
+#[test]
+fn fetch_metadata_for_single_title() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_metadata_for_single_title");
+    run_ok(&dir, Some(&server), &["--regions", "US", "--title", SKY_RACER, "fetch-metadata", "--omit-ninja-contents"]);
+
+    assert!(dir.join(format!("3ds/samurai/US/en/title/{}", SKY_RACER)).is_file());
+    assert!(!dir.join(format!("3ds/samurai/US/en/title/{}", BLOCK_PUZZLE)).exists());
+    assert!(!dir.join("3ds/samurai/US/en/contents").exists());
+}
+
+#[test]
+fn fetch_media_and_verify() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_media_and_verify");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    // Media isn't available before it's fetched
+    assert!(!run(&dir, None, &["--regions", "US", "verify"]).status.success());
+
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-media"]);
+    for name in ["news_us_1.jpg", "rating_e.jpg", "directory_1001_banner.jpg", "movie_20010000000202_thumb.jpg",
+                 "title_50010000000001_ss_upper.jpg", "demo_50010000000101_icon.jpg"] {
+        assert_eq!(read(&dir, &format!("kanzashi/{}", name)), format!("fake media {}\n", name));
+    }
+    assert!(!dir.join("kanzashi-movie/showcase_2016.moflex").exists());
+    run_ok(&dir, None, &["--regions", "US", "verify"]);
+
+    // Videos need to be requested explicitly. The trailer is referenced by the title and its movie document, but only downloaded once
+    server.take_requests();
+    run_ok(&dir, Some(&server), &["--regions", "US", "--title", BLOCK_PUZZLE, "fetch-media", "--fetch-videos"]);
+    assert!(dir.join("kanzashi-movie/trailer_20010000000201.moflex").is_file());
+    assert_eq!(server.take_requests().iter().filter(|request| request.ends_with("/trailer_20010000000201.moflex")).count(), 1);
+    assert!(!dir.join("kanzashi-movie/showcase_2016.moflex").exists());
+    let output = run(&dir, None, &["--regions", "US", "verify", "--videos"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("kanzashi-movie/showcase_2016.moflex"));
+
+    // Stored media is reused rather than downloaded again
+    server.take_requests();
+    std::fs::remove_file(dir.join("kanzashi/rating_e.jpg")).unwrap();
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-media"]);
+    assert!(dir.join("kanzashi/rating_e.jpg").is_file());
+    assert!(server.take_requests().iter().all(|request| !request.starts_with("/kanzashi")));
+
+    // Corruption that keeps the size intact is detected by its hash, and repaired by fetching again
+    std::fs::write(dir.join("kanzashi/rating_e.jpg"), "fake media rating_X.jpg\n").unwrap();
+    let output = run(&dir, None, &["--regions", "US", "verify"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Hash mismatch: kanzashi/rating_e.jpg"));
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-media", "--refetch"]);
+    assert_eq!(read(&dir, "kanzashi/rating_e.jpg"), "fake media rating_e.jpg\n");
+    run_ok(&dir, None, &["--regions", "US", "verify"]);
+}
+
+#[test]
+fn fetch_media_with_missing_demo() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_media_with_missing_demo");
+    fetch_metadata(&dir, &server, "US", &[]);
+    std::fs::remove_file(dir.join(format!("3ds/samurai/US/en/demo/{}", BLOCK_PUZZLE_DEMO))).unwrap();
+
+    // The missing demo is reported, but doesn't stop the remaining media from being fetched
+    let output = run(&dir, Some(&server), &["--regions", "US", "fetch-media", "--on-missing-demo", "fail"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("Title {} references demo {}", BLOCK_PUZZLE, BLOCK_PUZZLE_DEMO)), "{}", stdout);
+    assert!(dir.join("kanzashi/rating_e.jpg").is_file());
+    assert!(dir.join("kanzashi/movie_20010000000202_thumb.jpg").is_file());
+
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-media", "--on-missing-demo", "fetch"]);
+    assert!(dir.join(format!("3ds/samurai/US/en/demo/{}", BLOCK_PUZZLE_DEMO)).is_file());
+}
+
+#[test]
+fn fetch_media_to_warc_only() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_media_to_warc_only");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    run_ok(&dir, Some(&server), &["--regions", "US", "--warc", "warc", "--warc-only", "fetch-media"]);
+    assert!(!dir.join("kanzashi/rating_e.jpg").exists());
+    let warc: Vec<u8> = std::fs::read_dir(dir.join("warc")).unwrap()
+        .flat_map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+    let warc = String::from_utf8_lossy(&warc);
+    assert!(warc.contains("fake media rating_e.jpg\n"));
+    // Framing headers describe the stored body
+    assert!(!warc.to_lowercase().contains("transfer-encoding"));
+    assert!(warc.contains(&format!("content-length: {}\r\n", "fake media rating_e.jpg\n".len())));
+
+    // Files archived before aren't downloaded again
+    server.take_requests();
+    run_ok(&dir, Some(&server), &["--regions", "US", "--warc", "warc", "--warc-only", "fetch-media"]);
+    assert!(server.take_requests().iter().all(|request| !request.starts_with("/kanzashi")));
+}
+
+#[test]
+fn fetch_all() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_all");
+    let cert = fixture_path("client.pem");
+    run_ok(&dir, Some(&server), &["--regions", "US,GB", "fetch-all", "--cert", cert.to_str().unwrap()]);
+
+    assert!(dir.join("3ds/samurai/GB/en/title/50010000000011").is_file());
+    assert!(dir.join("kanzashi/title_50010000000011_icon.jpg").is_file());
+    run_ok(&dir, None, &["--regions", "US,GB", "verify"]);
+}
+
+#[test]
+fn fetch_wiiu_alongside_3ds() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("fetch_wiiu_alongside_3ds");
+    let cert = fixture_path("client.pem");
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-all", "--cert", cert.to_str().unwrap()]);
+    let stdout = run_ok(&dir, Some(&server), &["--regions", "US", "--platform", "wiiu", "fetch-all", "--cert", cert.to_str().unwrap()]);
+    assert!(stdout.contains("Skipping directory 1090749"), "{}", stdout);
+
+    // Wii U metadata, crawl state and request journal are kept apart from the 3DS ones, while media is stored next to each other
+    for path in [
+        format!("3ds/samurai/US/en/title/{}", BLOCK_PUZZLE),
+        format!("wiiu/samurai/US/en/title/{}", TANK_BRAWL),
+        "wiiu/samurai/US/en/directory/1101".to_string(),
+        format!("wiiu/ninja/US/en/title/{}/ec_info", TANK_BRAWL),
+        "wiiu/ninja/US/en/titles/online_prices".to_string(),
+        "wiiu/crawl_state".to_string(),
+        "wiiu/request_journal".to_string(),
+        format!("kanzashi/title_{}_icon.jpg", BLOCK_PUZZLE),
+        format!("kanzashi-wup/title_{}_icon.jpg", TANK_BRAWL),
+        format!("kanzashi-wup/title_{}_ss_thumb.jpg", TANK_BRAWL),
+        "kanzashi-wup/directory_1101_icon.jpg".to_string(),
+    ] {
+        assert!(dir.join(&path).is_file(), "{} is missing", path);
+    }
+    assert!(!dir.join(format!("3ds/samurai/US/en/title/{}", TANK_BRAWL)).exists());
+    assert!(read(&dir, "wiiu/request_journal").contains(&format!("https://samurai.wup.shop.nintendo.net/samurai/ws/US/title/{}?shop_id=2&lang=en", TANK_BRAWL)));
+    assert!(!read(&dir, "3ds/request_journal").contains("samurai.wup.shop.nintendo.net"));
+
+    run_ok(&dir, None, &["--regions", "US", "--platform", "wiiu", "verify"]);
+    run_ok(&dir, None, &["--regions", "US", "verify"]);
+
+    run_ok(&dir, None, &["--regions", "US", "--platform", "wiiu", "export-json", "--output", "json-wiiu"]);
+    let title: serde_json::Value = serde_json::from_str(&read(&dir, &format!("json-wiiu/US/en/title/{}.json", TANK_BRAWL))).unwrap();
+    assert_eq!(title["off_tv_play"], true);
+    assert_eq!(title["miiverse"], true);
+    assert_eq!(title["prices"]["regular"]["raw_value"], "14.99");
+    assert!(!read(&dir, "json-wiiu/index.json").contains(BLOCK_PUZZLE));
+    let index: serde_json::Value = serde_json::from_str(&read(&dir, "json-wiiu/index.json")).unwrap();
+    assert_eq!(index["platform"], "wiiu");
+
+    // Exports of one platform aren't overwritten by those of another
+    let output = run(&dir, None, &["--regions", "US", "export-json", "--output", "json-wiiu"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("holds an export for platform wiiu"));
+    assert!(read(&dir, "json-wiiu/index.json").contains(TANK_BRAWL));
+
+    run_ok(&dir, None, &["--regions", "US", "--platform", "wiiu", "export-sqlite", "--output", "wiiu.sqlite"]);
+    let output = run(&dir, None, &["--regions", "US", "export-sqlite", "--output", "wiiu.sqlite"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("holds an export for platform wiiu"));
+    run_ok(&dir, None, &["--regions", "US", "--platform", "wiiu", "export-sqlite", "--output", "wiiu.sqlite"]);
+}
+
+#[test]
+fn migrate_single_platform_archive() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("migrate_single_platform_archive");
+    let cert = fixture_path("client.pem");
+    let wiiu_args = ["--regions", "US", "--platform", "wiiu"];
+    run_ok(&dir, Some(&server), &[&wiiu_args[..], &["fetch-all", "--cert", cert.to_str().unwrap()]].concat());
+
+    // Earlier versions stored everything in the archive root, and Wii U media in kanzashi/
+    for name in ["samurai", "ninja", "crawl_state", "request_journal"] {
+        std::fs::rename(dir.join("wiiu").join(name), dir.join(name)).unwrap();
+    }
+    std::fs::remove_dir(dir.join("kanzashi")).unwrap();
+    std::fs::rename(dir.join("kanzashi-wup"), dir.join("kanzashi")).unwrap();
+
+    let output = run(&dir, None, &[&wiiu_args[..], &["verify"]].concat());
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("saveShop migrate"));
+
+    // The request journal tells that this isn't 3DS data
+    let output = run(&dir, None, &["migrate"]);
+    assert!(!output.status.success());
+    assert!(!dir.join("3ds/samurai").exists());
+
+    run_ok(&dir, None, &["--platform", "wiiu", "migrate"]);
+    assert!(dir.join(format!("wiiu/samurai/US/en/title/{}", TANK_BRAWL)).is_file());
+    assert!(dir.join(format!("kanzashi-wup/title_{}_icon.jpg", TANK_BRAWL)).is_file());
+    assert!(!dir.join("request_journal").exists());
+    run_ok(&dir, None, &[&wiiu_args[..], &["verify"]].concat());
+
+    // The crawl state was moved along, so nothing is fetched again
+    server.take_requests();
+    run_ok(&dir, Some(&server), &[&wiiu_args[..], &["fetch-metadata", "--cert", cert.to_str().unwrap()]].concat());
+    let requests = server.take_requests();
+    assert!(requests.is_empty(), "{:?}", requests);
+}
+
+#[test]
+fn migrate_archive_without_media_store() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("migrate_archive_without_media_store");
+    let cert = fixture_path("client.pem");
+    let wiiu_args = ["--regions", "US", "--platform", "wiiu"];
+    run_ok(&dir, Some(&server), &[&wiiu_args[..], &["fetch-all", "--cert", cert.to_str().unwrap()]].concat());
+
+    // Archives from before the media store and request journal only had the http_log, with Wii U media in kanzashi/
+    let mut http_log = String::new();
+    for line in read(&dir, "wiiu/request_journal").lines() {
+        let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+        let content_length = entry["body_size"].as_u64().map(|size| format!("\"content-length\": \"{}\"", size)).unwrap_or_default();
+        http_log += &format!("{{\n  \"url\": \"{}\",\n  \"response_headers\": {{\n    {}\n  }}\n}}\n{}\n",
+                             entry["url"].as_str().unwrap(), content_length, "-".repeat(50));
+    }
+    std::fs::write(dir.join("http_log"), http_log).unwrap();
+    for name in ["samurai", "ninja"] {
+        std::fs::rename(dir.join("wiiu").join(name), dir.join(name)).unwrap();
+    }
+    std::fs::remove_dir_all(dir.join("wiiu")).unwrap();
+    std::fs::remove_dir_all(dir.join("media")).unwrap();
+    std::fs::remove_dir(dir.join("kanzashi")).unwrap();
+    std::fs::rename(dir.join("kanzashi-wup"), dir.join("kanzashi")).unwrap();
+
+    let output = run_ok(&dir, None, &["--platform", "wiiu", "migrate"]);
+    assert!(output.contains("media files to their new location"), "{}", output);
+    assert!(dir.join("wiiu/http_log").is_file());
+    assert_eq!(read(&dir, &format!("kanzashi-wup/title_{}_icon.jpg", TANK_BRAWL)), format!("fake media title_{}_icon.jpg\n", TANK_BRAWL));
+    run_ok(&dir, None, &[&wiiu_args[..], &["verify"]].concat());
+}
+
+#[test]
+fn fetch_from_mirror() {
+    let dir = test_dir("fetch_from_mirror");
+
+    // Mirror of all servers under different hosts, whose metadata refers to media on the mirror
+    let eshop = fixture_path("eshop");
+    let mirror = dir.join("mirror");
+    copy_dir(&eshop.join("samurai.ctr.shop.nintendo.net"), &mirror.join("samurai.mirror.test"));
+    copy_dir(&eshop.join("ninja.ctr.shop.nintendo.net"), &mirror.join("ninja.mirror.test"));
+    copy_dir(&eshop.join("kanzashi-ctr.cdn.nintendo.net"), &mirror.join("cdn.mirror.test/kanzashi-ctr"));
+    let title_path = mirror.join(format!("samurai.mirror.test/samurai/ws/US/title/{}.xml", SKY_RACER));
+    let title = std::fs::read_to_string(&title_path).unwrap();
+    std::fs::write(&title_path, title.replace("https://kanzashi-ctr.cdn.nintendo.net/", "https://cdn.mirror.test/kanzashi-ctr/")).unwrap();
+    let server = MockServer::start(&[mirror.to_str().unwrap()]);
+
+    let archive = dir.join("archive");
+    std::fs::create_dir_all(&archive).unwrap();
+    std::fs::write(archive.join("config.json"), r#"{
+        "samurai_urls": { "3ds": "https://samurai.mirror.test/samurai/ws" },
+        "cdn_urls": { "kanzashi-ctr.cdn.nintendo.net": "https://cdn.mirror.test/kanzashi-ctr" },
+        "host_aliases": { "https://cdn.mirror.test/kanzashi-ctr": "kanzashi-ctr.cdn.nintendo.net" }
+    }"#).unwrap();
+    let cert = fixture_path("client.pem");
+    run_ok(&archive, Some(&server), &["--regions", "US", "--config", "config.json", "--ninja-url", "https://ninja.mirror.test/ninja/ws",
+                                      "fetch-metadata", "--cert", cert.to_str().unwrap()]);
+    run_ok(&archive, Some(&server), &["--regions", "US", "--config", "config.json", "fetch-media"]);
+
+    assert!(server.take_requests().iter().all(|request| !request.contains("nintendo.net")));
+    assert!(read(&archive, "3ds/request_journal").contains("https://samurai.mirror.test/samurai/ws/US/contents"));
+    assert_eq!(read(&archive, &format!("kanzashi/title_{}_icon.jpg", SKY_RACER)), format!("fake media title_{}_icon.jpg\n", SKY_RACER));
+    assert!(archive.join("kanzashi/rating_e.jpg").is_file());
+
+    // The mirrors only apply to the platform they're configured for
+    run(&archive, Some(&server), &["--regions", "US", "--platform", "wiiu", "--config", "config.json", "--max-attempts", "1",
+                                   "fetch-metadata", "--cert", cert.to_str().unwrap()]);
+    let requests = server.take_requests();
+    assert!(requests.iter().any(|request| request.contains("samurai.wup.shop.nintendo.net")), "{:?}", requests);
+    assert!(requests.iter().all(|request| !request.contains("mirror.test")), "{:?}", requests);
+
+    // Media URLs from the mirror are only recognized with the alias
+    run_ok(&archive, None, &["--regions", "US", "--config", "config.json", "verify"]);
+    let output = run(&archive, None, &["--regions", "US", "verify"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Unrecognized resource URL \"https://cdn.mirror.test/kanzashi-ctr/i/"));
+
+    let output = run(&archive, None, &["--regions", "US", "--host-alias", "cdn.mirror.test=kanzashi-ctr.cdn.nintendo.net", "verify"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not an http(s) URL"));
+
+    std::fs::write(archive.join("config.json"), r#"{ "ninja_urls": { "ctr": "https://ninja.mirror.test/ninja/ws" } }"#).unwrap();
+    let output = run(&archive, None, &["--regions", "US", "--config", "config.json", "verify"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a platform"));
+}
+
+#[test]
+fn replay() {
+    let server = MockServer::start(&["eshop"]);
+    let original = test_dir("replay_original");
+    fetch_metadata(&original, &server, "US", &[]);
+    run_ok(&original, Some(&server), &["--regions", "US", "--title", BLOCK_PUZZLE, "fetch-media", "--fetch-videos"]);
+    drop(server);
+
+    // Crawl again after a title was renamed, so the archive holds two versions of it
+    let server = MockServer::start(&["eshop-update", "eshop"]);
+    fetch_metadata(&original, &server, "US", &["--refetch"]);
+    drop(server);
+
+    // Nothing is requested from the network, so neither a server nor a certificate are needed
+    let dir = test_dir("replay");
+    let replay_args = ["--replay", original.to_str().unwrap(), "--max-attempts", "1", "--regions", "US"];
+    run_ok(&dir, None, &[&replay_args[..], &["fetch-metadata", "--page-size", "2"]].concat());
+    for path in ["3ds/samurai/US/en/contents".to_string(), format!("3ds/samurai/US/en/title/{}", BLOCK_PUZZLE),
+                 format!("3ds/samurai/US/en/title/{}", SKY_RACER), "3ds/samurai/US/en/ranking/2001".to_string(),
+                 format!("3ds/ninja/US/en/title/{}/ec_info", BLOCK_PUZZLE_DEMO), "3ds/ninja/US/en/titles/online_prices".to_string()] {
+        assert_eq!(read(&dir, &path), read(&original, &path), "{} differs", path);
+    }
+    assert!(read(&dir, &format!("3ds/samurai/US/en/title/{}", SKY_RACER)).contains("Sky Racer DX"));
+
+    run_ok(&dir, None, &[&replay_args[..], &["--title", BLOCK_PUZZLE, "fetch-media", "--fetch-videos"]].concat());
+    for path in [format!("kanzashi/title_{}_icon.jpg", BLOCK_PUZZLE), "kanzashi-movie/trailer_20010000000201.moflex".to_string()] {
+        assert_eq!(read(&dir, &path), read(&original, &path), "{} differs", path);
+    }
+
+    // Requests that weren't made for the original archive fail
+    let dir = test_dir("replay_miss");
+    let output = run(&dir, None, &[&replay_args[..], &["fetch-metadata", "--page-size", "3"]].concat());
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Can't replay https://samurai.ctr.shop.nintendo.net/samurai/ws/US/contents?offset=0&limit=3"), "{}", stdout);
+    assert!(stdout.contains("requests could not be answered from the replayed archive"), "{}", stdout);
+}
+
+#[test]
+fn export_json_and_sqlite() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("export_json_and_sqlite");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    run_ok(&dir, None, &["--regions", "US", "export-json", "--output", "json"]);
+    let title: serde_json::Value = serde_json::from_str(&read(&dir, &format!("json/US/en/title/{}.json", BLOCK_PUZZLE))).unwrap();
+    assert!(title.to_string().contains("CTR-N-JBPE"), "{}", title);
+    assert_eq!(title["platform_device"], "CTR");
+    assert_eq!(title["rating"]["system_id"], "201");
+    assert_eq!(title["rating"]["icons"][0]["type"], "large");
+    assert_eq!(title["rating"]["descriptors"][0], "Mild Fantasy Violence");
+    assert_eq!(title["rating"]["content_descriptors"][0]["type"], "1");
+    assert!(title["thumbnails"].is_array(), "{}", title);
+    let index = read(&dir, "json/index.json");
+    serde_json::from_str::<serde_json::Value>(&index).unwrap();
+    for id in [BLOCK_PUZZLE, SKY_RACER, BLOCK_PUZZLE_DEMO, SHOWCASE] {
+        assert!(index.contains(id), "index lacks {}", id);
+    }
+    assert!(read(&dir, "json/title_ids.json").contains("0004000000100200"));
+
+    run_ok(&dir, None, &["--regions", "US", "export-sqlite", "--output", "archive.sqlite"]);
+    let db = rusqlite::Connection::open(dir.join("archive.sqlite")).unwrap();
+    let count = |query: &str| db.query_row(query, [], |row| row.get::<_, i64>(0)).unwrap();
+    assert_eq!(count("SELECT COUNT(*) FROM titles"), 2);
+    assert_eq!(count("SELECT COUNT(*) FROM demos"), 1);
+    assert_eq!(count("SELECT COUNT(*) FROM movies"), 2);
+    assert_eq!(count("SELECT COUNT(*) FROM ranking_contents"), 2);
+    assert_eq!(count("SELECT COUNT(*) FROM prices"), 2);
+}
+
+#[test]
+fn lookup() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("lookup");
+    fetch_metadata(&dir, &server, "US", &[]);
+
+    assert!(run_ok(&dir, None, &["--regions", "US", "lookup", "0004000000100100"]).contains(BLOCK_PUZZLE));
+    assert!(run_ok(&dir, None, &["--regions", "US", "lookup", BLOCK_PUZZLE_DEMO]).contains("0004000200100100"));
+    assert!(!run(&dir, None, &["--regions", "US", "lookup", "0004000000999900"]).status.success());
+}
+
+#[test]
+fn read_only_subcommands_leave_directory_unchanged() {
+    let dir = test_dir("read_only_subcommands_leave_directory_unchanged");
+    for args in [&["--regions", "US", "verify"][..], &["--regions", "US", "lookup", BLOCK_PUZZLE], &["--regions", "US", "report-prices"]] {
+        run(&dir, None, args);
+    }
+    let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert!(entries.is_empty(), "{:?}", entries);
+}
+
+#[test]
+fn report_prices() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("report_prices");
+    fetch_metadata(&dir, &server, "US,GB", &[]);
+
+    let report = run_ok(&dir, None, &["--regions", "US,GB", "report-prices", "--format", "csv"]);
+    let block_puzzle = report.lines().find(|line| line.starts_with("CTR-N-JBP,")).unwrap_or_else(|| panic!("{}", report));
+    assert!(block_puzzle.contains(&format!("{},onsale,USD,4.99", BLOCK_PUZZLE)), "{}", block_puzzle);
+    assert!(block_puzzle.contains("50010000000011,onsale,GBP,4.49"), "{}", block_puzzle);
+
+    run_ok(&dir, None, &["--regions", "US", "report-prices", "--format", "json", "--output", "prices.json"]);
+    let report: serde_json::Value = serde_json::from_str(&read(&dir, "prices.json")).unwrap();
+    let sky_racer = report.as_array().unwrap().iter().find(|entry| entry["key"] == "CTR-N-JSR").unwrap();
+    assert_eq!(sky_racer["regions"]["US"]["discount"]["raw_value"], "4.99");
+
+    // Titles of the same region whose product codes only differ in the last character are listed separately
+    let sky_racer_path = format!("3ds/samurai/US/en/title/{}", SKY_RACER);
+    std::fs::write(dir.join(&sky_racer_path), read(&dir, &sky_racer_path).replace("CTR-N-JSRE", "CTR-N-JBPX")).unwrap();
+    let report = run_ok(&dir, None, &["--regions", "US,GB", "report-prices", "--format", "csv"]);
+    assert!(report.contains(&format!("{} shares product code CTR-N-JBP with {} in region US", SKY_RACER, BLOCK_PUZZLE)), "{}", report);
+    assert!(report.lines().any(|line| line.starts_with("CTR-N-JBP,") && line.contains(BLOCK_PUZZLE)), "{}", report);
+    assert!(report.lines().any(|line| line.starts_with(&format!("US:{},", SKY_RACER))), "{}", report);
+}
+
+#[test]
+fn snapshot_and_diff() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("snapshot_and_diff");
+    fetch_metadata(&dir, &server, "US", &["--snapshot"]);
+    fetch_metadata(&dir, &server, "GB", &["--snapshot"]);
+
+    // Unchanged metadata isn't recorded again, even if other regions were snapshotted in between
+    let output = run_ok(&dir, None, &["--regions", "US", "snapshot", "create"]);
+    let snapshots = run_ok(&dir, None, &["--regions", "US", "snapshot", "list"]);
+    let snapshots: Vec<&str> = snapshots.lines().collect();
+    assert_eq!(snapshots.len(), 2, "{:?}", snapshots);
+    assert!(output.contains(&format!("Metadata recorded as snapshot {}", snapshots[0])), "{}", output);
+    drop(server);
+
+    // Crawl again after a title was renamed and its price changed
+    let server = MockServer::start(&["eshop-update", "eshop"]);
+    fetch_metadata(&dir, &server, "US", &["--refetch", "--snapshot"]);
+
+    let snapshots = run_ok(&dir, None, &["--regions", "US", "snapshot", "list"]);
+    let snapshots: Vec<&str> = snapshots.lines().collect();
+    assert_eq!(snapshots.len(), 3, "{:?}", snapshots);
+
+    let diff = run_ok(&dir, None, &["--regions", "US", "diff", snapshots[0], snapshots[2]]);
+    assert!(diff.contains("Sky Racer DX"), "{}", diff);
+    assert!(diff.contains("7.99"), "{}", diff);
+    assert!(!diff.contains("Block Puzzle"), "{}", diff);
+
+    run_ok(&dir, None, &["--regions", "US", "snapshot", "checkout", snapshots[0], "--output", "old"]);
+    assert!(read(&dir, &format!("old/3ds/samurai/US/en/title/{}", SKY_RACER)).contains("<name>Sky Racer</name>"));
+}
+
+fn http_get(address: &str, path: &str) -> Option<String> {
+    http_get_range(address, path, None)
+}
+
+fn http_get_range(address: &str, path: &str, range: Option<&str>) -> Option<String> {
+    let mut stream = std::net::TcpStream::connect(address).ok()?;
+    let range = range.map(|range| format!("Range: {}\r\n", range)).unwrap_or_default();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\n\r\n", path, address, range).ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    Some(response)
+}
+
+#[test]
+fn serve() {
+    let server = MockServer::start(&["eshop"]);
+    let dir = test_dir("serve");
+    fetch_metadata(&dir, &server, "US", &[]);
+    run_ok(&dir, Some(&server), &["--regions", "US", "fetch-media"]);
+
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_saveShop"))
+        .current_dir(&dir)
+        .args(["serve", "--address", &address])
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut index = None;
+    for _ in 0..100 {
+        index = http_get(&address, "/");
+        if index.is_some() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let title = http_get(&address, &format!("/3ds/samurai/US/en/title/{}", BLOCK_PUZZLE));
+    let resource = http_get(&address, "/resource?url=https%3A%2F%2Fkanzashi-ctr.cdn.nintendo.net%2Fi%2Frating_e.jpg");
+    let ranges = [Some("bytes=5-9"), Some("bytes=-4"), Some("bytes=100-"), Some("bytes=0-1,4-5"), None]
+        .map(|range| http_get_range(&address, "/kanzashi/rating_e.jpg", range).unwrap());
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(index.unwrap().starts_with("HTTP/1.1 200"));
+    let title = title.unwrap();
+    assert!(title.starts_with("HTTP/1.1 200") && title.contains("<name>Block Puzzle</name>"), "{}", title);
+    let resource = resource.unwrap();
+    assert!(resource.starts_with("HTTP/1.1 302") && resource.contains("location: /kanzashi/rating_e.jpg"), "{}", resource);
+
+    // The file contains "fake media rating_e.jpg\n"
+    let [middle, suffix, unsatisfiable, multiple, full] = ranges;
+    assert!(middle.starts_with("HTTP/1.1 206") && middle.contains("content-range: bytes 5-9/24") && middle.ends_with("\r\n\r\nmedia"), "{}", middle);
+    assert!(suffix.starts_with("HTTP/1.1 206") && suffix.ends_with("\r\n\r\njpg\n"), "{}", suffix);
+    assert!(unsatisfiable.starts_with("HTTP/1.1 416") && unsatisfiable.contains("content-range: bytes */24"), "{}", unsatisfiable);
+    // Multiple ranges aren't supported, so the whole file is served
+    assert!(multiple.starts_with("HTTP/1.1 200") && multiple.ends_with("\r\n\r\nfake media rating_e.jpg\n"), "{}", multiple);
+    assert!(full.starts_with("HTTP/1.1 200") && full.contains("content-length: 24") && full.ends_with("\r\n\r\nfake media rating_e.jpg\n"), "{}", full);
+}